@@ -3,6 +3,14 @@ fn main() {
         "cargo:rustc-env=HOMELABD_VERSION={}",
         env!("CARGO_PKG_VERSION")
     );
+    println!(
+        "cargo:rustc-env=HOMELABD_TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
 
-    prost_build::compile_protos(&["proto/homelabd.proto"], &["proto"]).unwrap();
+    prost_build::Config::new()
+        // Lets `homelabd ctl watch` print decoded messages as JSON
+        .type_attribute(".", "#[derive(serde::Serialize)]")
+        .compile_protos(&["proto/homelabd.proto"], &["proto"])
+        .unwrap();
 }
@@ -0,0 +1,209 @@
+//! Types served by the HTTP API and read back by `homelabd ctl`.
+
+use crate::election::Election;
+use crate::protocol;
+use crate::receivers::hostdb::HostDatabase;
+
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub node_id: String,
+    pub name: String,
+    pub hostname: String,
+    pub primary_ip: String,
+    pub ips: Vec<String>,
+    pub version: String,
+    pub uptime_seconds: i64,
+    pub last_seen_seconds: u64,
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+    pub source_ip: String,
+    pub address_mismatch: bool,
+    #[serde(default)]
+    pub rolled_back_versions: Vec<String>,
+    #[serde(default)]
+    pub build_hash: String,
+    #[serde(default)]
+    pub target: String,
+    /// Other builds the host serves, as `target/version`
+    #[serde(default)]
+    pub cached_binaries: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceInfo {
+    pub name: String,
+    pub host: String,
+    pub node_id: String,
+    pub address: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExporterInfo {
+    pub job: String,
+    pub instance: String,
+    pub target: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub hostname: String,
+    pub uptime_seconds: i64,
+    pub version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskInfo {
+    pub name: String,
+    pub interval_seconds: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaderInfo {
+    pub node_id: String,
+    pub hostname: String,
+    pub term: u64,
+    pub expires_in_seconds: u64,
+    pub is_self: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RolloutState {
+    Idle,
+    Running,
+    Paused,
+    Completed,
+    Aborted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateState {
+    Pending,
+    Updating,
+    Updated,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RolloutNode {
+    pub node_id: String,
+    pub hostname: String,
+    /// 0 for the canaries, then each batch in turn
+    pub wave: usize,
+    pub state: UpdateState,
+    /// Peer the node fetches the update from
+    pub source: String,
+    pub updating_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RolloutStatus {
+    /// Only the leader coordinates rollouts; other nodes always report idle
+    pub is_leader: bool,
+    pub state: RolloutState,
+    pub version: Option<String>,
+    pub reason: Option<String>,
+    pub held: Vec<String>,
+    pub nodes: Vec<RolloutNode>,
+}
+
+/// The current leader, if any node holds an unexpired lease.
+pub fn leader(
+    election: &Election,
+    hostdb: &HostDatabase,
+    self_node_id: &str,
+    now: SystemTime,
+) -> Option<LeaderInfo> {
+    let lease = election.lease()?;
+    let hostname = hostdb
+        .get_host(&lease.holder)
+        .map(|host| host.hostname.clone())
+        .unwrap_or_default();
+
+    Some(LeaderInfo {
+        is_self: lease.holder == self_node_id,
+        expires_in_seconds: lease
+            .expires_at
+            .duration_since(now)
+            .unwrap_or_default()
+            .as_secs(),
+        term: lease.term,
+        hostname,
+        node_id: lease.holder,
+    })
+}
+
+/// Known hosts, with ages measured from `now`.
+pub fn hosts(hostdb: &HostDatabase, now: SystemTime) -> Vec<HostInfo> {
+    hostdb
+        .hosts()
+        .iter()
+        .map(|host| {
+            let since = hostdb
+                .last_seen(&host.node_id)
+                .and_then(|seen| now.duration_since(seen).ok())
+                .unwrap_or_default()
+                .as_secs();
+
+            HostInfo {
+                node_id: host.node_id.clone(),
+                name: host.name.clone(),
+                hostname: host.hostname.clone(),
+                primary_ip: host.primaryip.to_string(),
+                ips: host.ip.clone(),
+                version: host.version.clone(),
+                // Uptime was reported when the host last announced itself
+                uptime_seconds: host.uptime + since as i64,
+                last_seen_seconds: since,
+                protocol_version: host.protocol_version,
+                capabilities: protocol::capability_names(host.capabilities)
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                source_ip: host.source_ip.to_string(),
+                address_mismatch: host.address_mismatch,
+                rolled_back_versions: host.rolled_back_versions.clone(),
+                build_hash: host.build_hash.clone(),
+                target: host.target.clone(),
+                cached_binaries: host
+                    .cached_binaries
+                    .iter()
+                    .map(|cached| format!("{}/{}", cached.target, cached.version))
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+pub fn services(hostdb: &HostDatabase) -> Vec<ServiceInfo> {
+    hostdb
+        .services()
+        .into_iter()
+        .map(|(host, service)| ServiceInfo {
+            name: service.name,
+            host: host.hostname.clone(),
+            node_id: host.node_id.clone(),
+            address: host.primaryip.to_string(),
+            port: service.port,
+        })
+        .collect()
+}
+
+pub fn exporters(hostdb: &HostDatabase) -> Vec<ExporterInfo> {
+    hostdb
+        .services()
+        .into_iter()
+        .map(|(host, service)| ExporterInfo {
+            job: service.name,
+            instance: format!("{}:{}", host.name, service.port),
+            target: SocketAddr::new(host.primaryip, service.port).to_string(),
+        })
+        .collect()
+}
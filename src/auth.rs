@@ -0,0 +1,365 @@
+//! Who may call which HTTP API routes.
+//!
+//! Clients authenticate with a bearer token from the file passed with `--auth-tokens`, or with a
+//! client certificate signed by the cluster CA (`--tls-ca`). Tokens carry a role:
+//!
+//! ```json
+//! {
+//!   "tokens": [
+//!     { "name": "grafana", "token": "3f6c…", "role": "read" },
+//!     { "name": "laptop", "token": "9a1e…", "role": "control" }
+//!   ]
+//! }
+//! ```
+//!
+//! `/metrics` is always public so Prometheus can scrape without credentials. Other reads,
+//! including peers fetching the binary, need the read role; anything that changes state needs
+//! control. Client certificates from the cluster CA carry their role as the subject's
+//! organizational unit, set with `homelabd ca issue --role`; those without one get read.
+
+use crate::config::Config;
+
+use hyper::Method;
+use hyper::header::{AUTHORIZATION, HeaderMap};
+use rcgen::{CertificateParams, DnType, DnValue};
+use serde::Deserialize;
+use std::path::Path;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Deserialize,
+    clap::ValueEnum,
+    strum::IntoStaticStr,
+    strum::EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Role {
+    /// May read cluster state and fetch binaries
+    Read,
+    /// May also start rollouts, trigger tasks and wake hosts
+    Control,
+}
+
+/// What a route needs from its caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Public,
+    Requires(Role),
+}
+
+impl Role {
+    /// The role in a client certificate the CA has already verified, from its organizational
+    /// unit. Certificates without a recognised one only get read.
+    pub fn of_certificate(cert: &CertificateDer) -> Role {
+        let params = match CertificateParams::from_ca_cert_der(cert) {
+            Ok(params) => params,
+            Err(e) => {
+                log::debug!("Failed to parse client certificate: {}", e);
+                return Role::Read;
+            }
+        };
+        let unit = match params
+            .distinguished_name
+            .get(&DnType::OrganizationalUnitName)
+        {
+            Some(DnValue::Utf8String(unit)) => unit.as_str(),
+            Some(DnValue::PrintableString(unit)) => unit.as_str(),
+            _ => return Role::Read,
+        };
+        unit.parse().unwrap_or(Role::Read)
+    }
+}
+
+impl Access {
+    pub fn required(method: &Method, segments: &[&str]) -> Self {
+        match (method, segments) {
+            (_, ["metrics"]) => Access::Public,
+            (&Method::GET | &Method::HEAD, _) => Access::Requires(Role::Read),
+            _ => Access::Requires(Role::Control),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenFile {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    name: String,
+    token: String,
+    role: Role,
+}
+
+// Tokens shorter than this are too easy to guess
+const MIN_TOKEN_LENGTH: usize = 16;
+
+/// Why a request was turned away.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// No usable credentials (401)
+    Unauthenticated,
+    /// Valid credentials without the role the route needs (403)
+    Forbidden,
+}
+
+pub struct Authenticator {
+    tokens: Vec<Token>,
+    /// Off when neither tokens nor a CA are configured, leaving the API open as before
+    enabled: bool,
+}
+
+impl Authenticator {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let tokens = match &config.auth_tokens {
+            Some(path) => load(path)
+                .map_err(|e| format!("Failed to load tokens from {}: {}", path.display(), e))?,
+            None => Vec::new(),
+        };
+
+        if config.auth_tokens.is_some() && config.tls_cert.is_none() {
+            log::warn!(
+                "--auth-tokens without --tls-cert, so tokens cross the network in the clear"
+            );
+        }
+
+        Ok(Self {
+            enabled: config.auth_tokens.is_some() || config.tls_ca.is_some(),
+            tokens,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Checks a request's credentials against what the route needs. `client_role` is set when
+    /// the connection presented a certificate signed by the cluster CA.
+    pub fn authorize(
+        &self,
+        access: Access,
+        headers: &HeaderMap,
+        client_role: Option<Role>,
+    ) -> Result<(), Denied> {
+        let Access::Requires(required) = access else {
+            return Ok(());
+        };
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let role = if let Some(role) = client_role {
+            role
+        } else {
+            let presented = headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or(Denied::Unauthenticated)?;
+            let token = self
+                .tokens
+                .iter()
+                .find(|token| constant_time_eq(token.token.as_bytes(), presented.trim().as_bytes()))
+                .ok_or(Denied::Unauthenticated)?;
+            log::debug!("Request authenticated as {}", token.name);
+            token.role
+        };
+
+        if role >= required {
+            Ok(())
+        } else {
+            Err(Denied::Forbidden)
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Token>, String> {
+    let contents = std::fs::read(path).map_err(|e| e.to_string())?;
+    let file = serde_json::from_slice::<TokenFile>(&contents).map_err(|e| e.to_string())?;
+
+    for token in &file.tokens {
+        if token.token.len() < MIN_TOKEN_LENGTH {
+            return Err(format!(
+                "Token {} must be at least {} characters",
+                token.name, MIN_TOKEN_LENGTH
+            ));
+        }
+    }
+    Ok(file.tokens)
+}
+
+/// Compares without stopping at the first difference, so response times don't give away how
+/// much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use rcgen::{DistinguishedName, KeyPair};
+
+    fn certificate(unit: Option<&str>) -> CertificateDer<'static> {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "node");
+        if let Some(unit) = unit {
+            params
+                .distinguished_name
+                .push(DnType::OrganizationalUnitName, unit);
+        }
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().clone()
+    }
+
+    /// An authenticator with a read token and a control token.
+    fn authenticator() -> Authenticator {
+        let path = std::env::temp_dir().join(format!("homelabd-tokens-{}", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            serde_json::json!({
+                "tokens": [
+                    { "name": "grafana", "token": "read-token-0123456789", "role": "read" },
+                    { "name": "laptop", "token": "control-token-0123456789", "role": "control" },
+                ]
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let config = Config::parse_from(["homelabd", "--auth-tokens", path.to_str().unwrap()]);
+        let authenticator = Authenticator::new(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        authenticator
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn reads_need_read_and_changes_need_control() {
+        assert_eq!(Access::required(&Method::GET, &["metrics"]), Access::Public);
+        assert_eq!(
+            Access::required(&Method::POST, &["metrics"]),
+            Access::Public
+        );
+        assert_eq!(
+            Access::required(&Method::GET, &["hosts"]),
+            Access::Requires(Role::Read)
+        );
+        assert_eq!(
+            Access::required(&Method::HEAD, &["homelabd"]),
+            Access::Requires(Role::Read)
+        );
+        assert_eq!(
+            Access::required(&Method::POST, &["rollout"]),
+            Access::Requires(Role::Control)
+        );
+        assert_eq!(
+            Access::required(&Method::DELETE, &["rollout", "hold"]),
+            Access::Requires(Role::Control)
+        );
+    }
+
+    #[test]
+    fn takes_the_role_from_the_certificate_unit() {
+        assert_eq!(
+            Role::of_certificate(&certificate(Some("control"))),
+            Role::Control
+        );
+        assert_eq!(Role::of_certificate(&certificate(Some("read"))), Role::Read);
+        assert_eq!(Role::of_certificate(&certificate(None)), Role::Read);
+        assert_eq!(
+            Role::of_certificate(&certificate(Some("admin"))),
+            Role::Read
+        );
+        assert_eq!(
+            Role::of_certificate(&CertificateDer::from(vec![1, 2, 3])),
+            Role::Read
+        );
+    }
+
+    #[test]
+    fn checks_tokens_against_the_route() {
+        let authenticator = authenticator();
+        let read = Access::Requires(Role::Read);
+        let control = Access::Requires(Role::Control);
+
+        assert_eq!(
+            authenticator.authorize(read, &bearer("read-token-0123456789"), None),
+            Ok(())
+        );
+        assert_eq!(
+            authenticator.authorize(control, &bearer("read-token-0123456789"), None),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(
+            authenticator.authorize(control, &bearer("control-token-0123456789"), None),
+            Ok(())
+        );
+        assert_eq!(
+            authenticator.authorize(read, &bearer("read-token-012345678"), None),
+            Err(Denied::Unauthenticated)
+        );
+        assert_eq!(
+            authenticator.authorize(read, &HeaderMap::new(), None),
+            Err(Denied::Unauthenticated)
+        );
+        assert_eq!(
+            authenticator.authorize(Access::Public, &HeaderMap::new(), None),
+            Ok(())
+        );
+        // A CA-signed certificate stands in for a token
+        assert_eq!(
+            authenticator.authorize(control, &HeaderMap::new(), Some(Role::Control)),
+            Ok(())
+        );
+        assert_eq!(
+            authenticator.authorize(control, &HeaderMap::new(), Some(Role::Read)),
+            Err(Denied::Forbidden)
+        );
+    }
+
+    #[test]
+    fn is_open_without_tokens_or_a_ca() {
+        let authenticator = Authenticator::new(&Config::parse_from(["homelabd"])).unwrap();
+        assert!(!authenticator.enabled());
+        assert_eq!(
+            authenticator.authorize(Access::Requires(Role::Control), &HeaderMap::new(), None),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_short_tokens() {
+        let path = std::env::temp_dir().join(format!("homelabd-tokens-{}", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"{ "tokens": [{ "name": "weak", "token": "guessable", "role": "read" }] }"#,
+        )
+        .unwrap();
+        let error = load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.contains("weak"), "{}", error);
+    }
+
+    #[test]
+    fn compares_tokens_in_full() {
+        assert!(constant_time_eq(b"same-token", b"same-token"));
+        assert!(!constant_time_eq(b"same-token", b"same-tokem"));
+        assert!(!constant_time_eq(b"same-token", b"same-token-longer"));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+}
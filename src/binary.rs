@@ -0,0 +1,317 @@
+//! The running executable, as served to peers at `/homelabd`.
+
+use crate::proto::homelabd::CachedBinary;
+use crate::update;
+
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+pub const BINARY_PATH: &str = "/proc/self/exe";
+
+/// Target triple this binary was built for.
+pub const TARGET: &str = env!("HOMELABD_TARGET");
+
+// Where builds for other targets are kept, under the state directory
+const CACHE_DIR: &str = "binaries";
+
+// Size of each chunk read from disk and sent to the client
+const READ_CHUNK: usize = 64 * 1024;
+
+static CURRENT: OnceCell<BinaryInfo> = OnceCell::new();
+
+#[derive(Debug, Clone)]
+pub struct BinaryInfo {
+    pub size: u64,
+    /// Hex SHA-256 of the executable. Peers with the same hash run the same build, so any of
+    /// them can serve it.
+    pub hash: String,
+}
+
+impl BinaryInfo {
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.hash)
+    }
+
+    /// The zstd-encoded binary is a different representation, so it has a tag of its own.
+    pub fn zstd_etag(&self) -> String {
+        format!("\"{}-zstd\"", self.hash)
+    }
+}
+
+/// The running executable's size and hash, worked out on first use. `/proc/self/exe` keeps
+/// pointing at the running build even after an update replaces the file on disk.
+pub fn current() -> io::Result<&'static BinaryInfo> {
+    CURRENT.get_or_try_init(|| {
+        let mut file = File::open(BINARY_PATH)?;
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut file, &mut hasher)?;
+        Ok(BinaryInfo {
+            size,
+            hash: hex(&hasher.finalize()),
+        })
+    })
+}
+
+/// The build hash to announce, empty if the executable can't be read.
+pub fn build_hash() -> String {
+    current().map(|info| info.hash.clone()).unwrap_or_default()
+}
+
+/// Copies of builds for other targets, kept so peers of that architecture can update from this
+/// node. Laid out as `binaries/{target}/{version}`, with the hash alongside in `{version}.sha256`.
+#[derive(Clone)]
+pub struct BinaryCache {
+    dir: PathBuf,
+}
+
+impl BinaryCache {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            dir: state_dir.join(CACHE_DIR),
+        }
+    }
+
+    /// Every build in the cache.
+    pub fn entries(&self) -> Vec<CachedBinary> {
+        let mut entries = Vec::new();
+        for target in read_dir_names(&self.dir) {
+            for version in read_dir_names(&self.dir.join(&target)) {
+                if version.ends_with(".sha256") {
+                    continue;
+                }
+                if let Some((_, info)) = self.get(&target, &version) {
+                    entries.push(CachedBinary {
+                        target: target.clone(),
+                        version: version.to_string(),
+                        build_hash: info.hash,
+                    });
+                }
+            }
+        }
+        entries
+    }
+
+    /// A cached build and its hash. Builds without a saved hash are left out until
+    /// `hash_unhashed` has seen them.
+    pub fn get(&self, target: &str, version: &str) -> Option<(PathBuf, BinaryInfo)> {
+        if !valid_component(target) || !valid_component(version) {
+            return None;
+        }
+        let path = self.dir.join(target).join(version);
+        let size = fs::metadata(&path).ok()?.len();
+        let hash = fs::read_to_string(hash_path(&path))
+            .ok()?
+            .trim()
+            .to_string();
+        Some((path, BinaryInfo { size, hash }))
+    }
+
+    /// Hashes builds placed in the cache by hand. Reads every such build in full, so it's run
+    /// once at startup rather than when serving.
+    pub fn hash_unhashed(&self) {
+        for target in read_dir_names(&self.dir) {
+            for version in read_dir_names(&self.dir.join(&target)) {
+                let path = self.dir.join(&target).join(&version);
+                if version.ends_with(".sha256")
+                    || !valid_component(&version)
+                    || hash_path(&path).exists()
+                {
+                    continue;
+                }
+                let result =
+                    fs::read(&path).and_then(|binary| fs::write(hash_path(&path), sha256(&binary)));
+                match result {
+                    Ok(()) => log::info!("Hashed cached {} build of {}", target, version),
+                    Err(e) => log::warn!("Failed to hash {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+
+    /// Adds a build, renaming it into place last so a half-written copy is never served.
+    pub fn store(&self, target: &str, version: &str, binary: &[u8]) -> io::Result<()> {
+        if !valid_component(target) || !valid_component(version) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid target or version: {}/{}", target, version),
+            ));
+        }
+        let dir = self.dir.join(target);
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(version);
+        let staging = dir.join(format!(".{}.new", version));
+        fs::write(&staging, binary)?;
+        fs::write(hash_path(&path), sha256(binary))?;
+        fs::rename(&staging, &path)
+    }
+
+    /// Drops all but the newest `keep` versions for `target`.
+    pub fn prune(&self, target: &str, keep: usize) {
+        let mut versions = self
+            .entries()
+            .into_iter()
+            .filter(|entry| entry.target == target)
+            .map(|entry| entry.version)
+            .collect::<Vec<_>>();
+        versions.sort_by(|a, b| {
+            if update::is_newer(a, b) {
+                std::cmp::Ordering::Less
+            } else if update::is_newer(b, a) {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        });
+
+        for version in versions.into_iter().skip(keep) {
+            log::info!("Removing cached {} build of {}", target, version);
+            let path = self.dir.join(target).join(&version);
+            let _ = fs::remove_file(hash_path(&path));
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Appended rather than `with_extension`, which would replace the last part of the version
+fn hash_path(path: &Path) -> PathBuf {
+    let mut hash_path = path.as_os_str().to_owned();
+    hash_path.push(".sha256");
+    PathBuf::from(hash_path)
+}
+
+/// Targets and versions become path components, so keep them to what triples and versions use.
+fn valid_component(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+'))
+}
+
+fn read_dir_names(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn sha256(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a single `Range: bytes=...` against a body of `size` bytes into an inclusive range.
+/// Returns `Ok(None)` for ranges to ignore (multiple ranges, other units), so the whole body is
+/// sent, and `Err` for ranges that can't be satisfied.
+pub fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, size.saturating_sub(1)),
+        (start, end) => (
+            start.parse().map_err(|_| ())?,
+            end.parse::<u64>()
+                .map_err(|_| ())?
+                .min(size.saturating_sub(1)),
+        ),
+    };
+
+    if start > end || start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Reads `len` bytes of `path` from `start`, optionally zstd-compressed, handing each chunk to
+/// `send` until it returns false. Blocking, so run it on its own thread.
+pub fn read_range(
+    path: &Path,
+    start: u64,
+    len: u64,
+    compress: bool,
+    mut send: impl FnMut(Vec<u8>) -> bool,
+) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let section = file.take(len);
+
+    let mut reader: Box<dyn Read> = if compress {
+        Box::new(zstd::stream::read::Encoder::new(section, 0)?)
+    } else {
+        Box::new(section)
+    };
+
+    loop {
+        let mut chunk = vec![0; READ_CHUNK];
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(());
+        }
+        chunk.truncate(read);
+        if !send(chunk) {
+            // The client went away
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_range;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-0", 100), Ok(Some((0, 0))));
+        assert_eq!(parse_range("bytes=10-19", 100), Ok(Some((10, 19))));
+        // Ends past the body are clamped to it
+        assert_eq!(parse_range("bytes=90-200", 100), Ok(Some((90, 99))));
+    }
+
+    #[test]
+    fn parses_open_ended_and_suffix_ranges() {
+        assert_eq!(parse_range("bytes=40-", 100), Ok(Some((40, 99))));
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some((90, 99))));
+        // A suffix longer than the body is the whole body
+        assert_eq!(parse_range("bytes=-500", 100), Ok(Some((0, 99))));
+    }
+
+    #[test]
+    fn ignores_ranges_it_does_not_serve() {
+        assert_eq!(parse_range("items=0-10", 100), Ok(None));
+        assert_eq!(parse_range("bytes=0-10,20-30", 100), Ok(None));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), Err(()));
+        assert_eq!(parse_range("bytes=20-10", 100), Err(()));
+        assert_eq!(parse_range("bytes=-0", 100), Err(()));
+        assert_eq!(parse_range("bytes=0-0", 0), Err(()));
+        assert_eq!(parse_range("bytes=a-b", 100), Err(()));
+        assert_eq!(parse_range("bytes=10", 100), Err(()));
+    }
+}
@@ -0,0 +1,114 @@
+//! Capture files written by `homelabd sniff`: a magic line followed by length-delimited
+//! `CaptureRecord`s, so a capture can be appended to and read back without loading it whole.
+
+use crate::dispatch::MessageContext;
+use crate::proto::homelabd::CaptureRecord;
+
+use prost::Message;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: &[u8] = b"homelabd-capture-v1\n";
+
+pub struct CaptureWriter {
+    file: BufWriter<File>,
+    records: usize,
+}
+
+impl CaptureWriter {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        Ok(Self { file, records: 0 })
+    }
+
+    pub fn write(&mut self, data: &[u8], context: &MessageContext) -> std::io::Result<()> {
+        let record = CaptureRecord {
+            received_at_micros: context
+                .received_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            source: context.source.to_string(),
+            interface: context.interface.clone().unwrap_or_default(),
+            data: data.to_vec(),
+        };
+
+        self.file
+            .write_all(&record.encode_length_delimited_to_vec())?;
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+
+    pub fn records(&self) -> usize {
+        self.records
+    }
+}
+
+pub struct CaptureReader {
+    file: BufReader<File>,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let mut file = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+
+        let mut magic = vec![0u8; MAGIC.len()];
+        file.read_exact(&mut magic)
+            .map_err(|_| "File is too short to be a capture".to_string())?;
+        if magic != MAGIC {
+            return Err("Not a homelabd capture file".to_string());
+        }
+
+        Ok(Self { file })
+    }
+
+    /// Reads the next record, or None at the end of the capture.
+    pub fn next_record(&mut self) -> Result<Option<CaptureRecord>, String> {
+        let Some(len) = self.read_length()? else {
+            return Ok(None);
+        };
+
+        let mut data = vec![0u8; len];
+        self.file
+            .read_exact(&mut data)
+            .map_err(|e| format!("Truncated capture record: {}", e))?;
+        CaptureRecord::decode(data.as_slice())
+            .map(Some)
+            .map_err(|e| format!("Invalid capture record: {}", e))
+    }
+
+    /// Reads a varint length prefix a byte at a time, since the record's size isn't known yet.
+    fn read_length(&mut self) -> Result<Option<usize>, String> {
+        let mut len = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8; 1];
+            match self.file.read_exact(&mut byte) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && shift == 0 => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(format!("Truncated capture record: {}", e)),
+            }
+
+            len |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(len as usize));
+            }
+        }
+
+        Err("Invalid capture record length".to_string())
+    }
+}
+
+impl CaptureRecord {
+    pub fn received_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.received_at_micros)
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Source of the current time for anything that ages out state, so replays can run on the
+/// timestamps in a capture rather than the wall clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct VirtualClock {
+    now: Mutex<SystemTime>,
+}
+
+impl VirtualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(start),
+        }
+    }
+
+    /// Moves the clock forward to `time`. Time never goes backwards, so out-of-order records
+    /// don't undo evictions that have already happened.
+    pub fn advance_to(&self, time: SystemTime) {
+        let mut now = self.now.lock().unwrap();
+        if time > *now {
+            *now = time;
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
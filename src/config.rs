@@ -1,17 +1,31 @@
-use clap::{Args, Parser};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use ipnet::IpNet;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
 #[command(name = "homelabd", about = "Peer daemon for your homelab")]
 pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Multicast group address (IPv4)
     #[arg(long, default_value = "239.255.0.1")]
     pub multicast_group: Ipv4Addr,
 
-    /// Multicast port
+    /// Multicast port, also used by the unicast transport
     #[arg(long, default_value_t = 44044)]
     pub multicast_port: u16,
 
+    /// How to reach peers
+    #[arg(long, value_enum, default_value_t = TransportKind::Multicast)]
+    pub transport: TransportKind,
+
+    /// Peers to send to with the unicast transport (comma-separated ip:port). Include this node
+    /// if it should see its own announcements, as it does over multicast
+    #[arg(long, value_delimiter = ',')]
+    pub unicast_peers: Vec<SocketAddr>,
+
     /// HTTP bind address
     #[arg(long, default_value = "0.0.0.0")]
     pub http_bind_ip: IpAddr,
@@ -20,11 +34,129 @@ pub struct Config {
     #[arg(long, default_value_t = 8800)]
     pub http_port: u16,
 
+    /// Serve the HTTP API over HTTPS with this PEM certificate chain
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Cluster CA certificate (see `homelabd ca`). Clients presenting a certificate it signed get
+    /// the API role in its organizational unit, or read without one, and peers' HTTPS
+    /// certificates are checked against it instead of the public roots
+    #[arg(long)]
+    pub tls_ca: Option<PathBuf>,
+
+    /// File holding a secret shared by every node. Leases, update requests and Wake-on-LAN
+    /// requests are then signed with it, and unsigned ones are ignored
+    #[arg(long)]
+    pub cluster_key: Option<PathBuf>,
+
+    /// JSON file of bearer tokens that may use the HTTP API, and what each may do. With neither
+    /// this nor --tls-ca, the API is open to anyone who can reach it
+    #[arg(long)]
+    pub auth_tokens: Option<PathBuf>,
+
+    /// Bearer token to present to the HTTP API, by `ctl` and when fetching updates from peers
+    #[arg(long, env = "HOMELABD_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Serve DNS for known hosts and services under this zone (e.g. lab.internal)
+    #[arg(long)]
+    pub dns_zone: Option<String>,
+
+    /// DNS bind address
+    #[arg(long, default_value = "0.0.0.0")]
+    pub dns_bind_ip: IpAddr,
+
+    /// DNS port
+    #[arg(long, default_value_t = 53)]
+    pub dns_port: u16,
+
     /// Override the hostname
     #[arg(long)]
     pub hostname_override: Option<String>,
 
+    /// JSON file configuring where to send notifications about cluster events
+    #[arg(long)]
+    pub notifications: Option<PathBuf>,
+
+    /// Directory for state that must survive restarts, such as the node ID
+    #[arg(long, default_value = "/var/lib/homelabd")]
+    pub state_dir: PathBuf,
+
+    /// Announce this address as our primary IP instead of letting peers choose one
+    #[arg(long)]
+    pub primary_ip: Option<IpAddr>,
+
+    /// Prefer peer addresses in these networks when choosing their primary IP
+    #[arg(long, value_delimiter = ',')]
+    pub preferred_cidrs: Vec<IpNet>,
+
+    /// Avoid peer addresses in these networks (e.g. Docker bridges) when choosing their primary IP
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "172.17.0.0/16,169.254.0.0/16"
+    )]
+    pub excluded_cidrs: Vec<IpNet>,
+
+    /// Confirm peer primary IPs with a forward DNS lookup of their hostname
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub confirm_primary_ip_dns: bool,
+
+    /// Hosts (hostnames or node IDs) that rollouts must never update. A node in its own list
+    /// also refuses update requests
+    #[arg(long, value_delimiter = ',')]
+    pub rollout_hold: Vec<String>,
+
+    /// How many nodes a rollout updates first, before any batches
+    #[arg(long, default_value_t = 1)]
+    pub rollout_canaries: usize,
+
+    /// How many nodes a rollout updates at once after the canaries are healthy
+    #[arg(long, default_value_t = 2)]
+    pub rollout_batch_size: usize,
+
+    /// How long a node has to come back on the new version before the rollout pauses
+    #[arg(long, default_value_t = 300)]
+    pub rollout_health_timeout_seconds: u64,
+
+    /// How long an update must run healthily before the previous binary is no longer needed
+    #[arg(long, default_value_t = 120)]
+    pub update_confirm_seconds: u64,
+
+    /// Keep a copy of the newest build for each other target peers run, and serve it at
+    /// /homelabd/{target}/{version}. Builds can also be put in <state-dir>/binaries/{target}/{version}
+    /// by hand
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub cache_binaries: bool,
+
     /// Enable Prometheus discover emission, if /etc/prometheus exists
     #[arg(long, default_value_t = true)]
     pub prometheus_discovery: bool,
 }
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Query and drive a running daemon
+    Ctl(crate::ctl::CtlArgs),
+    /// Print multicast traffic without joining the cluster, optionally capturing it to a file
+    Sniff(crate::sniff::SniffArgs),
+    /// Replay a capture through the receivers on virtual time and print the resulting state
+    Replay(crate::replay::ReplayArgs),
+    /// Run several nodes in one process on a simulated network and report what each sees
+    #[cfg(any(test, feature = "sim"))]
+    Sim(crate::sim::SimArgs),
+    /// Send a sample notification through every sink in --notifications
+    NotifyTest,
+    /// Create a cluster CA and issue certificates from it for --tls-cert and --tls-ca
+    Ca(crate::tls::CaArgs),
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Multicast,
+    Unicast,
+}
@@ -0,0 +1,383 @@
+use crate::api::{
+    ExporterInfo, HostInfo, LeaderInfo, LeaderboardEntry, RolloutStatus, ServiceInfo, TaskInfo,
+};
+use crate::config::Config;
+use crate::net::{self, Network};
+use crate::proto::homelabd::{TestMessage, envelope};
+use crate::sniff::{self, SniffArgs};
+use crate::tls;
+
+use clap::{Args, Subcommand, ValueEnum};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::AUTHORIZATION;
+use hyper::{Method, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Args, Debug, Clone)]
+pub struct CtlArgs {
+    /// Base URL of the daemon's HTTP API [default: the local daemon on --http-port, over HTTPS
+    /// if --tls-cert is set]
+    #[arg(long)]
+    pub url: Option<String>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub output: OutputFormat,
+
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CtlCommand {
+    /// List hosts known to the daemon
+    Hosts,
+    /// List services announced by hosts
+    Services,
+    /// List Prometheus exporters and their scrape targets
+    Exporters,
+    /// Rank hosts by uptime
+    Leaderboard,
+    /// Show which node currently leads the cluster
+    Leader,
+    /// Show or drive the leader's rolling update
+    Rollout {
+        #[command(subcommand)]
+        command: Option<RolloutCommand>,
+    },
+    /// List scheduled tasks
+    Tasks,
+    /// Run a scheduled task now
+    Trigger { task: String },
+    /// Wake a sleeping host through a peer on its network
+    Wake { host: String },
+    /// Print multicast traffic as it arrives (same as `homelabd sniff`)
+    Watch,
+    /// Send a test message to the multicast group
+    SendTest {
+        #[arg(default_value = "hello from homelabd ctl")]
+        text: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum RolloutCommand {
+    /// Show the current rollout (the default)
+    Status,
+    /// Update every node to a version some node is already running
+    Start { version: String },
+    /// Stop starting new updates
+    Pause,
+    /// Continue a paused rollout, retrying failed nodes
+    Resume,
+    /// Give up on the current rollout
+    Abort,
+    /// Never update this host (hostname or node ID)
+    Hold { host: String },
+    /// Allow a held host to be updated again
+    Release { host: String },
+}
+
+pub async fn run(config: &Config, args: CtlArgs) -> Result<(), Error> {
+    let client = ApiClient {
+        base: args.url.clone().unwrap_or_else(|| {
+            let scheme = if config.tls_cert.is_some() {
+                "https"
+            } else {
+                "http"
+            };
+            format!("{}://127.0.0.1:{}", scheme, config.http_port)
+        }),
+        client: tls::client(config)?,
+        token: config.token.clone(),
+    };
+    let output = args.output;
+
+    match args.command {
+        CtlCommand::Hosts => {
+            let hosts: Vec<HostInfo> = client.get("/hosts").await?;
+            print_rows(
+                output,
+                &hosts,
+                &[
+                    "HOSTNAME",
+                    "PRIMARY IP",
+                    "VERSION",
+                    "UPTIME",
+                    "LAST SEEN",
+                    "NODE ID",
+                ],
+                |host| {
+                    vec![
+                        host.hostname.clone(),
+                        host.primary_ip.clone(),
+                        host.version.clone(),
+                        format_duration(host.uptime_seconds.max(0) as u64),
+                        format!("{} ago", format_duration(host.last_seen_seconds)),
+                        host.node_id.clone(),
+                    ]
+                },
+            )
+        }
+        CtlCommand::Services => {
+            let services: Vec<ServiceInfo> = client.get("/services").await?;
+            print_rows(output, &services, &["SERVICE", "HOST", "ADDRESS"], |s| {
+                vec![
+                    s.name.clone(),
+                    s.host.clone(),
+                    format!("{}:{}", s.address, s.port),
+                ]
+            })
+        }
+        CtlCommand::Exporters => {
+            let exporters: Vec<ExporterInfo> = client.get("/exporters").await?;
+            print_rows(output, &exporters, &["JOB", "INSTANCE", "TARGET"], |e| {
+                vec![e.job.clone(), e.instance.clone(), e.target.clone()]
+            })
+        }
+        CtlCommand::Leaderboard => {
+            let entries: Vec<LeaderboardEntry> = client.get("/leaderboard").await?;
+            print_rows(
+                output,
+                &entries,
+                &["#", "HOSTNAME", "UPTIME", "VERSION"],
+                |e| {
+                    vec![
+                        e.rank.to_string(),
+                        e.hostname.clone(),
+                        format_duration(e.uptime_seconds.max(0) as u64),
+                        e.version.clone(),
+                    ]
+                },
+            )
+        }
+        CtlCommand::Leader => {
+            let leader: LeaderInfo = client.get("/leader").await?;
+            print_value(output, &serde_json::to_value(&leader)?, || {
+                format!(
+                    "{} ({}) leads in term {}, lease expires in {}",
+                    leader.hostname,
+                    leader.node_id,
+                    leader.term,
+                    format_duration(leader.expires_in_seconds)
+                )
+            })
+        }
+        CtlCommand::Rollout { command } => {
+            let status: RolloutStatus = match command.unwrap_or(RolloutCommand::Status) {
+                RolloutCommand::Status => client.get("/rollout").await?,
+                RolloutCommand::Start { version } => {
+                    client.post(&format!("/rollout/start/{}", version)).await?
+                }
+                RolloutCommand::Pause => client.post("/rollout/pause").await?,
+                RolloutCommand::Resume => client.post("/rollout/resume").await?,
+                RolloutCommand::Abort => client.post("/rollout/abort").await?,
+                RolloutCommand::Hold { host } => {
+                    client.post(&format!("/rollout/hold/{}", host)).await?
+                }
+                RolloutCommand::Release { host } => {
+                    client.post(&format!("/rollout/release/{}", host)).await?
+                }
+            };
+            print_rollout(output, &status)
+        }
+        CtlCommand::Tasks => {
+            let tasks: Vec<TaskInfo> = client.get("/tasks").await?;
+            print_rows(output, &tasks, &["TASK", "INTERVAL"], |t| {
+                vec![t.name.clone(), format_duration(t.interval_seconds)]
+            })
+        }
+        CtlCommand::Trigger { task } => {
+            let response: serde_json::Value = client.post(&format!("/tasks/{}", task)).await?;
+            print_value(output, &response, || format!("Triggered {}", task))
+        }
+        CtlCommand::Wake { host } => {
+            let plan: serde_json::Value = client.post(&format!("/hosts/{}/wake", host)).await?;
+            print_value(output, &plan, || {
+                format!(
+                    "Asked {} to wake {} ({})",
+                    plan["relay_hostname"].as_str().unwrap_or_default(),
+                    plan["target"].as_str().unwrap_or_default(),
+                    plan["target_mac"].as_str().unwrap_or_default()
+                )
+            })
+        }
+        CtlCommand::Watch => {
+            let args = SniffArgs {
+                capture: None,
+                output,
+            };
+            sniff::sniff(config, args).await
+        }
+        CtlCommand::SendTest { text } => {
+            // ctl isn't a peer, so don't claim the daemon's persistent identity
+            let transport = net::transport(config)?;
+            let backend = transport.name();
+            let network = Network::new(transport, format!("ctl-{}", uuid::Uuid::new_v4()));
+            network
+                .send(envelope::Msg::Test(TestMessage { text }))
+                .await?;
+            println!("Sent test message over {}", backend);
+            Ok(())
+        }
+    }
+}
+
+struct ApiClient {
+    base: String,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    token: Option<String>,
+}
+
+impl ApiClient {
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        self.request(Method::GET, path).await
+    }
+
+    async fn post<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        self.request(Method::POST, path).await
+    }
+
+    async fn request<T: DeserializeOwned>(&self, method: Method, path: &str) -> Result<T, Error> {
+        let url = format!("{}{}", self.base.trim_end_matches('/'), path);
+        let mut request = Request::builder().method(method).uri(&url);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Full::new(Bytes::new()))?;
+
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+
+        if status != StatusCode::OK && status != StatusCode::ACCEPTED {
+            let message = serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|v| v["error"].as_str().map(String::from))
+                .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+            return Err(format!("{} returned {}: {}", url, status, message).into());
+        }
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+fn print_rows<T: Serialize>(
+    output: OutputFormat,
+    items: &[T],
+    headers: &[&str],
+    row: impl Fn(&T) -> Vec<String>,
+) -> Result<(), Error> {
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(items)?);
+        return Ok(());
+    }
+
+    let rows = items.iter().map(row).collect::<Vec<_>>();
+    let mut widths = headers.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let print_line = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+
+    print_line(headers.to_vec());
+    for row in &rows {
+        print_line(row.iter().map(String::as_str).collect());
+    }
+    Ok(())
+}
+
+fn print_rollout(output: OutputFormat, status: &RolloutStatus) -> Result<(), Error> {
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(status)?);
+        return Ok(());
+    }
+
+    let mut summary = format!("Rollout {}", format!("{:?}", status.state).to_lowercase());
+    if let Some(version) = &status.version {
+        summary += &format!(" to {}", version);
+    }
+    if let Some(reason) = &status.reason {
+        summary += &format!(": {}", reason);
+    }
+    if !status.is_leader {
+        summary += " (this node isn't the leader; ask it instead)";
+    }
+    println!("{}", summary);
+    if !status.held.is_empty() {
+        println!("Held: {}", status.held.join(", "));
+    }
+    if status.nodes.is_empty() {
+        return Ok(());
+    }
+
+    println!();
+    print_rows(
+        output,
+        &status.nodes,
+        &["HOSTNAME", "WAVE", "STATE", "SOURCE", "NODE ID"],
+        |n| {
+            let state = match n.updating_seconds {
+                Some(seconds) => format!("{:?} for {}", n.state, format_duration(seconds)),
+                None => format!("{:?}", n.state),
+            };
+            vec![
+                n.hostname.clone(),
+                if n.wave == 0 {
+                    "canary".to_string()
+                } else {
+                    n.wave.to_string()
+                },
+                state.to_lowercase(),
+                n.source.clone(),
+                n.node_id.clone(),
+            ]
+        },
+    )
+}
+
+fn print_value(
+    output: OutputFormat,
+    value: &serde_json::Value,
+    summary: impl Fn() -> String,
+) -> Result<(), Error> {
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+        OutputFormat::Table => println!("{}", summary()),
+    }
+    Ok(())
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, m) => format!("{}m {}s", m, seconds % 60),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, _) => format!("{}d {}h", d, h),
+    }
+}
@@ -1,54 +1,674 @@
+use crate::cluster_key::ClusterKey;
+use crate::fragment::Reassembler;
 use crate::metrics;
-use crate::proto::homelabd::Envelope;
+use crate::proto::homelabd::{Envelope, Fragment, Signed, envelope};
+use crate::protocol;
+use crate::receivers::hostdb::HostDatabase;
 
 use prost::Message;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime};
+use tokio::sync::{Notify, mpsc};
 
+// Number of decoded messages that can be waiting on a single handler before new ones are dropped
+const HANDLER_QUEUE_DEPTH: usize = 64;
+
+/// The kinds of message an `Envelope` can carry, used to route messages to the handlers that
+/// asked for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    SystemInfo,
+    PrometheusDiscovery,
+    WakeOnLan,
+    Test,
+    Lease,
+    UpdateRequest,
+    /// Consumed by the dispatcher itself; handlers receive the reassembled message instead
+    Fragment,
+    /// Also consumed by the dispatcher, which hands on the inner message once it's verified
+    Signed,
+}
+
+impl MessageType {
+    pub fn of(msg: &envelope::Msg) -> Self {
+        match msg {
+            envelope::Msg::SystemInfo(_) => MessageType::SystemInfo,
+            envelope::Msg::PrometheusDiscovery(_) => MessageType::PrometheusDiscovery,
+            envelope::Msg::WakeOnLan(_) => MessageType::WakeOnLan,
+            envelope::Msg::Test(_) => MessageType::Test,
+            envelope::Msg::Lease(_) => MessageType::Lease,
+            envelope::Msg::UpdateRequest(_) => MessageType::UpdateRequest,
+            envelope::Msg::Fragment(_) => MessageType::Fragment,
+            envelope::Msg::Signed(_) => MessageType::Signed,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::SystemInfo => "SystemInfo",
+            MessageType::PrometheusDiscovery => "PrometheusDiscovery",
+            MessageType::WakeOnLan => "WakeOnLan",
+            MessageType::Test => "Test",
+            MessageType::Lease => "Lease",
+            MessageType::UpdateRequest => "UpdateRequest",
+            MessageType::Fragment => "Fragment",
+            MessageType::Signed => "Signed",
+        }
+    }
+
+    /// Messages that make nodes act for the cluster, which must be signed when there's a
+    /// cluster key.
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            MessageType::Lease | MessageType::UpdateRequest | MessageType::WakeOnLan
+        )
+    }
+}
+
+/// Where and when a message was received, handed to handlers alongside the decoded envelope.
+#[derive(Clone, Debug)]
+pub struct MessageContext {
+    pub source: SocketAddr,
+    /// Local interface whose subnet contains the sender, if it could be determined
+    pub interface: Option<String>,
+    pub received_at: SystemTime,
+    pub size: usize,
+}
+
+#[async_trait::async_trait]
 pub trait Dispatchable: Send + Sync {
     fn dispatcher_name(&self) -> &'static str;
 
-    fn dispatch(&self, message: &Envelope) -> Result<(), String>;
+    /// Message types this handler wants to receive. Nothing else is delivered to it.
+    fn message_types(&self) -> &'static [MessageType];
+
+    async fn dispatch(&self, message: &Envelope, context: &MessageContext) -> Result<(), String>;
+}
+
+struct Delivery {
+    envelope: Envelope,
+    context: MessageContext,
+}
+
+/// Messages queued for or being handled by one handler, so callers can wait for it to catch up.
+#[derive(Default)]
+struct Pending {
+    count: AtomicUsize,
+    drained: Notify,
+}
+
+impl Pending {
+    fn done(&self) {
+        if self.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.drained.notify_waiters();
+        }
+    }
+}
+
+struct HandlerQueue {
+    name: &'static str,
+    sender: mpsc::Sender<Arc<Delivery>>,
+    pending: Arc<Pending>,
 }
 
 pub struct Dispatcher {
-    handlers: Vec<Arc<dyn Dispatchable>>,
+    handlers: Vec<HandlerQueue>,
+    routes: HashMap<MessageType, Vec<usize>>,
+    reassembler: Reassembler,
+    sequences: protocol::SequenceTracker,
+    /// Kept apart from unsigned messages, so a forged header can't make a genuine signed
+    /// message look like a duplicate
+    signed_sequences: protocol::SequenceTracker,
+    /// Control messages are only routed if signed with this, when set
+    cluster_key: Option<ClusterKey>,
+    /// Known peers, the only senders counted under their own label
+    peers: Option<Arc<HostDatabase>>,
 }
 
 impl Dispatcher {
     pub fn new() -> Self {
         Self {
             handlers: Vec::new(),
+            routes: HashMap::new(),
+            reassembler: Reassembler::new(),
+            sequences: protocol::SequenceTracker::default(),
+            signed_sequences: protocol::SequenceTracker::default(),
+            cluster_key: None,
+            peers: None,
         }
     }
 
+    /// Lets the dispatcher see which peers are around, so messages are only counted by sender
+    /// for hosts it already knows. Anyone can put anything in a header.
+    pub fn watch_peers(&mut self, hostdb: Arc<HostDatabase>) {
+        self.peers = Some(hostdb);
+    }
+
+    /// Requires control messages to be signed with `key`, if there is one.
+    pub fn with_cluster_key(mut self, key: Option<ClusterKey>) -> Self {
+        self.cluster_key = key;
+        self
+    }
+
+    /// Registers a handler and spawns the task that feeds it from its own bounded queue, so a
+    /// slow handler only ever delays its own messages.
     pub fn register<T: Dispatchable + 'static>(&mut self, handler: Arc<T>) {
+        let name = handler.dispatcher_name();
+        let message_types = handler.message_types();
         log::info!(
-            "Registering dispatch handler: {}",
-            handler.dispatcher_name()
+            "Registering dispatch handler: {} for {:?}",
+            name,
+            message_types
         );
-        self.handlers.push(handler);
+
+        let (sender, receiver) = mpsc::channel(HANDLER_QUEUE_DEPTH);
+        let pending = Arc::new(Pending::default());
+        tokio::spawn(run_handler(handler, receiver, Arc::clone(&pending)));
+
+        let index = self.handlers.len();
+        self.handlers.push(HandlerQueue {
+            name,
+            sender,
+            pending,
+        });
+        for message_type in message_types {
+            self.routes.entry(*message_type).or_default().push(index);
+        }
     }
 
-    pub fn dispatch(&self, buf: &[u8]) {
+    pub fn dispatch(&self, buf: &[u8], context: MessageContext) {
         metrics::MESSAGES_RECEIVED.inc();
 
         match Envelope::decode(buf) {
-            Ok(env) => {
-                for handler in &self.handlers {
-                    metrics::MESSAGES_DISPATCHED.inc();
-                    if let Err(e) = handler.dispatch(&env) {
-                        metrics::MESSAGES_FAILED_DISPATCH
-                            .with_label_values(&[handler.dispatcher_name()])
-                            .inc();
-                        log::warn!(
-                            "Dispatcher {} failed to handle message: {}",
-                            handler.dispatcher_name(),
-                            e
-                        );
-                    }
+            Ok(env) => self.route(env, context, false),
+            Err(e) => {
+                metrics::DECODE_FAILURES.inc();
+                log::warn!("Failed to decode message: {}", e)
+            }
+        }
+    }
+
+    /// Hands a message to its handlers. `signed` is set once its cluster key signature has been
+    /// checked.
+    fn route(&self, env: Envelope, context: MessageContext, signed: bool) {
+        // Variants this build doesn't know about decode to an empty oneof
+        let Some(msg) = &env.msg else {
+            metrics::MESSAGES_UNKNOWN.inc();
+            match &env.header {
+                Some(header) if header.protocol_version > protocol::PROTOCOL_VERSION => {
+                    log::debug!(
+                        "Ignoring unknown message from {} (protocol {}, we speak {})",
+                        header.node_id,
+                        header.protocol_version,
+                        protocol::PROTOCOL_VERSION
+                    )
                 }
+                _ => log::debug!("Ignoring message with an unknown or empty payload"),
+            }
+            return;
+        };
+
+        // Counted once reassembled, so a fragmented message isn't also counted piece by piece
+        if let envelope::Msg::Fragment(fragment) = msg {
+            self.reassemble(fragment, context);
+            return;
+        }
+        if let envelope::Msg::Signed(inner) = msg {
+            self.verify(inner, context);
+            return;
+        }
+
+        let message_type = MessageType::of(msg);
+        let sender = match &env.header {
+            Some(header) if !header.node_id.is_empty() => header.node_id.clone(),
+            _ => context.source.ip().to_string(),
+        };
+        metrics::MESSAGES_BY_TYPE
+            .with_label_values(&[message_type.name()])
+            .inc();
+
+        if message_type.is_control() && self.cluster_key.is_some() && !signed {
+            metrics::MESSAGES_UNAUTHENTICATED.inc();
+            log::warn!(
+                "Dropping unsigned {} message from {}",
+                message_type.name(),
+                sender
+            );
+            return;
+        }
+
+        // Signed messages only get here once verified, so replays are caught by their sequence
+        let sequences = match signed {
+            true => &self.signed_sequences,
+            false => &self.sequences,
+        };
+        if let Some(header) = env
+            .header
+            .as_ref()
+            .filter(|header| !header.node_id.is_empty())
+            && !sequences.check(&header.node_id, header.boot, header.sequence)
+        {
+            log::debug!(
+                "Dropping duplicate message {} from {}",
+                header.sequence,
+                header.node_id
+            );
+            return;
+        }
+
+        let known = self.peers.as_ref().is_some_and(|hostdb| {
+            env.header
+                .as_ref()
+                .is_some_and(|header| hostdb.get_host(&header.node_id).is_some())
+        });
+        metrics::MESSAGES_BY_SENDER
+            .with_label_values(&[if known { sender.as_str() } else { "unknown" }])
+            .inc();
+
+        let Some(routes) = self.routes.get(&message_type) else {
+            metrics::MESSAGES_UNHANDLED
+                .with_label_values(&[message_type.name()])
+                .inc();
+            log::debug!(
+                "No handlers registered for {} messages",
+                message_type.name()
+            );
+            return;
+        };
+
+        let delivery = Arc::new(Delivery {
+            envelope: env,
+            context,
+        });
+        for handler in routes.iter().map(|&index| &self.handlers[index]) {
+            handler.pending.count.fetch_add(1, Ordering::AcqRel);
+            let result = handler.sender.try_send(Arc::clone(&delivery));
+            if result.is_err() {
+                handler.pending.done();
+            }
+
+            match result {
+                Ok(()) => metrics::MESSAGES_DISPATCHED.inc(),
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    metrics::MESSAGES_DROPPED
+                        .with_label_values(&[handler.name])
+                        .inc();
+                    log::warn!(
+                        "Dispatcher {} queue is full, dropping message",
+                        handler.name
+                    );
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    metrics::MESSAGES_DROPPED
+                        .with_label_values(&[handler.name])
+                        .inc();
+                    log::error!("Dispatcher {} is no longer running", handler.name);
+                }
+            }
+        }
+    }
+
+    /// Waits until every handler has finished with the messages dispatched so far.
+    pub async fn idle(&self) {
+        for handler in &self.handlers {
+            loop {
+                let drained = handler.pending.drained.notified();
+                if handler.pending.count.load(Ordering::Acquire) == 0 {
+                    break;
+                }
+                drained.await;
+            }
+        }
+    }
+
+    /// Discards partially received messages whose remaining fragments never arrived.
+    pub fn expire_fragments(&self, now: SystemTime) {
+        self.reassembler.expire(now);
+    }
+
+    fn reassemble(&self, fragment: &Fragment, context: MessageContext) {
+        metrics::FRAGMENTS_RECEIVED.inc();
+
+        let data = match self
+            .reassembler
+            .add(context.source.ip(), fragment, context.received_at)
+        {
+            Ok(Some(data)) => data,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("Dropping bad fragment from {}: {}", context.source, e);
+                return;
+            }
+        };
+
+        match Envelope::decode(data.as_slice()) {
+            Ok(Envelope {
+                msg: Some(envelope::Msg::Fragment(_)),
+                ..
+            }) => log::warn!("Dropping nested fragment from {}", context.source),
+            Ok(env) => {
+                let context = MessageContext {
+                    size: data.len(),
+                    ..context
+                };
+                self.route(env, context, false);
+            }
+            Err(e) => {
+                metrics::DECODE_FAILURES.inc();
+                log::warn!(
+                    "Failed to decode reassembled message from {}: {}",
+                    context.source,
+                    e
+                )
             }
-            Err(e) => log::warn!("Failed to decode message: {}", e),
         }
     }
+
+    fn verify(&self, signed: &Signed, context: MessageContext) {
+        let Some(key) = &self.cluster_key else {
+            log::debug!(
+                "Ignoring signed message from {}: no --cluster-key to check it with",
+                context.source
+            );
+            return;
+        };
+
+        match key.verify(signed) {
+            Ok(Envelope {
+                msg: Some(envelope::Msg::Fragment(_) | envelope::Msg::Signed(_)),
+                ..
+            }) => log::warn!("Dropping nested signed message from {}", context.source),
+            Ok(env) => {
+                let context = MessageContext {
+                    size: signed.envelope.len(),
+                    ..context
+                };
+                self.route(env, context, true);
+            }
+            Err(e) => {
+                metrics::MESSAGES_UNAUTHENTICATED.inc();
+                log::warn!("Dropping signed message from {}: {}", context.source, e);
+            }
+        }
+    }
+}
+
+async fn run_handler<T: Dispatchable + 'static>(
+    handler: Arc<T>,
+    mut receiver: mpsc::Receiver<Arc<Delivery>>,
+    pending: Arc<Pending>,
+) {
+    let name = handler.dispatcher_name();
+
+    while let Some(delivery) = receiver.recv().await {
+        let start = Instant::now();
+        let result = handler
+            .dispatch(&delivery.envelope, &delivery.context)
+            .await;
+        metrics::DISPATCH_LATENCY
+            .with_label_values(&[name])
+            .observe(start.elapsed().as_secs_f64());
+
+        if let Err(e) = result {
+            metrics::MESSAGES_FAILED_DISPATCH
+                .with_label_values(&[name])
+                .inc();
+            log::warn!(
+                "Dispatcher {} failed to handle {} byte message from {}: {}",
+                name,
+                delivery.context.size,
+                delivery.context.source,
+                e
+            );
+        }
+        pending.done();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::homelabd::{SystemInfoMessage, TestMessage, UpdateRequest};
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicU64;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    /// Records what it's handed, optionally waiting for a permit before each message.
+    struct Recorder {
+        name: &'static str,
+        types: &'static [MessageType],
+        received: Mutex<Vec<MessageType>>,
+        gate: Option<Semaphore>,
+    }
+
+    impl Recorder {
+        fn new(name: &'static str, types: &'static [MessageType]) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                types,
+                received: Mutex::new(Vec::new()),
+                gate: None,
+            })
+        }
+
+        /// A handler that takes messages off its queue only as permits are added.
+        fn gated(name: &'static str, types: &'static [MessageType]) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                types,
+                received: Mutex::new(Vec::new()),
+                gate: Some(Semaphore::new(0)),
+            })
+        }
+
+        fn received(&self) -> Vec<MessageType> {
+            self.received.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Dispatchable for Recorder {
+        fn dispatcher_name(&self) -> &'static str {
+            self.name
+        }
+
+        fn message_types(&self) -> &'static [MessageType] {
+            self.types
+        }
+
+        async fn dispatch(&self, message: &Envelope, _: &MessageContext) -> Result<(), String> {
+            if let Some(gate) = &self.gate {
+                gate.acquire().await.unwrap().forget();
+            }
+            let message_type = MessageType::of(message.msg.as_ref().unwrap());
+            self.received.lock().unwrap().push(message_type);
+            Ok(())
+        }
+    }
+
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+    /// An encoded message from a single test sender, numbered so none look like duplicates.
+    fn encode(msg: envelope::Msg) -> Vec<u8> {
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        protocol::envelope("sender", 1, sequence, msg).encode_to_vec()
+    }
+
+    fn test_message() -> Vec<u8> {
+        encode(envelope::Msg::Test(TestMessage {
+            text: "hello".to_string(),
+        }))
+    }
+
+    fn context() -> MessageContext {
+        MessageContext {
+            source: "10.0.0.1:9999".parse().unwrap(),
+            interface: None,
+            received_at: SystemTime::now(),
+            size: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queues_drop_messages_for_that_handler_only() {
+        let mut dispatcher = Dispatcher::new();
+        let stuck = Recorder::gated("test-stuck", &[MessageType::Test]);
+        let other = Recorder::new("test-unaffected", &[MessageType::Test]);
+        dispatcher.register(Arc::clone(&stuck));
+        dispatcher.register(Arc::clone(&other));
+
+        // Yielding lets the unaffected handler drain its queue after every message, while the
+        // stuck one holds the first and queues up to the limit behind it
+        for _ in 0..HANDLER_QUEUE_DEPTH + 3 {
+            dispatcher.dispatch(&test_message(), context());
+            tokio::task::yield_now().await;
+        }
+        let dropped = |name| metrics::MESSAGES_DROPPED.with_label_values(&[name]).get();
+        assert_eq!(dropped("test-stuck"), 2);
+        assert_eq!(dropped("test-unaffected"), 0);
+
+        stuck
+            .gate
+            .as_ref()
+            .unwrap()
+            .add_permits(HANDLER_QUEUE_DEPTH + 1);
+        dispatcher.idle().await;
+        assert_eq!(stuck.received().len(), HANDLER_QUEUE_DEPTH + 1);
+        assert_eq!(other.received().len(), HANDLER_QUEUE_DEPTH + 3);
+    }
+
+    #[tokio::test]
+    async fn slow_handlers_only_delay_their_own_messages() {
+        let mut dispatcher = Dispatcher::new();
+        let slow = Recorder::gated("test-slow", &[MessageType::Test]);
+        let fast = Recorder::new("test-fast", &[MessageType::Test]);
+        dispatcher.register(Arc::clone(&slow));
+        dispatcher.register(Arc::clone(&fast));
+
+        for _ in 0..3 {
+            dispatcher.dispatch(&test_message(), context());
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while fast.received().len() < 3 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the fast handler was held up");
+        assert!(slow.received().is_empty());
+
+        // idle() has to wait for the slow handler too
+        let idle = dispatcher.idle();
+        tokio::pin!(idle);
+        assert!(futures::poll!(idle.as_mut()).is_pending());
+        slow.gate.as_ref().unwrap().add_permits(3);
+        idle.await;
+        assert_eq!(slow.received().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn routes_each_type_only_to_handlers_that_want_it() {
+        let mut dispatcher = Dispatcher::new();
+        let info = Recorder::new("test-info", &[MessageType::SystemInfo]);
+        let both = Recorder::new("test-both", &[MessageType::SystemInfo, MessageType::Test]);
+        dispatcher.register(Arc::clone(&info));
+        dispatcher.register(Arc::clone(&both));
+
+        dispatcher.dispatch(&test_message(), context());
+        dispatcher.dispatch(
+            &encode(envelope::Msg::SystemInfo(SystemInfoMessage::default())),
+            context(),
+        );
+        dispatcher.idle().await;
+
+        assert_eq!(info.received(), [MessageType::SystemInfo]);
+        assert_eq!(
+            both.received(),
+            [MessageType::Test, MessageType::SystemInfo]
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_messages_nobody_handles() {
+        let mut dispatcher = Dispatcher::new();
+        let info = Recorder::new("test-unhandled", &[MessageType::SystemInfo]);
+        dispatcher.register(Arc::clone(&info));
+        let unhandled = || {
+            metrics::MESSAGES_UNHANDLED
+                .with_label_values(&[MessageType::UpdateRequest.name()])
+                .get()
+        };
+        let before = unhandled();
+
+        dispatcher.dispatch(
+            &encode(envelope::Msg::UpdateRequest(UpdateRequest::default())),
+            context(),
+        );
+        dispatcher.idle().await;
+
+        assert_eq!(unhandled(), before + 1);
+        assert!(info.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_unsigned_control_messages_when_there_is_a_key() {
+        let mut dispatcher = Dispatcher::new().with_cluster_key(Some(ClusterKey::new(b"secret")));
+        let handler = Recorder::new(
+            "test-signed",
+            &[MessageType::UpdateRequest, MessageType::Test],
+        );
+        dispatcher.register(Arc::clone(&handler));
+        let update = || {
+            protocol::envelope(
+                "sender",
+                1,
+                SEQUENCE.fetch_add(1, Ordering::Relaxed),
+                envelope::Msg::UpdateRequest(UpdateRequest::default()),
+            )
+        };
+
+        dispatcher.dispatch(&update().encode_to_vec(), context());
+        dispatcher.dispatch(
+            &ClusterKey::new(b"guessed").sign(&update()).encode_to_vec(),
+            context(),
+        );
+        // Only control messages need signing
+        dispatcher.dispatch(&test_message(), context());
+        dispatcher.dispatch(
+            &ClusterKey::new(b"secret").sign(&update()).encode_to_vec(),
+            context(),
+        );
+        dispatcher.idle().await;
+
+        assert_eq!(
+            handler.received(),
+            [MessageType::Test, MessageType::UpdateRequest]
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_unknown_senders_under_one_label() {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.watch_peers(Arc::new(HostDatabase::new(
+            &clap::Parser::parse_from(["homelabd"]),
+            Arc::new(crate::events::EventBus::new()),
+        )));
+        let msg = envelope::Msg::Test(TestMessage::default());
+
+        dispatcher.dispatch(
+            &protocol::envelope("forged-sender", 1, 0, msg).encode_to_vec(),
+            context(),
+        );
+
+        assert!(
+            metrics::MESSAGES_BY_SENDER
+                .remove_label_values(&["forged-sender"])
+                .is_err()
+        );
+    }
 }
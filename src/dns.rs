@@ -0,0 +1,587 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hickory_proto::op::{Header, Message, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::rdata::{A, AAAA, PTR, SOA, SRV};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::config::Config;
+use crate::receivers::hostdb::{Host, HostDatabase};
+
+// Hosts announce every few seconds and are evicted after minutes, so keep caches short
+const RECORD_TTL: u32 = 60;
+
+// Close TCP connections that send nothing for this long, so idle clients can't pile up
+const TCP_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Authoritative DNS for the homelab zone, answering from the HostDatabase so machines without
+/// homelabd can still resolve homelab names.
+pub struct DnsServer {
+    config: Arc<Config>,
+    zone: Name,
+    hostdb: Arc<HostDatabase>,
+}
+
+struct Answer {
+    code: ResponseCode,
+    answers: Vec<Record>,
+    additionals: Vec<Record>,
+    /// Whether to include the zone's SOA in the authority section (negative answers)
+    soa: bool,
+}
+
+impl Answer {
+    fn records(answers: Vec<Record>) -> Self {
+        Self {
+            code: ResponseCode::NoError,
+            answers,
+            additionals: Vec::new(),
+            soa: false,
+        }
+    }
+
+    fn negative(code: ResponseCode) -> Self {
+        Self {
+            code,
+            answers: Vec::new(),
+            additionals: Vec::new(),
+            soa: true,
+        }
+    }
+
+    fn refused() -> Self {
+        Self {
+            code: ResponseCode::Refused,
+            answers: Vec::new(),
+            additionals: Vec::new(),
+            soa: false,
+        }
+    }
+}
+
+impl DnsServer {
+    pub fn new(config: Arc<Config>, hostdb: Arc<HostDatabase>) -> Result<Self, String> {
+        let zone = match &config.dns_zone {
+            Some(zone) => zone,
+            None => return Err("No DNS zone is configured".to_string()),
+        };
+
+        let mut zone = Name::from_ascii(zone)
+            .map_err(|e| format!("Invalid DNS zone {}: {}", zone, e))?
+            .to_lowercase();
+        zone.set_fqdn(true);
+
+        Ok(Self {
+            config,
+            zone,
+            hostdb,
+        })
+    }
+
+    pub async fn start(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let addr = SocketAddr::new(self.config.dns_bind_ip, self.config.dns_port);
+
+        let udp = UdpSocket::bind(&addr).await?;
+        let tcp = TcpListener::bind(&addr).await?;
+        info!("DNS server for {} listening on {}", self.zone, addr);
+
+        let this = Arc::clone(&self);
+        tokio::spawn(async move {
+            loop {
+                match tcp.accept().await {
+                    Ok((stream, _)) => {
+                        let this = Arc::clone(&this);
+                        tokio::spawn(async move {
+                            if let Err(e) = this.serve_tcp(stream).await {
+                                warn!("Failed to serve DNS over TCP: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept DNS connection: {}", e),
+                }
+            }
+        });
+
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let (size, peer) = match udp.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Failed to receive DNS request: {}", e);
+                    continue;
+                }
+            };
+            if let Some(response) = self.handle(&buf[..size], true)
+                && let Err(e) = udp.send_to(&response, peer).await
+            {
+                warn!("Failed to send DNS response to {}: {}", peer, e);
+            }
+        }
+    }
+
+    /// Answers length-prefixed requests until the client closes the connection or leaves it
+    /// idle for `TCP_IDLE_TIMEOUT`.
+    async fn serve_tcp(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        loop {
+            let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+                Ok(Ok(len)) => len as usize,
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            };
+
+            let mut request = vec![0u8; len];
+            tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut request))
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+            if let Some(response) = self.handle(&request, false) {
+                let len = u16::try_from(response.len())
+                    .map_err(|_| std::io::Error::other("DNS response too long for TCP"))?;
+                stream.write_u16(len).await?;
+                stream.write_all(&response).await?;
+            }
+        }
+    }
+
+    /// Builds the wire response for a request, or None if it couldn't be parsed at all.
+    fn handle(&self, request: &[u8], udp: bool) -> Option<Vec<u8>> {
+        let request = match Message::from_vec(request) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Failed to parse DNS request: {}", e);
+                return None;
+            }
+        };
+
+        if request.message_type() != MessageType::Query {
+            return None;
+        }
+
+        let mut response = Message::new();
+        response.set_header(Header::response_from_request(request.header()));
+        response.add_queries(request.queries().to_vec());
+
+        let answer = match (request.op_code(), request.queries()) {
+            (OpCode::Query, [query]) => self.answer(query.name(), query.query_type()),
+            (OpCode::Query, _) => Answer::negative(ResponseCode::FormErr),
+            _ => Answer::negative(ResponseCode::NotImp),
+        };
+
+        response
+            .set_authoritative(answer.code != ResponseCode::Refused)
+            .set_response_code(answer.code)
+            .add_answers(answer.answers)
+            .add_additionals(answer.additionals);
+        if answer.soa {
+            response.add_name_server(self.soa());
+        }
+
+        let bytes = match response.to_vec() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Failed to encode DNS response: {}", e);
+                return None;
+            }
+        };
+
+        // Over UDP, tell the client to retry with TCP rather than sending a response that may be
+        // dropped or truncated on the way. TCP responses are length-prefixed with 16 bits, so
+        // anything longer is cut short there too.
+        let limit = match udp {
+            true => request.max_payload() as usize,
+            false => u16::MAX as usize,
+        };
+        if bytes.len() > limit {
+            let mut truncated = response.truncate();
+            truncated.set_truncated(true);
+            return truncated.to_vec().ok();
+        }
+
+        Some(bytes)
+    }
+
+    fn answer(&self, name: &Name, query_type: RecordType) -> Answer {
+        let name = name.to_lowercase();
+
+        if is_reverse_name(&name) {
+            return self.answer_reverse(&name, query_type);
+        }
+
+        if !self.zone.zone_of(&name) {
+            return Answer::refused();
+        }
+
+        if name == self.zone {
+            return match query_type {
+                RecordType::SOA => Answer::records(vec![self.soa()]),
+                _ => Answer::negative(ResponseCode::NoError),
+            };
+        }
+
+        // _<service>._tcp.<zone>
+        if name.num_labels() == self.zone.num_labels() + 2 {
+            let mut labels = name.iter();
+            let service = labels.next().and_then(|l| std::str::from_utf8(l).ok());
+            let proto = labels.next().and_then(|l| std::str::from_utf8(l).ok());
+            if let (Some(service), Some("_tcp")) = (service, proto)
+                && let Some(service) = service.strip_prefix('_')
+            {
+                return self.answer_service(service, query_type);
+            }
+        }
+
+        let hosts = self
+            .hostdb
+            .hosts()
+            .into_iter()
+            .filter(|host| self.host_name(host).as_ref() == Some(&name))
+            .collect::<Vec<_>>();
+
+        if hosts.is_empty() {
+            return Answer::negative(ResponseCode::NXDomain);
+        }
+
+        let records = hosts
+            .iter()
+            .filter_map(|host| address_record(&name, host.primaryip, query_type))
+            .collect::<Vec<_>>();
+
+        if records.is_empty() {
+            // The name exists, just not with this record type
+            return Answer::negative(ResponseCode::NoError);
+        }
+
+        Answer::records(records)
+    }
+
+    fn answer_service(&self, service: &str, query_type: RecordType) -> Answer {
+        let providers = self
+            .hostdb
+            .services()
+            .into_iter()
+            .filter(|(_, s)| s.name.eq_ignore_ascii_case(service))
+            .filter_map(|(host, s)| Some((self.host_name(&host)?, host, s)))
+            .collect::<Vec<_>>();
+
+        if providers.is_empty() {
+            return Answer::negative(ResponseCode::NXDomain);
+        }
+
+        if query_type != RecordType::SRV && query_type != RecordType::ANY {
+            return Answer::negative(ResponseCode::NoError);
+        }
+
+        let owner = Name::from_ascii(format!("_{}._tcp", service))
+            .and_then(|name| name.append_domain(&self.zone))
+            .map(|name| name.to_lowercase());
+        let Ok(owner) = owner else {
+            return Answer::negative(ResponseCode::ServFail);
+        };
+
+        let mut answer = Answer::records(Vec::new());
+        for (target, host, service) in providers {
+            answer.answers.push(Record::from_rdata(
+                owner.clone(),
+                RECORD_TTL,
+                RData::SRV(SRV::new(0, 0, service.port, target.clone())),
+            ));
+
+            let address_type = match host.primaryip {
+                IpAddr::V4(_) => RecordType::A,
+                IpAddr::V6(_) => RecordType::AAAA,
+            };
+            if let Some(record) = address_record(&target, host.primaryip, address_type) {
+                answer.additionals.push(record);
+            }
+        }
+
+        answer
+    }
+
+    fn answer_reverse(&self, name: &Name, query_type: RecordType) -> Answer {
+        let mut targets = self
+            .hostdb
+            .hosts()
+            .into_iter()
+            .filter(|host| reverse_addresses(host).any(|ip| Name::from(ip).to_lowercase() == *name))
+            .filter_map(|host| self.host_name(&host))
+            .collect::<Vec<_>>();
+        targets.sort();
+        targets.dedup();
+
+        // We're only authoritative for addresses that belong to exactly one known host
+        let target = match targets.as_slice() {
+            [target] => target.clone(),
+            [] => return Answer::refused(),
+            _ => {
+                warn!(
+                    "Refusing reverse lookup of {}, which several hosts claim",
+                    name
+                );
+                return Answer::refused();
+            }
+        };
+
+        match query_type {
+            RecordType::PTR | RecordType::ANY => Answer::records(vec![Record::from_rdata(
+                name.clone(),
+                RECORD_TTL,
+                RData::PTR(PTR(target)),
+            )]),
+            _ => Answer::records(Vec::new()),
+        }
+    }
+
+    /// The host's name inside the zone, built from the first label of its announced hostname.
+    fn host_name(&self, host: &Host) -> Option<Name> {
+        let label = host.hostname.split('.').next()?;
+        Name::from_ascii(label)
+            .and_then(|name| name.append_domain(&self.zone))
+            .map(|name| name.to_lowercase())
+            .ok()
+    }
+
+    fn soa(&self) -> Record {
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+
+        let mname = Name::from_ascii("ns")
+            .and_then(|name| name.append_domain(&self.zone))
+            .unwrap_or_else(|_| self.zone.clone());
+        let rname = Name::from_ascii("hostmaster")
+            .and_then(|name| name.append_domain(&self.zone))
+            .unwrap_or_else(|_| self.zone.clone());
+
+        Record::from_rdata(
+            self.zone.clone(),
+            RECORD_TTL,
+            RData::SOA(SOA::new(
+                mname,
+                rname,
+                serial,
+                RECORD_TTL as i32,
+                RECORD_TTL as i32,
+                (RECORD_TTL * 10) as i32,
+                RECORD_TTL,
+            )),
+        )
+    }
+}
+
+/// Addresses a host can be looked up by in reverse: its primary IP and those on its physical
+/// interfaces. Bridges and the like often share an address across hosts (172.17.0.1 on every
+/// Docker host), so they're left out.
+fn reverse_addresses(host: &Host) -> impl Iterator<Item = IpAddr> + '_ {
+    let physical = host
+        .interfaces
+        .iter()
+        .filter(|iface| !iface.is_virtual)
+        .flat_map(|iface| &iface.addresses)
+        .filter_map(|address| address.address.parse().ok());
+    std::iter::once(host.primaryip).chain(physical)
+}
+
+fn is_reverse_name(name: &Name) -> bool {
+    let labels = name.iter().rev().collect::<Vec<_>>();
+    matches!(
+        labels.as_slice(),
+        [arpa, kind, ..] if arpa.eq_ignore_ascii_case(b"arpa")
+            && (kind.eq_ignore_ascii_case(b"in-addr") || kind.eq_ignore_ascii_case(b"ip6"))
+    )
+}
+
+fn address_record(name: &Name, ip: IpAddr, query_type: RecordType) -> Option<Record> {
+    let rdata = match (ip, query_type) {
+        (IpAddr::V4(ip), RecordType::A | RecordType::ANY) => RData::A(A(ip)),
+        (IpAddr::V6(ip), RecordType::AAAA | RecordType::ANY) => RData::AAAA(AAAA(ip)),
+        _ => return None,
+    };
+
+    Some(Record::from_rdata(name.clone(), RECORD_TTL, rdata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventBus;
+    use crate::proto::homelabd::{InterfaceAddress, NetworkInterface};
+    use crate::receivers::hostdb::Service;
+    use clap::Parser;
+    use hickory_proto::op::Query;
+
+    fn server() -> DnsServer {
+        let config = Arc::new(Config {
+            dns_zone: Some("lab.example".to_string()),
+            ..Config::parse_from(["homelabd"])
+        });
+        let hostdb = Arc::new(HostDatabase::new(&config, Arc::new(EventBus::new())));
+        DnsServer::new(config, hostdb).unwrap()
+    }
+
+    fn interface(name: &str, address: &str, is_virtual: bool) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            addresses: vec![InterfaceAddress {
+                address: address.to_string(),
+                prefix_len: 24,
+            }],
+            up: true,
+            is_virtual,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a host with a Docker bridge alongside its primary interface.
+    fn add_host(server: &DnsServer, hostname: &str, primary: &str) {
+        let primaryip = primary.parse().unwrap();
+        server.hostdb.host_seen(
+            Host {
+                ip: vec![primary.to_string(), "172.17.0.1".to_string()],
+                interfaces: vec![
+                    interface("eth0", primary, false),
+                    interface("docker0", "172.17.0.1", true),
+                ],
+                ..Host::announced(&format!("id-{}", hostname), hostname, primaryip)
+            },
+            SystemTime::now(),
+        );
+    }
+
+    fn query(server: &DnsServer, name: &str, query_type: RecordType) -> Message {
+        let mut request = Message::new();
+        request
+            .set_id(7)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        let response = server.handle(&request.to_vec().unwrap(), false).unwrap();
+        Message::from_vec(&response).unwrap()
+    }
+
+    fn rdata(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| record.data().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn answers_address_queries_with_the_primary_ip() {
+        let server = server();
+        add_host(&server, "alpha.home", "10.0.0.1");
+        add_host(&server, "beta", "fd00::2");
+
+        let response = query(&server, "alpha.lab.example.", RecordType::A);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.authoritative());
+        assert_eq!(rdata(response.answers()), ["10.0.0.1"]);
+
+        let response = query(&server, "BETA.lab.example.", RecordType::AAAA);
+        assert_eq!(rdata(response.answers()), ["fd00::2"]);
+
+        // The name exists, but has no address of that type
+        let response = query(&server, "alpha.lab.example.", RecordType::AAAA);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers().len(), 1);
+
+        let response = query(&server, "gamma.lab.example.", RecordType::A);
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(response.name_servers().len(), 1);
+    }
+
+    #[test]
+    fn answers_services_with_addresses_of_their_hosts() {
+        let server = server();
+        add_host(&server, "alpha", "10.0.0.1");
+        add_host(&server, "beta", "10.0.0.2");
+        let node = Service {
+            name: "node".to_string(),
+            port: 9100,
+        };
+        server.hostdb.services_seen("id-alpha", vec![node.clone()]);
+        server.hostdb.services_seen("id-beta", vec![node]);
+
+        let response = query(&server, "_node._tcp.lab.example.", RecordType::SRV);
+        let mut answers = rdata(response.answers());
+        answers.sort();
+        assert_eq!(
+            answers,
+            ["0 0 9100 alpha.lab.example.", "0 0 9100 beta.lab.example."]
+        );
+        let mut additionals = rdata(response.additionals());
+        additionals.sort();
+        assert_eq!(additionals, ["10.0.0.1", "10.0.0.2"]);
+
+        let response = query(&server, "_ssh._tcp.lab.example.", RecordType::SRV);
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    }
+
+    #[test]
+    fn answers_reverse_lookups_for_addresses_only_one_host_has() {
+        let server = server();
+        add_host(&server, "alpha", "10.0.0.1");
+        add_host(&server, "beta", "10.0.0.2");
+
+        let response = query(&server, "1.0.0.10.in-addr.arpa.", RecordType::PTR);
+        assert_eq!(rdata(response.answers()), ["alpha.lab.example."]);
+
+        // Every host has the bridge address, and nobody has this one
+        for name in ["1.0.17.172.in-addr.arpa.", "9.0.0.10.in-addr.arpa."] {
+            let response = query(&server, name, RecordType::PTR);
+            assert_eq!(response.response_code(), ResponseCode::Refused);
+            assert!(response.answers().is_empty());
+        }
+
+        add_host(&server, "gamma", "10.0.0.1");
+        let response = query(&server, "1.0.0.10.in-addr.arpa.", RecordType::PTR);
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+    }
+
+    #[test]
+    fn answers_soa_for_the_zone_and_refuses_other_names() {
+        let server = server();
+
+        let response = query(&server, "lab.example.", RecordType::SOA);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].record_type(), RecordType::SOA);
+
+        let response = query(&server, "example.com.", RecordType::A);
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert!(!response.authoritative());
+    }
+
+    #[test]
+    fn truncates_responses_too_long_for_tcp() {
+        let server = server();
+        for index in 0..3000u32 {
+            let hostname = format!("host-{}", index);
+            let ip = format!("10.{}.{}.1", index / 256, index % 256);
+            add_host(&server, &hostname, &ip);
+            let service = Service {
+                name: "node".to_string(),
+                port: 9100,
+            };
+            server
+                .hostdb
+                .services_seen(&format!("id-{}", hostname), vec![service]);
+        }
+
+        let mut request = Message::new();
+        request.add_query(Query::query(
+            Name::from_ascii("_node._tcp.lab.example.").unwrap(),
+            RecordType::SRV,
+        ));
+        let response = server.handle(&request.to_vec().unwrap(), false).unwrap();
+        assert!(response.len() <= u16::MAX as usize);
+        assert!(Message::from_vec(&response).unwrap().truncated());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::clock::Clock;
+use crate::dispatch::{Dispatchable, MessageContext, MessageType};
+use crate::net::Network;
+use crate::proto::homelabd::{Envelope, LeaseMessage, envelope};
+use crate::protocol::capability;
+use crate::receivers::hostdb::HostDatabase;
+use crate::scheduler::Schedulable;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// How long a lease lasts without renewal. The leader renews every `RENEW_INTERVAL_SECONDS`, so
+// it can miss a couple of renewals before losing the lease.
+const LEASE_DURATION: Duration = Duration::from_secs(15);
+const RENEW_INTERVAL_SECONDS: u64 = 5;
+
+// How long other nodes wait after a lease expires before claiming it, giving the preferred
+// candidate (lowest node ID) the first chance
+const CLAIM_GRACE: Duration = Duration::from_secs(30);
+
+// Highest term seen, kept in the state directory so a restarted leader never reuses a token
+const TERM_FILE: &str = "leader_term";
+
+// Runtime rollout holds, one per line, kept in the state directory so they outlive restarts
+const HOLDS_FILE: &str = "rollout_holds";
+
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub holder: String,
+    /// Fencing token: strictly increases with every change of leader
+    pub term: u64,
+    pub expires_at: SystemTime,
+}
+
+struct State {
+    lease: Option<Lease>,
+    /// Highest term seen, kept after the lease expires so the next claim supersedes it
+    term: u64,
+    /// Hosts held back from rollouts, set by the leader and announced with its lease
+    holds: Vec<String>,
+}
+
+/// Lease-based leader election over the peer protocol. The leader renews its lease with a
+/// `LeaseMessage` every few seconds; when it stops, another node claims the next term.
+pub struct Election {
+    network: Arc<Network>,
+    hostdb: Arc<HostDatabase>,
+    clock: Arc<dyn Clock>,
+    term_path: PathBuf,
+    holds_path: PathBuf,
+    /// Nodes listen for a full lease before claiming, so they don't depose a healthy leader
+    started: SystemTime,
+    state: Mutex<State>,
+}
+
+impl Election {
+    pub fn new(
+        network: Arc<Network>,
+        hostdb: Arc<HostDatabase>,
+        clock: Arc<dyn Clock>,
+        state_dir: &Path,
+    ) -> Self {
+        let term_path = state_dir.join(TERM_FILE);
+        let term = std::fs::read_to_string(&term_path)
+            .ok()
+            .and_then(|contents| contents.trim().parse().ok())
+            .unwrap_or(0);
+        let holds_path = state_dir.join(HOLDS_FILE);
+        let holds = std::fs::read_to_string(&holds_path)
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        Self {
+            network,
+            hostdb,
+            started: clock.now(),
+            clock,
+            term_path,
+            holds_path,
+            state: Mutex::new(State {
+                lease: None,
+                term,
+                holds,
+            }),
+        }
+    }
+
+    fn save_holds(&self, holds: &[String]) {
+        let contents = holds
+            .iter()
+            .map(|host| format!("{}\n", host))
+            .collect::<String>();
+        if let Err(e) = std::fs::write(&self.holds_path, contents) {
+            log::warn!(
+                "Failed to save rollout holds to {}: {}",
+                self.holds_path.display(),
+                e
+            );
+        }
+    }
+
+    /// Hosts held back from rollouts at runtime, as last announced by a leader.
+    pub fn rollout_holds(&self) -> Vec<String> {
+        self.state.lock().unwrap().holds.clone()
+    }
+
+    /// Replaces the runtime rollout holds. Only the leader should call this; its next renewal
+    /// announces them to every other node.
+    pub fn set_rollout_holds(&self, holds: Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        if state.holds != holds {
+            self.save_holds(&holds);
+            state.holds = holds;
+        }
+    }
+
+    fn save_term(&self, term: u64) {
+        if let Err(e) = std::fs::write(&self.term_path, format!("{}\n", term)) {
+            log::warn!(
+                "Failed to save leader term to {}: {}",
+                self.term_path.display(),
+                e
+            );
+        }
+    }
+
+    /// The current lease, if it hasn't expired.
+    pub fn lease(&self) -> Option<Lease> {
+        let now = self.clock.now();
+        self.state
+            .lock()
+            .unwrap()
+            .lease
+            .clone()
+            .filter(|lease| lease.expires_at > now)
+    }
+
+    /// This node's fencing token if it currently holds the lease.
+    pub fn leadership(&self) -> Option<u64> {
+        self.lease()
+            .filter(|lease| lease.holder == self.network.node_id())
+            .map(|lease| lease.term)
+    }
+
+    /// Whether this node should claim an expired lease straight away: it's the live election
+    /// participant with the lowest node ID.
+    fn preferred_candidate(&self) -> bool {
+        let node_id = self.network.node_id();
+        self.hostdb
+            .hosts()
+            .iter()
+            .filter(|host| host.supports(capability::LEADER_ELECTION))
+            .all(|host| host.node_id.as_str() >= node_id)
+    }
+
+    /// Decides whether to renew or claim the lease, returning the message to send if so.
+    fn tick(&self, now: SystemTime) -> Option<LeaseMessage> {
+        let node_id = self.network.node_id();
+        let mut state = self.state.lock().unwrap();
+
+        let term = match &state.lease {
+            Some(lease) if lease.expires_at > now && lease.holder == node_id => lease.term,
+            Some(lease) if lease.expires_at > now => return None,
+            lease => {
+                let listened = now.duration_since(self.started).unwrap_or_default();
+                if listened < LEASE_DURATION {
+                    return None;
+                }
+
+                let vacant_since = lease
+                    .as_ref()
+                    .map_or(self.started, |lease| lease.expires_at);
+                let vacant = now.duration_since(vacant_since).unwrap_or_default();
+                if !self.preferred_candidate() && vacant < CLAIM_GRACE {
+                    return None;
+                }
+
+                log::info!("Claiming leadership for term {}", state.term + 1);
+                state.term + 1
+            }
+        };
+
+        if term > state.term {
+            self.save_term(term);
+        }
+        state.term = term;
+        state.lease = Some(Lease {
+            holder: node_id.to_string(),
+            term,
+            expires_at: now + LEASE_DURATION,
+        });
+
+        Some(LeaseMessage {
+            holder_node_id: node_id.to_string(),
+            term,
+            duration_ms: LEASE_DURATION.as_millis() as u64,
+            rollout_holds: state.holds.clone(),
+        })
+    }
+
+    /// Applies a lease announced by any node, including this one's own echoes.
+    fn observe(&self, lease: &LeaseMessage, received_at: SystemTime) {
+        let node_id = self.network.node_id();
+        let mut state = self.state.lock().unwrap();
+
+        // Higher terms win; on a tie (two simultaneous claims) the lower node ID does
+        let supersedes = match &state.lease {
+            Some(current) => {
+                lease.term > current.term
+                    || (lease.term == current.term && lease.holder_node_id <= current.holder)
+            }
+            None => lease.term >= state.term,
+        };
+        if !supersedes {
+            log::debug!(
+                "Ignoring stale lease for {} in term {}",
+                lease.holder_node_id,
+                lease.term
+            );
+            return;
+        }
+
+        if let Some(current) = &state.lease
+            && current.holder == node_id
+            && lease.holder_node_id != node_id
+        {
+            log::warn!(
+                "Stepping down: {} holds the lease for term {}",
+                lease.holder_node_id,
+                lease.term
+            );
+        }
+
+        // Our own renewals come back to us too; they're already applied
+        if lease.holder_node_id == node_id {
+            return;
+        }
+
+        if state
+            .lease
+            .as_ref()
+            .is_none_or(|current| current.holder != lease.holder_node_id)
+        {
+            log::info!(
+                "{} is the leader for term {}",
+                lease.holder_node_id,
+                lease.term
+            );
+        }
+
+        if lease.term > state.term {
+            self.save_term(lease.term);
+            state.term = lease.term;
+        }
+        if state.holds != lease.rollout_holds {
+            self.save_holds(&lease.rollout_holds);
+            state.holds = lease.rollout_holds.clone();
+        }
+        state.lease = Some(Lease {
+            holder: lease.holder_node_id.clone(),
+            term: lease.term,
+            expires_at: received_at + Duration::from_millis(lease.duration_ms),
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl Schedulable for Election {
+    fn name(&self) -> &'static str {
+        "Election"
+    }
+
+    fn interval_seconds(&self) -> u64 {
+        RENEW_INTERVAL_SECONDS
+    }
+
+    async fn run(&self) {
+        let Some(lease) = self.tick(self.clock.now()) else {
+            return;
+        };
+
+        if let Err(e) = self.network.send(envelope::Msg::Lease(lease)).await {
+            log::warn!("Failed to send lease: {}", e);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Dispatchable for Election {
+    fn dispatcher_name(&self) -> &'static str {
+        "Election"
+    }
+
+    fn message_types(&self) -> &'static [MessageType] {
+        &[MessageType::Lease]
+    }
+
+    async fn dispatch(&self, msg: &Envelope, context: &MessageContext) -> Result<(), String> {
+        match &msg.msg {
+            Some(envelope::Msg::Lease(lease)) => {
+                if lease.holder_node_id.is_empty() {
+                    return Err("Lease has no holder".to_string());
+                }
+                self.observe(lease, context.received_at);
+                Ok(())
+            }
+            _ => Err("Unexpected message type".to_string()),
+        }
+    }
+}
+
+/// Cluster-wide work that only the leader does. Leadership can be lost while a run is under
+/// way, so tasks check `Leadership::holds` again right before acting.
+#[async_trait::async_trait]
+pub trait LeaderTask: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn interval_seconds(&self) -> u64;
+    async fn run(&self, leadership: &Leadership);
+}
+
+/// The term a `LeaderTask` was started in, as its fencing token.
+pub struct Leadership {
+    election: Arc<Election>,
+    term: u64,
+}
+
+impl Leadership {
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Whether this node still holds the lease, in the same term.
+    pub fn holds(&self) -> bool {
+        self.election.leadership() == Some(self.term)
+    }
+}
+
+/// Runs a task only while this node is the leader, so cluster-wide work happens exactly once.
+pub struct LeaderOnly<T: LeaderTask> {
+    task: Arc<T>,
+    election: Arc<Election>,
+}
+
+impl<T: LeaderTask> LeaderOnly<T> {
+    pub fn new(task: Arc<T>, election: Arc<Election>) -> Self {
+        Self { task, election }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: LeaderTask> Schedulable for LeaderOnly<T> {
+    fn name(&self) -> &'static str {
+        self.task.name()
+    }
+
+    fn interval_seconds(&self) -> u64 {
+        self.task.interval_seconds()
+    }
+
+    async fn run(&self) {
+        match self.election.leadership() {
+            Some(term) => {
+                log::debug!("Running {} as leader for term {}", self.task.name(), term);
+                let leadership = Leadership {
+                    election: Arc::clone(&self.election),
+                    term,
+                };
+                self.task.run(&leadership).await;
+            }
+            None => log::debug!("Skipping {}: not the leader", self.task.name()),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+// Events a slow subscriber can fall behind by before it starts missing them
+const EVENT_BUFFER: usize = 256;
+
+/// Something that changed about the cluster, as seen by this node.
+#[derive(Clone, Debug, Serialize, strum::IntoStaticStr, strum::VariantNames)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Event {
+    HostJoined {
+        node_id: String,
+        hostname: String,
+        primary_ip: String,
+        version: String,
+    },
+    /// The host's name or addresses changed
+    HostUpdated {
+        node_id: String,
+        hostname: String,
+        primary_ip: String,
+    },
+    HostLeft {
+        node_id: String,
+        hostname: String,
+    },
+    ExporterAdded {
+        node_id: String,
+        hostname: String,
+        job: String,
+        port: u16,
+    },
+    ExporterRemoved {
+        node_id: String,
+        hostname: String,
+        job: String,
+        port: u16,
+    },
+    VersionChanged {
+        node_id: String,
+        hostname: String,
+        from: String,
+        to: String,
+    },
+    /// The host undid an update that didn't run healthily
+    RolledBack {
+        node_id: String,
+        hostname: String,
+        from: String,
+        to: String,
+    },
+    /// The host is still behind the newest version in the cluster a while after it appeared
+    OutdatedVersion {
+        node_id: String,
+        hostname: String,
+        version: String,
+        newest: String,
+    },
+}
+
+impl Event {
+    /// Every event's name, as used in notification filters.
+    pub const NAMES: &'static [&'static str] = <Self as strum::VariantNames>::VARIANTS;
+
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+
+    /// A one-line description for people.
+    pub fn summary(&self) -> String {
+        match self {
+            Event::HostJoined {
+                hostname,
+                primary_ip,
+                version,
+                ..
+            } => format!(
+                "Host {} joined at {} running {}",
+                hostname, primary_ip, version
+            ),
+            Event::HostUpdated {
+                hostname,
+                primary_ip,
+                ..
+            } => format!("Host {} changed, now at {}", hostname, primary_ip),
+            Event::HostLeft { hostname, .. } => format!("Host {} disappeared", hostname),
+            Event::ExporterAdded {
+                hostname,
+                job,
+                port,
+                ..
+            } => format!("{} started exporting {} on port {}", hostname, job, port),
+            Event::ExporterRemoved {
+                hostname,
+                job,
+                port,
+                ..
+            } => format!("{} stopped exporting {} on port {}", hostname, job, port),
+            Event::VersionChanged {
+                hostname, from, to, ..
+            } => format!("{} is now running {} (was {})", hostname, to, from),
+            Event::RolledBack {
+                hostname, from, to, ..
+            } => format!("{} rolled back from {} to {}", hostname, from, to),
+            Event::OutdatedVersion {
+                hostname,
+                version,
+                newest,
+                ..
+            } => format!(
+                "{} is still running {} while {} is available",
+                hostname, version, newest
+            ),
+        }
+    }
+
+    /// Events with the same key describe the same change, for deduplicating notifications.
+    pub fn dedup_key(&self) -> String {
+        match self {
+            Event::HostJoined { node_id, .. }
+            | Event::HostUpdated { node_id, .. }
+            | Event::HostLeft { node_id, .. } => format!("{}:{}", self.name(), node_id),
+            Event::ExporterAdded {
+                node_id, job, port, ..
+            }
+            | Event::ExporterRemoved {
+                node_id, job, port, ..
+            } => format!("{}:{}:{}:{}", self.name(), node_id, job, port),
+            Event::VersionChanged { node_id, to, .. } => {
+                format!("{}:{}:{}", self.name(), node_id, to)
+            }
+            Event::RolledBack { node_id, from, .. } => {
+                format!("{}:{}:{}", self.name(), node_id, from)
+            }
+            Event::OutdatedVersion {
+                node_id, newest, ..
+            } => format!("{}:{}:{}", self.name(), node_id, newest),
+        }
+    }
+}
+
+/// Fans cluster events out to everything that subscribed. Publishing never blocks; subscribers
+/// that fall too far behind miss events and are told how many.
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: Event) {
+        log::debug!("Event: {:?}", event);
+        // An error only means nobody is listening right now
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
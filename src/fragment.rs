@@ -0,0 +1,345 @@
+use crate::metrics;
+use crate::proto::homelabd::{Envelope, Fragment, envelope};
+
+use bytes::Bytes;
+use prost::Message;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Largest datagram we'll send, leaving headroom under a 1500 byte Ethernet MTU for IP/UDP headers
+pub const MAX_DATAGRAM_SIZE: usize = 1400;
+
+// Payload carried by each fragment, leaving room for the fragment's own framing
+const FRAGMENT_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - 64;
+
+// Upper bound on fragments per message, so a bad sender can't make us buffer without limit
+const MAX_FRAGMENTS: u32 = 64;
+
+// How long to wait for the rest of a message before discarding what we have
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Partial messages held at once, per sender and in total, so a sender rotating message IDs
+// can't make us buffer without limit
+const MAX_PARTIAL_PER_SOURCE: usize = 16;
+const MAX_PARTIAL_TOTAL: usize = 256;
+
+// Recently completed messages remembered, so copies of their fragments arriving late on another
+// interface are ignored instead of starting a message that can never finish
+const MAX_COMPLETED: usize = 1024;
+
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Splits an encoded envelope into datagrams no larger than `MAX_DATAGRAM_SIZE`. Small messages
+/// are returned as-is so peers that predate fragmentation can still read them.
+pub fn split(data: Bytes) -> Vec<Bytes> {
+    if data.len() <= MAX_DATAGRAM_SIZE {
+        return vec![data];
+    }
+
+    // Receivers key partial messages by sender address, so the ID must not repeat across
+    // restarts or between processes on one host: mix in the clock.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let message_id = nanos ^ NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+
+    let chunks = data.chunks(FRAGMENT_PAYLOAD_SIZE).collect::<Vec<_>>();
+    let count = chunks.len() as u32;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            Envelope {
+                header: None,
+                msg: Some(envelope::Msg::Fragment(Fragment {
+                    message_id,
+                    index: index as u32,
+                    count,
+                    payload: chunk.to_vec(),
+                })),
+            }
+            .encode_to_vec()
+            .into()
+        })
+        .collect()
+}
+
+struct PartialMessage {
+    started: SystemTime,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u32,
+}
+
+#[derive(Default)]
+struct State {
+    partial: HashMap<(IpAddr, u64), PartialMessage>,
+    /// When each recently reassembled message was completed
+    completed: HashMap<(IpAddr, u64), SystemTime>,
+}
+
+/// Collects fragments from peers until a whole message is available.
+pub struct Reassembler {
+    state: Mutex<State>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Adds a fragment, returning the reassembled message once every piece has arrived.
+    pub fn add(
+        &self,
+        source: IpAddr,
+        fragment: &Fragment,
+        now: SystemTime,
+    ) -> Result<Option<Vec<u8>>, String> {
+        if fragment.count == 0 || fragment.count > MAX_FRAGMENTS {
+            return Err(format!("Invalid fragment count {}", fragment.count));
+        }
+        if fragment.index >= fragment.count {
+            return Err(format!(
+                "Fragment index {} out of range for {} fragments",
+                fragment.index, fragment.count
+            ));
+        }
+        if fragment.payload.len() > FRAGMENT_PAYLOAD_SIZE {
+            return Err(format!(
+                "Fragment payload of {} bytes is over the {} byte limit",
+                fragment.payload.len(),
+                FRAGMENT_PAYLOAD_SIZE
+            ));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        expire(&mut state, now);
+
+        let key = (source, fragment.message_id);
+        if state.completed.contains_key(&key) {
+            log::debug!(
+                "Ignoring late fragment of message {} from {}",
+                fragment.message_id,
+                source
+            );
+            return Ok(None);
+        }
+        let partial = &mut state.partial;
+        if !partial.contains_key(&key) {
+            if partial.len() >= MAX_PARTIAL_TOTAL {
+                return Err("Too many partial messages in flight".to_string());
+            }
+            let from_source = partial.keys().filter(|(ip, _)| *ip == source).count();
+            if from_source >= MAX_PARTIAL_PER_SOURCE {
+                return Err(format!("Too many partial messages from {}", source));
+            }
+        }
+        let message = partial.entry(key).or_insert_with(|| PartialMessage {
+            started: now,
+            fragments: vec![None; fragment.count as usize],
+            received: 0,
+        });
+
+        if message.fragments.len() != fragment.count as usize {
+            partial.remove(&key);
+            return Err("Fragment count changed mid-message".to_string());
+        }
+
+        let slot = &mut message.fragments[fragment.index as usize];
+        if slot.is_none() {
+            *slot = Some(fragment.payload.clone());
+            message.received += 1;
+        }
+
+        if message.received < fragment.count {
+            return Ok(None);
+        }
+
+        let message = partial.remove(&key).unwrap();
+        if state.completed.len() >= MAX_COMPLETED
+            && let Some(oldest) = state
+                .completed
+                .iter()
+                .min_by_key(|(_, completed)| **completed)
+                .map(|(key, _)| *key)
+        {
+            state.completed.remove(&oldest);
+        }
+        state.completed.insert(key, now);
+        Ok(Some(
+            message.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Discards messages that have waited too long for their remaining fragments. Also runs on
+    /// every fragment, but needs calling periodically so a sender that goes quiet is cleaned up.
+    pub fn expire(&self, now: SystemTime) {
+        expire(&mut self.state.lock().unwrap(), now);
+    }
+}
+
+fn expire(state: &mut State, now: SystemTime) {
+    let age = |since: SystemTime| now.duration_since(since).unwrap_or(Duration::ZERO);
+    state
+        .completed
+        .retain(|_, completed| age(*completed) < REASSEMBLY_TIMEOUT);
+    state.partial.retain(|(source, message_id), message| {
+        if age(message.started) < REASSEMBLY_TIMEOUT {
+            return true;
+        }
+
+        metrics::REASSEMBLY_TIMEOUTS.inc();
+        log::warn!(
+            "Discarding message {} from {}: only {} of {} fragments arrived",
+            message_id,
+            source,
+            message.received,
+            message.fragments.len()
+        );
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    fn fragments(data: &[u8]) -> Vec<Fragment> {
+        split(Bytes::copy_from_slice(data))
+            .into_iter()
+            .map(|datagram| match Envelope::decode(datagram).unwrap().msg {
+                Some(envelope::Msg::Fragment(fragment)) => fragment,
+                other => panic!("expected a fragment, got {:?}", other),
+            })
+            .collect()
+    }
+
+    fn fragment(message_id: u64, index: u32, count: u32) -> Fragment {
+        Fragment {
+            message_id,
+            index,
+            count,
+            payload: vec![0; 10],
+        }
+    }
+
+    fn partial_count(reassembler: &Reassembler) -> usize {
+        reassembler.state.lock().unwrap().partial.len()
+    }
+
+    #[test]
+    fn small_messages_are_sent_whole() {
+        let data = Bytes::from(vec![7; MAX_DATAGRAM_SIZE]);
+        assert_eq!(split(data.clone()), [data]);
+    }
+
+    #[test]
+    fn reassembles_fragments_in_any_order() {
+        let data = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut pieces = fragments(&data);
+        assert_eq!(pieces.len(), 4);
+        for datagram in split(Bytes::from(data.clone())) {
+            assert!(datagram.len() <= MAX_DATAGRAM_SIZE);
+        }
+
+        pieces.reverse();
+        let reassembler = Reassembler::new();
+        let now = SystemTime::now();
+        let (last, rest) = pieces.split_last().unwrap();
+        for piece in rest {
+            assert_eq!(reassembler.add(SOURCE, piece, now), Ok(None));
+        }
+        assert_eq!(reassembler.add(SOURCE, last, now), Ok(Some(data)));
+        assert_eq!(partial_count(&reassembler), 0);
+    }
+
+    #[test]
+    fn ignores_late_copies_of_completed_messages() {
+        let pieces = fragments(&[1; 3000]);
+        let reassembler = Reassembler::new();
+        let now = SystemTime::now();
+        for piece in &pieces {
+            reassembler.add(SOURCE, piece, now).unwrap();
+        }
+
+        assert_eq!(reassembler.add(SOURCE, &pieces[2], now), Ok(None));
+        assert_eq!(partial_count(&reassembler), 0);
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let reassembler = Reassembler::new();
+        let now = SystemTime::now();
+        reassembler.add(SOURCE, &fragment(1, 0, 2), now).unwrap();
+
+        reassembler.expire(now + REASSEMBLY_TIMEOUT / 2);
+        assert_eq!(partial_count(&reassembler), 1);
+        reassembler.expire(now + REASSEMBLY_TIMEOUT);
+        assert_eq!(partial_count(&reassembler), 0);
+
+        // The rest arriving afterwards starts over rather than completing the message
+        assert_eq!(
+            reassembler.add(SOURCE, &fragment(1, 1, 2), now + REASSEMBLY_TIMEOUT),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_fragments() {
+        let reassembler = Reassembler::new();
+        let now = SystemTime::now();
+        assert!(reassembler.add(SOURCE, &fragment(1, 0, 0), now).is_err());
+        assert!(
+            reassembler
+                .add(SOURCE, &fragment(1, 0, MAX_FRAGMENTS + 1), now)
+                .is_err()
+        );
+        assert!(reassembler.add(SOURCE, &fragment(1, 2, 2), now).is_err());
+
+        let oversized = Fragment {
+            payload: vec![0; FRAGMENT_PAYLOAD_SIZE + 1],
+            ..fragment(1, 0, 2)
+        };
+        assert!(reassembler.add(SOURCE, &oversized, now).is_err());
+
+        reassembler.add(SOURCE, &fragment(2, 0, 2), now).unwrap();
+        assert!(reassembler.add(SOURCE, &fragment(2, 1, 3), now).is_err());
+        assert_eq!(partial_count(&reassembler), 0);
+    }
+
+    #[test]
+    fn limits_partial_messages_per_sender_and_in_total() {
+        let reassembler = Reassembler::new();
+        let now = SystemTime::now();
+        for message_id in 0..MAX_PARTIAL_PER_SOURCE as u64 {
+            reassembler
+                .add(SOURCE, &fragment(message_id, 0, 2), now)
+                .unwrap();
+        }
+        assert!(reassembler.add(SOURCE, &fragment(99, 0, 2), now).is_err());
+        // Fragments of messages already started are still accepted
+        assert!(reassembler.add(SOURCE, &fragment(0, 1, 2), now).is_ok());
+
+        let mut sources = (1..=u8::MAX).map(|i| IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 1, i)));
+        while partial_count(&reassembler) < MAX_PARTIAL_TOTAL {
+            let source = sources.next().unwrap();
+            for message_id in 0..MAX_PARTIAL_PER_SOURCE as u64 {
+                if partial_count(&reassembler) < MAX_PARTIAL_TOTAL {
+                    reassembler
+                        .add(source, &fragment(message_id, 0, 2), now)
+                        .unwrap();
+                }
+            }
+        }
+        let newcomer = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 2, 1));
+        assert!(reassembler.add(newcomer, &fragment(0, 0, 2), now).is_err());
+    }
+}
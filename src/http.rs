@@ -1,85 +1,712 @@
-use std::{convert::Infallible, fs, net::SocketAddr, path::PathBuf};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime};
+use std::{convert::Infallible, net::SocketAddr};
 
-use http_body_util::Full;
-use hyper::body::Bytes;
+use futures::stream::{self, Stream};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::{self, HeaderMap};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response};
+use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use log::{info, warn};
+use log::{debug, info, warn};
 use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio_rustls::TlsAcceptor;
 
+use crate::api::{self, LeaderboardEntry, TaskInfo};
+use crate::auth::{Access, Authenticator, Denied, Role};
 use crate::config::Config;
-use crate::metrics;
+use crate::election::Election;
+use crate::events::{Event, EventBus};
+use crate::net::Network;
+use crate::proto::homelabd::{WakeOnLanMessage, envelope};
+use crate::receivers::hostdb::HostDatabase;
+use crate::rollout::Rollout;
+use crate::scheduler::Schedulable;
+use crate::{binary, metrics, tls, wol};
 
-const BINARY_PATH: &str = "/proc/self/exe"; // For self-serve
+// Comment sent on idle event streams so proxies and clients don't time the connection out
+const SSE_KEEPALIVE: std::time::Duration = std::time::Duration::from_secs(15);
+// Clients that stall mid-handshake are dropped rather than holding a connection open
+const TLS_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+type Body = BoxBody<Bytes, Infallible>;
 
 pub struct HttpServer {
     config: Arc<Config>,
+    auth: Authenticator,
+    hostdb: Arc<HostDatabase>,
+    network: Arc<Network>,
+    events: Arc<EventBus>,
+    election: Arc<Election>,
+    rollout: Arc<Rollout>,
+    tasks: Vec<Arc<dyn Schedulable>>,
 }
 
 impl HttpServer {
-    pub fn new(config: Arc<Config>) -> Self {
-        HttpServer { config }
+    /// Fails if the `--auth-tokens` file can't be loaded.
+    pub fn new(
+        config: Arc<Config>,
+        hostdb: Arc<HostDatabase>,
+        network: Arc<Network>,
+        events: Arc<EventBus>,
+        election: Arc<Election>,
+        rollout: Arc<Rollout>,
+        tasks: Vec<Arc<dyn Schedulable>>,
+    ) -> Result<Self, String> {
+        Ok(HttpServer {
+            auth: Authenticator::new(&config)?,
+            config,
+            hostdb,
+            network,
+            events,
+            election,
+            rollout,
+            tasks,
+        })
     }
 
     pub async fn start(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let addr = SocketAddr::new(self.config.http_bind_ip, self.config.http_port);
 
-        let server = TcpListener::bind(&addr).await?;
-        info!("HTTP server listening on http://{}", addr);
+        let tls = tls::server_config(&self.config)?.map(TlsAcceptor::from);
 
-        let service = {
-            let this = Arc::clone(&self);
-            service_fn(move |req: Request<hyper::body::Incoming>| {
-                let this = Arc::clone(&this);
-                async move { this.route(req).await }
-            })
-        };
+        let server = TcpListener::bind(&addr).await?;
+        info!(
+            "HTTP server listening on {}://{}{}",
+            if tls.is_some() { "https" } else { "http" },
+            addr,
+            if self.auth.enabled() {
+                ""
+            } else {
+                " without authentication"
+            }
+        );
 
         loop {
             let (stream, _) = server.accept().await?;
-
-            let io = TokioIo::new(stream);
-            let service = service.clone();
+            let this = Arc::clone(&self);
+            let tls = tls.clone();
 
             tokio::task::spawn(async move {
-                if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
+                let result = match tls {
+                    Some(acceptor) => {
+                        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                            .await
+                        {
+                            Ok(Ok(stream)) => {
+                                // The verifier has already rejected certificates the CA didn't
+                                // sign
+                                let client_role = stream
+                                    .get_ref()
+                                    .1
+                                    .peer_certificates()
+                                    .and_then(|certs| certs.first())
+                                    .map(Role::of_certificate);
+                                this.serve(stream, client_role).await
+                            }
+                            Ok(Err(e)) => {
+                                debug!("TLS handshake failed: {}", e);
+                                return;
+                            }
+                            Err(_) => {
+                                debug!("TLS handshake timed out");
+                                return;
+                            }
+                        }
+                    }
+                    None => this.serve(stream, None).await,
+                };
+                if let Err(e) = result {
                     warn!("Failed to serve connection: {}", e);
                 }
             });
         }
     }
 
+    async fn serve<IO>(
+        self: Arc<Self>,
+        io: IO,
+        client_role: Option<Role>,
+    ) -> Result<(), hyper::Error>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+            let this = Arc::clone(&self);
+            async move { this.route(req, client_role).await }
+        });
+        http1::Builder::new()
+            .serve_connection(TokioIo::new(io), service)
+            .await
+    }
+
     async fn route(
         &self,
         req: Request<hyper::body::Incoming>,
-    ) -> Result<Response<Full<Bytes>>, Infallible> {
-        match req.uri().path() {
-            "/metrics" => {
+        client_role: Option<Role>,
+    ) -> Result<Response<Body>, Infallible> {
+        let start = Instant::now();
+        let path = req.uri().path().to_string();
+        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+        let access = Access::required(req.method(), &segments);
+        let (route, response) = match self.auth.authorize(access, req.headers(), client_role) {
+            Ok(()) => self.handle(req.method(), &segments, req.headers()).await,
+            Err(denied) => ("denied", denied_response(denied)),
+        };
+
+        metrics::HTTP_REQUESTS
+            .with_label_values(&[route, response.status().as_str()])
+            .inc();
+        metrics::HTTP_REQUEST_DURATION
+            .with_label_values(&[route])
+            .observe(start.elapsed().as_secs_f64());
+        Ok(response)
+    }
+
+    /// Serves a request, returning the matched route pattern (for metrics) with the response.
+    async fn handle(
+        &self,
+        method: &Method,
+        segments: &[&str],
+        headers: &HeaderMap,
+    ) -> (&'static str, Response<Body>) {
+        match (method, segments) {
+            (_, ["metrics"]) => {
+                self.hostdb.update_metrics();
                 let encoder = TextEncoder::new();
                 let metric_families = metrics::gather();
                 let mut buffer = Vec::new();
                 encoder.encode(&metric_families, &mut buffer).unwrap();
-                Ok(Response::builder()
+                let response = Response::builder()
                     .header("Content-Type", "text/plain; version=0.0.4")
-                    .body(Full::new(Bytes::from(buffer)))
-                    .unwrap())
+                    .body(full(Bytes::from(buffer)))
+                    .unwrap();
+                ("/metrics", response)
+            }
+            (_, ["homelabd"]) => ("/homelabd", self.serve_own_binary(headers)),
+            (_, ["homelabd", target, version]) => (
+                "/homelabd/{target}/{version}",
+                self.serve_cached_binary(target, version, headers),
+            ),
+            (&Method::GET, ["hosts"]) => (
+                "/hosts",
+                json_response(StatusCode::OK, &api::hosts(&self.hostdb, SystemTime::now())),
+            ),
+            (&Method::GET, ["services"]) => (
+                "/services",
+                json_response(StatusCode::OK, &api::services(&self.hostdb)),
+            ),
+            (&Method::GET, ["exporters"]) => (
+                "/exporters",
+                json_response(StatusCode::OK, &api::exporters(&self.hostdb)),
+            ),
+            (&Method::GET, ["leaderboard"]) => (
+                "/leaderboard",
+                json_response(StatusCode::OK, &self.leaderboard()),
+            ),
+            (&Method::GET, ["leader"]) => ("/leader", self.leader()),
+            (&Method::GET, ["rollout"]) => (
+                "/rollout",
+                json_response(StatusCode::OK, &self.rollout.status()),
+            ),
+            (&Method::POST, ["rollout", "start", version]) => (
+                "/rollout/start/{version}",
+                rollout_response(self.rollout.start(version)),
+            ),
+            (&Method::POST, ["rollout", "pause"]) => {
+                ("/rollout/pause", rollout_response(self.rollout.pause()))
+            }
+            (&Method::POST, ["rollout", "resume"]) => {
+                ("/rollout/resume", rollout_response(self.rollout.resume()))
+            }
+            (&Method::POST, ["rollout", "abort"]) => {
+                ("/rollout/abort", rollout_response(self.rollout.abort()))
+            }
+            (&Method::POST, ["rollout", "hold", host]) => (
+                "/rollout/hold/{host}",
+                rollout_response(self.rollout.hold(host)),
+            ),
+            (&Method::POST, ["rollout", "release", host]) => (
+                "/rollout/release/{host}",
+                rollout_response(self.rollout.release(host)),
+            ),
+            (&Method::GET, ["tasks"]) => {
+                ("/tasks", json_response(StatusCode::OK, &self.task_infos()))
+            }
+            (&Method::GET, ["events"]) => ("/events", self.event_stream()),
+            (&Method::POST, ["tasks", name]) => ("/tasks/{name}", self.trigger_task(name)),
+            (&Method::POST, ["hosts", name, "wake"]) => {
+                ("/hosts/{name}/wake", self.wake_host(name).await)
+            }
+            _ => (
+                "unmatched",
+                Response::builder()
+                    .status(404)
+                    .body(full(Bytes::from("Not Found")))
+                    .unwrap(),
+            ),
+        }
+    }
+
+    fn leaderboard(&self) -> Vec<LeaderboardEntry> {
+        let mut hosts = api::hosts(&self.hostdb, SystemTime::now());
+        hosts.sort_by_key(|host| std::cmp::Reverse(host.uptime_seconds));
+
+        hosts
+            .into_iter()
+            .enumerate()
+            .map(|(index, host)| LeaderboardEntry {
+                rank: index + 1,
+                hostname: host.hostname,
+                uptime_seconds: host.uptime_seconds,
+                version: host.version,
+            })
+            .collect()
+    }
+
+    fn serve_own_binary(&self, headers: &HeaderMap) -> Response<Body> {
+        match binary::current() {
+            Ok(info) => serve_binary(PathBuf::from(binary::BINARY_PATH), info, headers),
+            Err(e) => json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &serde_json::json!({ "error": format!("Failed to read binary: {}", e) }),
+            ),
+        }
+    }
+
+    /// Serves a build by target and version: this node's own, or a copy from the cache.
+    fn serve_cached_binary(
+        &self,
+        target: &str,
+        version: &str,
+        headers: &HeaderMap,
+    ) -> Response<Body> {
+        if target == binary::TARGET && version == env!("HOMELABD_VERSION") {
+            return self.serve_own_binary(headers);
+        }
+        match binary::BinaryCache::new(&self.config.state_dir).get(target, version) {
+            Some((path, info)) => serve_binary(path, &info, headers),
+            None => json_response(
+                StatusCode::NOT_FOUND,
+                &serde_json::json!({ "error": format!("No {} build of {} here", target, version) }),
+            ),
+        }
+    }
+
+    fn leader(&self) -> Response<Body> {
+        match api::leader(
+            &self.election,
+            &self.hostdb,
+            self.network.node_id(),
+            SystemTime::now(),
+        ) {
+            Some(leader) => json_response(StatusCode::OK, &leader),
+            None => json_response(
+                StatusCode::NOT_FOUND,
+                &serde_json::json!({ "error": "No node holds the lease" }),
+            ),
+        }
+    }
+
+    fn task_infos(&self) -> Vec<TaskInfo> {
+        self.tasks
+            .iter()
+            .map(|task| TaskInfo {
+                name: task.name().to_string(),
+                interval_seconds: task.interval_seconds(),
+            })
+            .collect()
+    }
+
+    /// Runs a scheduled task now, in the background, alongside its regular schedule.
+    fn trigger_task(&self, name: &str) -> Response<Body> {
+        let Some(task) = self.tasks.iter().find(|task| task.name() == name) else {
+            return json_response(
+                StatusCode::NOT_FOUND,
+                &serde_json::json!({ "error": format!("Unknown task {}", name) }),
+            );
+        };
+
+        info!("Triggering task {} on request", name);
+        let task = Arc::clone(task);
+        tokio::spawn(async move { task.run().await });
+
+        json_response(
+            StatusCode::ACCEPTED,
+            &serde_json::json!({ "triggered": name }),
+        )
+    }
+
+    /// Streams cluster events as Server-Sent Events until the client disconnects.
+    fn event_stream(&self) -> Response<Body> {
+        let receiver = self.events.subscribe();
+        let keepalive =
+            tokio::time::interval_at(tokio::time::Instant::now() + SSE_KEEPALIVE, SSE_KEEPALIVE);
+
+        let frames = stream::unfold(
+            (receiver, keepalive),
+            |(mut receiver, mut keepalive)| async {
+                let chunk = tokio::select! {
+                    received = receiver.recv() => match received {
+                        Ok(event) => sse_event(&event),
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            format!(": missed {} events\n\n", missed)
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                    _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+                };
+                Some((Ok(Frame::data(Bytes::from(chunk))), (receiver, keepalive)))
+            },
+        );
+
+        Response::builder()
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .body(streaming(frames))
+            .unwrap()
+    }
+
+    /// Asks whichever peer shares a network with the host to send it a magic packet.
+    async fn wake_host(&self, name: &str) -> Response<Body> {
+        let plan = match wol::plan(&self.hostdb, name, self.network.node_id()) {
+            Ok(plan) => plan,
+            Err(e) => {
+                return json_response(StatusCode::NOT_FOUND, &serde_json::json!({ "error": e }));
+            }
+        };
+
+        let msg = envelope::Msg::WakeOnLan(WakeOnLanMessage {
+            relay_node_id: plan.relay_node_id.clone(),
+            target_mac: plan.target_mac.clone(),
+            broadcast_address: plan.broadcast_address.to_string(),
+            target: plan.target.clone(),
+        });
+
+        if let Err(e) = self.network.send(msg).await {
+            return json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &serde_json::json!({ "error": format!("Failed to send wake request: {}", e) }),
+            );
+        }
+
+        info!(
+            "Asked {} to wake {} ({})",
+            plan.relay_hostname, plan.target, plan.target_mac
+        );
+        json_response(StatusCode::ACCEPTED, &plan)
+    }
+}
+
+/// Streams a build for peers to update from. Supports a single byte range, If-None-Match on the
+/// build hash, and zstd. Only whole responses are compressed: a range of the compressed stream
+/// couldn't be decoded on its own, so ranges always get the plain binary.
+fn serve_binary(path: PathBuf, info: &binary::BinaryInfo, headers: &HeaderMap) -> Response<Body> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let range = match header(header::RANGE).map(|range| binary::parse_range(range, info.size)) {
+        Some(Ok(range)) => range,
+        Some(Err(())) => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", info.size))
+                .body(full(Bytes::new()))
+                .unwrap();
+        }
+        None => None,
+    };
+    let compress = range.is_none()
+        && header(header::ACCEPT_ENCODING).is_some_and(|encodings| {
+            encodings
+                .split(',')
+                .any(|encoding| encoding.split(';').next().unwrap_or_default().trim() == "zstd")
+        });
+    let etag = if compress {
+        info.zstd_etag()
+    } else {
+        info.etag()
+    };
+
+    if header(header::IF_NONE_MATCH).is_some_and(|tags| {
+        tags.split(',')
+            .any(|tag| tag.trim() == etag || tag.trim() == "*")
+    }) {
+        return Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, &etag)
+            .header(header::VARY, "Accept-Encoding")
+            .body(full(Bytes::new()))
+            .unwrap();
+    }
+
+    let (start, len) = match range {
+        Some((start, end)) => (start, end - start + 1),
+        None => (0, info.size),
+    };
+    let encoding = if compress { "zstd" } else { "identity" };
+
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let result = binary::read_range(&path, start, len, compress, |chunk| {
+            metrics::BINARY_BYTES_SERVED
+                .with_label_values(&[encoding])
+                .inc_by(chunk.len() as u64);
+            sender
+                .blocking_send(Ok(Frame::data(Bytes::from(chunk))))
+                .is_ok()
+        });
+        if let Err(e) = result {
+            warn!("Failed to send binary: {}", e);
+        }
+    });
+    let frames = stream::unfold(receiver, |mut receiver| async {
+        receiver.recv().await.map(|frame| (frame, receiver))
+    });
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::VARY, "Accept-Encoding");
+    if let Some((start, end)) = range {
+        response = response.status(StatusCode::PARTIAL_CONTENT).header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, info.size),
+        );
+    }
+    if compress {
+        response = response.header(header::CONTENT_ENCODING, "zstd");
+    } else {
+        response = response.header(header::CONTENT_LENGTH, len);
+    }
+    response.body(streaming(frames)).unwrap()
+}
+
+fn rollout_response(result: Result<api::RolloutStatus, String>) -> Response<Body> {
+    match result {
+        Ok(status) => json_response(StatusCode::OK, &status),
+        Err(e) => json_response(StatusCode::CONFLICT, &serde_json::json!({ "error": e })),
+    }
+}
+
+fn denied_response(denied: Denied) -> Response<Body> {
+    match denied {
+        Denied::Unauthenticated => {
+            let mut response = json_response(
+                StatusCode::UNAUTHORIZED,
+                &serde_json::json!({ "error": "A bearer token or client certificate is required" }),
+            );
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Bearer"),
+            );
+            response
+        }
+        Denied::Forbidden => json_response(
+            StatusCode::FORBIDDEN,
+            &serde_json::json!({ "error": "This token may not use this route" }),
+        ),
+    }
+}
+
+fn sse_event(event: &Event) -> String {
+    format!(
+        "event: {}\ndata: {}\n\n",
+        event.name(),
+        serde_json::to_string(event).unwrap_or_default()
+    )
+}
+
+fn full(bytes: Bytes) -> Body {
+    Full::new(bytes).boxed()
+}
+
+fn streaming<S>(frames: S) -> Body
+where
+    S: Stream<Item = Result<Frame<Bytes>, Infallible>> + Send + Sync + 'static,
+{
+    BodyExt::boxed(StreamBody::new(frames))
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(full(Bytes::from(
+            serde_json::to_vec(body).unwrap_or_default(),
+        )))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Build {
+        path: PathBuf,
+        info: binary::BinaryInfo,
+        contents: Vec<u8>,
+    }
+
+    impl Build {
+        fn new() -> Self {
+            let contents = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let path =
+                std::env::temp_dir().join(format!("homelabd-binary-{}", uuid::Uuid::new_v4()));
+            std::fs::write(&path, &contents).unwrap();
+            let info = binary::BinaryInfo {
+                size: contents.len() as u64,
+                hash: binary::sha256(&contents),
+            };
+            Self {
+                path,
+                info,
+                contents,
             }
-            "/homelabd" => {
-                let bin =
-                    fs::read(PathBuf::from(BINARY_PATH)).unwrap_or_else(|_| b"error".to_vec());
-                Ok(Response::builder()
-                    .header("Content-Type", "application/octet-stream")
-                    .body(Full::new(Bytes::from(bin)))
-                    .unwrap())
+        }
+
+        async fn get(&self, headers: &[(header::HeaderName, &str)]) -> (Response<()>, Bytes) {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(name, value.parse().unwrap());
             }
-            _ => Ok(Response::builder()
-                .status(404)
-                .body(Full::new(Bytes::from("Not Found")))
-                .unwrap()),
+            let (parts, body) = serve_binary(self.path.clone(), &self.info, &map).into_parts();
+            let body = body.collect().await.unwrap().to_bytes();
+            (Response::from_parts(parts, ()), body)
+        }
+    }
+
+    impl Drop for Build {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn header_of(response: &Response<()>, name: header::HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    #[tokio::test]
+    async fn serves_the_whole_build_without_a_range() {
+        let build = Build::new();
+        let (response, body) = build.get(&[]).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_of(&response, header::ETAG),
+            Some(&*build.info.etag())
+        );
+        assert_eq!(header_of(&response, header::CONTENT_LENGTH), Some("200000"));
+        assert_eq!(body, build.contents);
+    }
+
+    #[tokio::test]
+    async fn serves_byte_ranges() {
+        let build = Build::new();
+        for (range, start, end) in [
+            ("bytes=0-0", 0, 0),
+            ("bytes=1000-70999", 1000, 70999),
+            ("bytes=199990-", 199990, 199999),
+            ("bytes=-16", 199984, 199999),
+        ] {
+            let (response, body) = build.get(&[(header::RANGE, range)]).await;
+
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+            assert_eq!(
+                header_of(&response, header::CONTENT_RANGE),
+                Some(&*format!("bytes {}-{}/200000", start, end))
+            );
+            assert_eq!(body, build.contents[start..=end], "{}", range);
         }
     }
+
+    #[tokio::test]
+    async fn refuses_unsatisfiable_ranges() {
+        let build = Build::new();
+        let (response, body) = build.get(&[(header::RANGE, "bytes=200000-")]).await;
+
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            header_of(&response, header::CONTENT_RANGE),
+            Some("bytes */200000")
+        );
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compresses_whole_responses_under_their_own_etag() {
+        let build = Build::new();
+        let (response, body) = build.get(&[(header::ACCEPT_ENCODING, "gzip, zstd")]).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_of(&response, header::CONTENT_ENCODING), Some("zstd"));
+        assert_eq!(
+            header_of(&response, header::ETAG),
+            Some(&*build.info.zstd_etag())
+        );
+        assert_ne!(build.info.zstd_etag(), build.info.etag());
+        assert_eq!(zstd::stream::decode_all(&body[..]).unwrap(), build.contents);
+
+        // Ranges of a compressed stream couldn't be decoded alone, so they're sent plain
+        let (response, body) = build
+            .get(&[
+                (header::ACCEPT_ENCODING, "zstd"),
+                (header::RANGE, "bytes=0-9"),
+            ])
+            .await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header_of(&response, header::CONTENT_ENCODING), None);
+        assert_eq!(
+            header_of(&response, header::ETAG),
+            Some(&*build.info.etag())
+        );
+        assert_eq!(body, build.contents[..10]);
+    }
+
+    #[tokio::test]
+    async fn answers_matching_conditional_requests_with_not_modified() {
+        let build = Build::new();
+        let etag = build.info.etag();
+        let zstd_etag = build.info.zstd_etag();
+
+        let (response, body) = build.get(&[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header_of(&response, header::ETAG), Some(&*etag));
+        assert!(body.is_empty());
+
+        let (response, _) = build.get(&[(header::IF_NONE_MATCH, "\"other\", *")]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let (response, _) = build
+            .get(&[
+                (header::ACCEPT_ENCODING, "zstd"),
+                (header::IF_NONE_MATCH, &zstd_etag),
+            ])
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header_of(&response, header::ETAG), Some(&*zstd_etag));
+
+        // A cached plain copy doesn't stand in for the compressed one, or the other way round
+        let (response, _) = build
+            .get(&[
+                (header::ACCEPT_ENCODING, "zstd"),
+                (header::IF_NONE_MATCH, &etag),
+            ])
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let (response, _) = build.get(&[(header::IF_NONE_MATCH, &zstd_etag)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 }
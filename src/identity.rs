@@ -0,0 +1,44 @@
+use crate::protocol;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const NODE_ID_FILE: &str = "node_id";
+const BOOT_FILE: &str = "boot";
+
+/// Loads this node's persistent ID from the state directory, creating one on first start. The
+/// ID survives hostname changes and reinstalls that keep the state directory.
+pub fn load_or_create_node_id(state_dir: &Path) -> io::Result<String> {
+    let path = state_dir.join(NODE_ID_FILE);
+
+    match fs::read_to_string(&path) {
+        Ok(contents) if !contents.trim().is_empty() => return Ok(contents.trim().to_string()),
+        Ok(_) => log::warn!("{} is empty, generating a new node ID", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let node_id = uuid::Uuid::new_v4().to_string();
+    fs::create_dir_all(state_dir)?;
+    fs::write(&path, format!("{}\n", node_id))?;
+    log::info!("Generated new node ID {} in {}", node_id, path.display());
+
+    Ok(node_id)
+}
+
+/// Numbers this start of the node, higher than any before it: the clock in microseconds, or one
+/// past the last start if the clock has gone backwards since.
+pub fn next_boot(state_dir: &Path) -> io::Result<u64> {
+    let path = state_dir.join(BOOT_FILE);
+    let previous = match fs::read_to_string(&path) {
+        Ok(contents) => contents.trim().parse::<u64>().unwrap_or(0),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+
+    let boot = protocol::clock_boot().max(previous.saturating_add(1));
+    fs::create_dir_all(state_dir)?;
+    fs::write(&path, format!("{}\n", boot))?;
+    Ok(boot)
+}
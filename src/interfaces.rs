@@ -0,0 +1,68 @@
+use crate::proto::homelabd::{InterfaceAddress, NetworkInterface};
+
+use if_addrs::get_if_addrs;
+use ipnet::IpNet;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+const SYS_VIRTUAL_NET: &str = "/sys/devices/virtual/net";
+
+/// Lists this machine's non-loopback interfaces with their addresses and link details.
+pub fn local_interfaces() -> Vec<NetworkInterface> {
+    let mut interfaces: Vec<NetworkInterface> = Vec::new();
+
+    for ifa in get_if_addrs().unwrap_or_default() {
+        if ifa.is_loopback() {
+            continue;
+        }
+
+        let address = InterfaceAddress {
+            address: ifa.ip().to_string(),
+            prefix_len: match &ifa.addr {
+                if_addrs::IfAddr::V4(v4) => v4.prefixlen as u32,
+                if_addrs::IfAddr::V6(v6) => v6.prefixlen as u32,
+            },
+        };
+
+        if let Some(existing) = interfaces.iter_mut().find(|i| i.name == ifa.name) {
+            existing.addresses.push(address);
+            continue;
+        }
+
+        let sys = Path::new(SYS_CLASS_NET).join(&ifa.name);
+        interfaces.push(NetworkInterface {
+            mac: read_sys(&sys.join("address"))
+                .filter(|mac| mac != "00:00:00:00:00:00")
+                .unwrap_or_default(),
+            mtu: read_sys(&sys.join("mtu"))
+                .and_then(|mtu| mtu.parse().ok())
+                .unwrap_or_default(),
+            up: ifa.is_oper_up(),
+            is_virtual: Path::new(SYS_VIRTUAL_NET).join(&ifa.name).exists(),
+            name: ifa.name,
+            addresses: vec![address],
+        });
+    }
+
+    interfaces
+}
+
+/// The networks an announced interface's addresses belong to.
+pub fn networks(interface: &NetworkInterface) -> Vec<IpNet> {
+    interface
+        .addresses
+        .iter()
+        .filter_map(|address| {
+            let ip = address.address.parse::<IpAddr>().ok()?;
+            IpNet::new(ip, address.prefix_len as u8)
+                .ok()
+                .map(|net| net.trunc())
+        })
+        .collect()
+}
+
+fn read_sys(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
@@ -1,60 +1,268 @@
+mod api;
+mod auth;
+mod binary;
+mod capture;
+mod clock;
+mod cluster_key;
 mod config;
+mod ctl;
 mod dispatch;
+mod dns;
+mod election;
+mod events;
+mod fragment;
 mod http;
+mod identity;
+mod interfaces;
 mod metrics;
 mod net;
+mod notify;
+mod primary_ip;
 mod proto;
+mod protocol;
 mod receivers;
+mod replay;
+mod resolve;
+mod rollout;
 mod scheduler;
+#[cfg(any(test, feature = "sim"))]
+mod sim;
+mod sniff;
 mod subsystems;
 mod tasks;
+mod tls;
+mod transport;
+mod update;
+mod wol;
 
 use clap::Parser;
 use config::Config;
 use receivers::hostdb;
 use receivers::prometheus::PrometheusEmitter;
+use receivers::update::SelfUpdater;
+use receivers::wol::WakeOnLanRelay;
 use scheduler::Scheduler;
 use std::sync::Arc;
-use subsystems::{prometheus_scan, self_update, system_info};
+use subsystems::{binary_cache, prometheus_scan, self_update, system_info, update_watchdog};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
     let config = Arc::new(Config::parse());
 
+    if let Some(command) = config.command.clone() {
+        let result = match command {
+            config::Command::Ctl(args) => ctl::run(&config, args).await,
+            config::Command::Sniff(args) => sniff::sniff(&config, args).await,
+            config::Command::Replay(args) => replay::replay(&config, args).await,
+            config::Command::NotifyTest => notify::run_test(&config).await,
+            config::Command::Ca(args) => tls::run(args),
+            // The simulator needs a runtime of its own so it can control time
+            #[cfg(any(test, feature = "sim"))]
+            config::Command::Sim(args) => {
+                let config = Arc::clone(&config);
+                tokio::task::spawn_blocking(move || sim::run(&config, args))
+                    .await
+                    .unwrap_or_else(|e| Err(e.into()))
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let node_id = identity::load_or_create_node_id(&config.state_dir).unwrap_or_else(|e| {
+        let node_id = uuid::Uuid::new_v4().to_string();
+        log::error!(
+            "Failed to load node ID from {}, using {} until restart: {}",
+            config.state_dir.display(),
+            node_id,
+            e
+        );
+        node_id
+    });
+    log::info!("Node ID: {}", node_id);
+    if let Err(e) = update::check_pending(&config.state_dir) {
+        log::error!("Failed to check for a pending update: {}", e);
+    }
+    metrics::BUILD_INFO
+        .with_label_values(&[env!("HOMELABD_VERSION")])
+        .set(1);
+    // Hash our own binary and any cached by hand now, so serving them never has to
+    if let Err(e) = binary::current() {
+        log::error!("Failed to read {}: {}", binary::BINARY_PATH, e);
+    }
+    binary::BinaryCache::new(&config.state_dir).hash_unhashed();
+
+    let transport = match net::transport(&config) {
+        Ok(transport) => transport,
+        Err(e) => {
+            log::error!("Failed to start {:?} transport: {}", config.transport, e);
+            std::process::exit(1);
+        }
+    };
+    log::info!(
+        "Listening for peers on port {} over {}",
+        config.multicast_port,
+        transport.name()
+    );
+    let cluster_key = cluster_key::ClusterKey::from_config(&config).unwrap_or_else(|e| {
+        log::error!("Failed to load the cluster key: {}", e);
+        std::process::exit(1);
+    });
+    if cluster_key.is_none() {
+        log::warn!("No --cluster-key, so control messages are accepted from anyone on the network");
+    }
+    let boot = identity::next_boot(&config.state_dir).unwrap_or_else(|e| {
+        log::error!(
+            "Failed to record this start in {}: {}",
+            config.state_dir.display(),
+            e
+        );
+        protocol::clock_boot()
+    });
+    let network = Arc::new(
+        net::Network::new(transport, node_id)
+            .with_boot(boot)
+            .with_cluster_key(cluster_key.clone()),
+    );
+
+    let peers = Arc::new(tls::PeerClient::new(&config).unwrap_or_else(|e| {
+        log::error!("Failed to set up the client for peers: {}", e);
+        std::process::exit(1);
+    }));
+
     let mut scheduler = Scheduler::new(&config);
-    let mut dispatcher = dispatch::Dispatcher::new();
+    let mut dispatcher = dispatch::Dispatcher::new().with_cluster_key(cluster_key);
 
-    scheduler.register(Arc::new(system_info::SystemInfo::new(&config, 10)));
+    scheduler.register(Arc::new(system_info::SystemInfo::new(
+        &config,
+        10,
+        Arc::clone(&network),
+    )));
     scheduler.register(Arc::new(prometheus_scan::PrometheusScan::new(
-        Arc::clone(&config),
+        Arc::clone(&network),
         30,
     )));
-    scheduler.register(Arc::new(self_update::SelfUpdateCheck::new(&config, 60)));
 
-    let hostdb = Arc::new(hostdb::HostDatabase::new(&config));
+    let events = Arc::new(events::EventBus::new());
+    let hostdb = Arc::new(hostdb::HostDatabase::new(&config, Arc::clone(&events)));
+    network.watch_peers(Arc::clone(&hostdb));
     scheduler.register(Arc::clone(&hostdb));
+    scheduler.register(Arc::new(self_update::SelfUpdateCheck::new(
+        Arc::clone(&hostdb),
+        Arc::clone(&events),
+        60,
+    )));
+    if config.cache_binaries {
+        scheduler.register(Arc::new(binary_cache::BinaryCacheSync::new(
+            Arc::clone(&hostdb),
+            binary::BinaryCache::new(&config.state_dir),
+            Arc::clone(&peers),
+            config.state_dir.clone(),
+            300,
+        )));
+    }
+    scheduler.register(Arc::new(update_watchdog::UpdateWatchdog::new(
+        config.state_dir.clone(),
+        Arc::clone(&hostdb),
+        network.node_id(),
+        std::time::Duration::from_secs(config.update_confirm_seconds),
+    )));
+    dispatcher.watch_peers(Arc::clone(&hostdb));
     dispatcher.register(Arc::clone(&hostdb));
+    dispatcher.register(Arc::new(WakeOnLanRelay::new(network.node_id())));
 
+    let election = Arc::new(election::Election::new(
+        Arc::clone(&network),
+        Arc::clone(&hostdb),
+        Arc::new(clock::SystemClock),
+        &config.state_dir,
+    ));
+    scheduler.register(Arc::clone(&election));
+    dispatcher.register(Arc::clone(&election));
+    dispatcher.register(Arc::new(SelfUpdater::new(
+        &config,
+        network.node_id(),
+        Arc::clone(&hostdb),
+        Arc::clone(&election),
+        Arc::clone(&peers),
+    )));
+
+    let rollout = Arc::new(rollout::Rollout::new(
+        &config,
+        Arc::clone(&network),
+        Arc::clone(&hostdb),
+        Arc::clone(&election),
+        peers,
+    ));
+    scheduler.register(Arc::clone(&rollout));
+
+    // Every node keeps the targets up to date so a new leader can take over the file right away
     if let Ok(prometheus_emitter) = PrometheusEmitter::new(&config, Arc::clone(&hostdb)) {
         let emitter = Arc::new(prometheus_emitter);
         dispatcher.register(Arc::clone(&emitter));
-        scheduler.register(Arc::clone(&emitter));
+        scheduler.register(Arc::new(election::LeaderOnly::new(
+            emitter,
+            Arc::clone(&election),
+        )));
     } else {
         log::warn!("Prometheus discovery is disabled or configuration is invalid.");
     }
 
-    let multicast_config = Arc::clone(&config);
-    let http_config = Arc::clone(&config);
+    if let Some(path) = &config.notifications {
+        match notify::NotificationConfig::load(path) {
+            Ok(notifications) => {
+                let notifier = notify::Notifier::new(notifications, Arc::clone(&events))
+                    .unwrap_or_else(|e| {
+                        log::error!("Failed to set up notifications: {}", e);
+                        std::process::exit(1);
+                    })
+                    .leader_only(Arc::clone(&election));
+                tokio::spawn(notifier.start());
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to load notifications from {}: {}",
+                    path.display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
 
-    tokio::spawn(async move {
-        if let Err(e) = net::start_multicast_listener(&multicast_config, dispatcher).await {
-            log::error!("Failed to start multicast listener: {}", e);
-            std::process::exit(1);
+    match dns::DnsServer::new(Arc::clone(&config), Arc::clone(&hostdb)) {
+        Ok(dns_server) => {
+            tokio::spawn(async move {
+                if let Err(e) = Arc::new(dns_server).start().await {
+                    log::error!("Failed to start DNS server: {}", e);
+                    std::process::exit(1);
+                }
+            });
         }
+        Err(e) => log::info!("DNS responder is disabled: {}", e),
+    }
+
+    let http_server = http::HttpServer::new(
+        Arc::clone(&config),
+        Arc::clone(&hostdb),
+        Arc::clone(&network),
+        Arc::clone(&events),
+        Arc::clone(&election),
+        Arc::clone(&rollout),
+        scheduler.tasks(),
+    )
+    .unwrap_or_else(|e| {
+        log::error!("Failed to set up the HTTP server: {}", e);
+        std::process::exit(1);
     });
+    let http_server = Arc::new(http_server);
+
+    tokio::spawn(net::start_listener(network.transport(), dispatcher));
     tokio::spawn(async move {
-        let http_server = Arc::new(http::HttpServer::new(http_config));
         if let Err(e) = http_server.start().await {
             log::error!("Failed to start HTTP server: {}", e);
             std::process::exit(1);
@@ -1,5 +1,7 @@
 use once_cell::sync::Lazy;
-use prometheus::{IntCounter, IntCounterVec, Registry};
+use prometheus::{
+    Gauge, GaugeVec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry,
+};
 
 pub static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
 
@@ -31,6 +33,289 @@ pub static MESSAGES_FAILED_DISPATCH: Lazy<IntCounterVec> = Lazy::new(|| {
     m
 });
 
+pub static MESSAGES_UNKNOWN: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::new(
+        "homelabd_messages_unknown",
+        "Messages with a payload type this node does not understand",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static MESSAGES_DUPLICATE: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::new(
+        "homelabd_messages_duplicate",
+        "Messages dropped because their sender's sequence number was already seen or is stale",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static MESSAGES_UNAUTHENTICATED: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::new(
+        "homelabd_messages_unauthenticated",
+        "Control messages dropped because they weren't signed with the cluster key",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static MESSAGES_MISSED: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::new(
+        "homelabd_messages_missed",
+        "Messages never received, judging by gaps in senders' sequence numbers",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static MESSAGES_UNHANDLED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_messages_unhandled",
+        "Messages with no handler registered for their type",
+    );
+    let m = IntCounterVec::new(opts, &["type"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static MESSAGES_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_messages_dropped",
+        "Messages dropped because a handler queue was full",
+    );
+    let m = IntCounterVec::new(opts, &["handler"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static DISPATCH_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = prometheus::HistogramOpts::new(
+        "homelabd_dispatch_duration_seconds",
+        "Time taken by a handler to process a message",
+    );
+    let m = HistogramVec::new(opts, &["handler"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static ADDRESS_MISMATCHES: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::new(
+        "homelabd_address_mismatches",
+        "Announcements whose claimed addresses did not include the sender's address",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static FRAGMENTS_RECEIVED: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::new(
+        "homelabd_fragments_received",
+        "Fragments of oversized messages received",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static REASSEMBLY_TIMEOUTS: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::new(
+        "homelabd_reassembly_timeouts",
+        "Fragmented messages discarded because not every fragment arrived in time",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static HOSTNAME_COLLISIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_hostname_collisions",
+        "New nodes seen announcing a hostname already used by another node",
+    );
+    let m = IntCounterVec::new(opts, &["hostname"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static TRANSPORT_BYTES_SENT: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_transport_bytes_sent",
+        "Bytes sent, by transport backend",
+    );
+    let m = IntCounterVec::new(opts, &["transport"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static TRANSPORT_BYTES_RECEIVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_transport_bytes_received",
+        "Bytes received, by transport backend",
+    );
+    let m = IntCounterVec::new(opts, &["transport"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static TRANSPORT_SEND_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_transport_send_errors",
+        "Datagrams that could not be sent, by transport backend",
+    );
+    let m = IntCounterVec::new(opts, &["transport"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static TRANSPORT_RECEIVE_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_transport_receive_errors",
+        "Errors receiving datagrams, by transport backend",
+    );
+    let m = IntCounterVec::new(opts, &["transport"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static MESSAGES_BY_TYPE: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_messages_by_type",
+        "Messages received, by payload type",
+    );
+    let m = IntCounterVec::new(opts, &["type"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static MESSAGES_BY_SENDER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_messages_by_sender",
+        "Messages accepted, by sender node ID, with senders not yet known as \"unknown\"",
+    );
+    let m = IntCounterVec::new(opts, &["sender"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static DECODE_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::new(
+        "homelabd_decode_failures",
+        "Datagrams or reassembled messages that could not be decoded",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static KNOWN_HOSTS: Lazy<IntGauge> = Lazy::new(|| {
+    let m = IntGauge::new("homelabd_known_hosts", "Hosts in the host database").unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static KNOWN_EXPORTERS: Lazy<IntGauge> = Lazy::new(|| {
+    let m = IntGauge::new(
+        "homelabd_known_exporters",
+        "Prometheus exporters announced by known hosts",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static OLDEST_HOST_AGE: Lazy<Gauge> = Lazy::new(|| {
+    let m = Gauge::new(
+        "homelabd_oldest_host_age_seconds",
+        "Time since the least recently seen host last announced itself",
+    )
+    .unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static PEER_LAST_SEEN: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_peer_last_seen_seconds",
+        "Time since each known host last announced itself, as seen by this node",
+    );
+    let m = GaugeVec::new(opts, &["host", "node_id"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static PEER_UPTIME: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_peer_uptime_seconds",
+        "Uptime of each known host, as seen by this node",
+    );
+    let m = GaugeVec::new(opts, &["host", "node_id"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static PEER_VERSION: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_peer_version",
+        "Always 1, labelled with the homelabd version each known host runs",
+    );
+    let m = IntGaugeVec::new(opts, &["host", "node_id", "version"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new("homelabd_http_requests", "HTTP requests served");
+    let m = IntCounterVec::new(opts, &["route", "status"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = prometheus::HistogramOpts::new(
+        "homelabd_http_request_duration_seconds",
+        "Time taken to serve an HTTP request",
+    );
+    let m = HistogramVec::new(opts, &["route"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static BINARY_BYTES_SERVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_binary_bytes_served",
+        "Bytes of the homelabd binary sent to peers, by content encoding",
+    );
+    let m = IntCounterVec::new(opts, &["encoding"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static BINARY_CHUNKS_FETCHED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_binary_chunks_fetched",
+        "Chunks of an update fetched from peers, by peer and result",
+    );
+    let m = IntCounterVec::new(opts, &["peer", "result"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
+pub static BUILD_INFO: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let opts = prometheus::Opts::new(
+        "homelabd_build_info",
+        "Always 1, labelled with the running build",
+    );
+    let m = IntGaugeVec::new(opts, &["version"]).unwrap();
+    REGISTRY.register(Box::new(m.clone())).unwrap();
+    m
+});
+
 pub fn gather() -> Vec<prometheus::proto::MetricFamily> {
     REGISTRY.gather()
 }
@@ -1,43 +1,328 @@
-use crate::{config::Config, dispatch::Dispatcher, metrics::MESSAGES_SENT};
+use crate::cluster_key::ClusterKey;
+use crate::config::{Config, TransportKind};
+use crate::dispatch::{Dispatcher, MessageContext, MessageType};
+use crate::fragment;
+use crate::metrics;
+use crate::proto::homelabd::envelope;
+use crate::protocol::{self, capability};
+use crate::receivers::hostdb::HostDatabase;
+use crate::transport::{Datagram, Transport};
+
 use bytes::Bytes;
+use if_addrs::{IfAddr, Interface, get_if_addrs};
 use log::info;
+use once_cell::sync::{Lazy, OnceCell};
+use prost::Message;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::net::UdpSocket;
 
-pub async fn start_multicast_listener(
-    config: &Config,
-    mut dispatcher: Dispatcher,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let listen_addr: Ipv4Addr = "0.0.0.0".parse()?;
+// Large enough for any UDP datagram, so oversized messages from a misbehaving peer are
+// rejected whole rather than silently truncated
+pub const RECEIVE_BUFFER_SIZE: usize = 65536;
+
+// How long the local interface list is reused before asking the kernel again
+const INTERFACE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+type InterfaceCache = Option<(Instant, Vec<Interface>)>;
 
-    let addr = SocketAddrV4::new(listen_addr, config.multicast_port);
+static INTERFACES: Lazy<Mutex<InterfaceCache>> = Lazy::new(|| Mutex::new(None));
+
+/// Binds the multicast port and joins the group. The socket shares the port with any other
+/// listener on this machine, so this is safe to use alongside a running daemon.
+pub fn join_multicast(config: &Config) -> std::io::Result<UdpSocket> {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.multicast_port);
     let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
     socket.set_reuse_address(true)?;
     socket.set_nonblocking(true)?;
     socket.bind(&addr.into())?;
     socket.join_multicast_v4(&config.multicast_group, &Ipv4Addr::UNSPECIFIED)?;
-    let socket = UdpSocket::from_std(socket.into())?;
+    UdpSocket::from_std(socket.into())
+}
 
-    info!(
-        "Multicast listener started on {}:{}",
-        config.multicast_group, config.multicast_port
-    );
+/// Builds the transport selected in the config.
+pub fn transport(config: &Config) -> std::io::Result<Arc<dyn Transport>> {
+    Ok(match config.transport {
+        TransportKind::Multicast => Arc::new(MulticastTransport::new(config)?),
+        TransportKind::Unicast => Arc::new(UnicastTransport::new(config)?),
+    })
+}
 
-    let mut buf = vec![0u8; 1500];
-    loop {
-        if let Ok((size, peer)) = socket.recv_from(&mut buf).await {
-            let data = &buf[..size];
-            info!("Received {} bytes from {}", size, peer);
-            dispatcher.dispatch(data);
+/// The default network: UDP multicast to the configured group, sending and receiving on one
+/// socket.
+pub struct MulticastTransport {
+    group: SocketAddrV4,
+    socket: UdpSocket,
+    buf: tokio::sync::Mutex<Vec<u8>>,
+}
+
+impl MulticastTransport {
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        Ok(Self {
+            group: SocketAddrV4::new(config.multicast_group, config.multicast_port),
+            socket: join_multicast(config)?,
+            buf: tokio::sync::Mutex::new(vec![0u8; RECEIVE_BUFFER_SIZE]),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for MulticastTransport {
+    fn name(&self) -> &'static str {
+        "multicast"
+    }
+
+    async fn send(&self, datagram: Bytes) -> std::io::Result<()> {
+        self.socket.send_to(&datagram, self.group).await?;
+        Ok(())
+    }
+
+    async fn recv(&self) -> std::io::Result<Datagram> {
+        recv_udp(&self.socket, &self.buf).await
+    }
+}
+
+/// For networks that don't pass multicast: sends a copy of every datagram to each configured
+/// peer and listens on the multicast port for theirs.
+pub struct UnicastTransport {
+    peers: Vec<SocketAddr>,
+    socket: UdpSocket,
+    buf: tokio::sync::Mutex<Vec<u8>>,
+}
+
+impl UnicastTransport {
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        if config.unicast_peers.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The unicast transport needs at least one peer in --unicast-peers",
+            ));
+        }
+
+        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.multicast_port);
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+
+        Ok(Self {
+            peers: config.unicast_peers.clone(),
+            socket: UdpSocket::from_std(socket.into())?,
+            buf: tokio::sync::Mutex::new(vec![0u8; RECEIVE_BUFFER_SIZE]),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for UnicastTransport {
+    fn name(&self) -> &'static str {
+        "unicast"
+    }
+
+    /// Tries every peer even if some fail, reporting the last error.
+    async fn send(&self, datagram: Bytes) -> std::io::Result<()> {
+        let mut result = Ok(());
+        for peer in &self.peers {
+            if let Err(e) = self.socket.send_to(&datagram, peer).await {
+                result = Err(std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to send to {}: {}", peer, e),
+                ));
+            }
         }
+        result
+    }
+
+    async fn recv(&self) -> std::io::Result<Datagram> {
+        recv_udp(&self.socket, &self.buf).await
     }
 }
 
-pub async fn send_multicast(config: &Config, data: Bytes) -> std::io::Result<()> {
-    let addr = SocketAddrV4::new(config.multicast_group, config.multicast_port);
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.send_to(&data, addr).await?;
-    MESSAGES_SENT.inc();
-    Ok(())
+async fn recv_udp(
+    socket: &UdpSocket,
+    buf: &tokio::sync::Mutex<Vec<u8>>,
+) -> std::io::Result<Datagram> {
+    let mut buf = buf.lock().await;
+    let (size, source) = socket.recv_from(&mut buf).await?;
+
+    Ok(Datagram {
+        data: Bytes::copy_from_slice(&buf[..size]),
+        source,
+        interface: interface_for(source.ip()),
+        received_at: SystemTime::now(),
+    })
+}
+
+/// This node's view of the network: stamps outgoing messages with its identity and sends them
+/// to every peer over the transport.
+pub struct Network {
+    transport: Arc<dyn Transport>,
+    node_id: String,
+    boot: u64,
+    sequence: AtomicU64,
+    /// Signed messages are numbered separately, as receivers track them separately
+    signed_sequence: AtomicU64,
+    /// Known peers, to check they can all reassemble fragments
+    peers: OnceCell<Arc<HostDatabase>>,
+    /// Signs control messages, if the cluster has a key
+    cluster_key: Option<ClusterKey>,
+}
+
+impl Network {
+    pub fn new(transport: Arc<dyn Transport>, node_id: String) -> Self {
+        Self {
+            transport,
+            node_id,
+            boot: protocol::clock_boot(),
+            sequence: AtomicU64::new(0),
+            signed_sequence: AtomicU64::new(0),
+            peers: OnceCell::new(),
+            cluster_key: None,
+        }
+    }
+
+    /// Signs control messages with `key`, if there is one.
+    pub fn with_cluster_key(mut self, key: Option<ClusterKey>) -> Self {
+        self.cluster_key = key;
+        self
+    }
+
+    /// Numbers this start of the node `boot`, which must be higher than on any earlier start.
+    pub fn with_boot(mut self, boot: u64) -> Self {
+        self.boot = boot;
+        self
+    }
+
+    /// Lets the network see which peers are around, so it only fragments messages when they all
+    /// can reassemble them.
+    pub fn watch_peers(&self, hostdb: Arc<HostDatabase>) {
+        let _ = self.peers.set(hostdb);
+    }
+
+    /// Whether every known peer advertises `FRAGMENTATION`.
+    fn peers_reassemble(&self) -> bool {
+        self.peers.get().is_none_or(|hostdb| {
+            hostdb.hosts().iter().all(|host| {
+                host.node_id == self.node_id || host.supports(capability::FRAGMENTATION)
+            })
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn transport(&self) -> Arc<dyn Transport> {
+        Arc::clone(&self.transport)
+    }
+
+    /// Sends a message to every peer, fragmenting it if it won't fit in one datagram and every
+    /// peer can put it back together.
+    pub async fn send(&self, msg: envelope::Msg) -> std::io::Result<()> {
+        let control = MessageType::of(&msg).is_control();
+        let key = self.cluster_key.as_ref().filter(|_| control);
+        let sequence = match key {
+            Some(_) => &self.signed_sequence,
+            None => &self.sequence,
+        };
+        let sequence = sequence.fetch_add(1, Ordering::Relaxed);
+        let mut envelope = protocol::envelope(&self.node_id, self.boot, sequence, msg);
+        if let Some(key) = key {
+            envelope = key.sign(&envelope);
+        }
+        let data = Bytes::from(envelope.encode_to_vec());
+        let datagrams = if self.peers_reassemble() {
+            fragment::split(data)
+        } else {
+            vec![data]
+        };
+
+        let backend = self.transport.name();
+        for datagram in datagrams {
+            let size = datagram.len() as u64;
+            if let Err(e) = self.transport.send(datagram).await {
+                metrics::TRANSPORT_SEND_ERRORS
+                    .with_label_values(&[backend])
+                    .inc();
+                return Err(e);
+            }
+            metrics::TRANSPORT_BYTES_SENT
+                .with_label_values(&[backend])
+                .inc_by(size);
+        }
+        metrics::MESSAGES_SENT.inc();
+        Ok(())
+    }
+}
+
+/// Feeds everything the transport receives to the dispatcher, forever.
+pub async fn start_listener(transport: Arc<dyn Transport>, dispatcher: Dispatcher) {
+    let backend = transport.name();
+    let mut expiry = tokio::time::interval(fragment::REASSEMBLY_TIMEOUT);
+    loop {
+        let received = tokio::select! {
+            received = transport.recv() => received,
+            _ = expiry.tick() => {
+                dispatcher.expire_fragments(SystemTime::now());
+                continue;
+            }
+        };
+        let datagram = match received {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                metrics::TRANSPORT_RECEIVE_ERRORS
+                    .with_label_values(&[backend])
+                    .inc();
+                log::warn!("Failed to receive datagram: {}", e);
+                continue;
+            }
+        };
+        metrics::TRANSPORT_BYTES_RECEIVED
+            .with_label_values(&[backend])
+            .inc_by(datagram.data.len() as u64);
+
+        info!(
+            "Received {} bytes from {}",
+            datagram.data.len(),
+            datagram.source
+        );
+        let context = MessageContext {
+            source: datagram.source,
+            interface: datagram.interface,
+            received_at: datagram.received_at,
+            size: datagram.data.len(),
+        };
+        dispatcher.dispatch(&datagram.data, context);
+    }
+}
+
+/// Finds the local interface whose IPv4 subnet contains the given address. Runs for every
+/// datagram, so the interface list is cached briefly.
+pub fn interface_for(addr: IpAddr) -> Option<String> {
+    let IpAddr::V4(addr) = addr else {
+        return None;
+    };
+
+    let mut cache = INTERFACES.lock().unwrap();
+    if cache
+        .as_ref()
+        .is_none_or(|(fetched, _)| fetched.elapsed() > INTERFACE_CACHE_TTL)
+    {
+        *cache = Some((Instant::now(), get_if_addrs().unwrap_or_default()));
+    }
+    let (_, interfaces) = cache.as_ref()?;
+
+    interfaces
+        .iter()
+        .find(|iface| match &iface.addr {
+            IfAddr::V4(v4) => {
+                let mask = u32::from(v4.netmask);
+                u32::from(v4.ip) & mask == u32::from(addr) & mask
+            }
+            IfAddr::V6(_) => false,
+        })
+        .map(|iface| iface.name.clone())
 }
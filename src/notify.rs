@@ -0,0 +1,641 @@
+//! Outbound notifications for cluster events: JSON webhooks, ntfy and Gotify pushes, and email.
+//!
+//! Sinks are configured in a JSON file passed with `--notifications`, for example:
+//!
+//! ```json
+//! {
+//!   "dedup_window_seconds": 300,
+//!   "sinks": [
+//!     { "type": "ntfy", "url": "http://ntfy.lan/homelab", "events": ["host_left", "version_changed"] },
+//!     { "type": "webhook", "url": "http://automation.lan:8123/api/webhook/homelabd" },
+//!     { "type": "smtp", "server": "mail.lan:25", "from": "homelabd@lan", "to": ["me@example.com"] }
+//!   ]
+//! }
+//! ```
+//!
+//! HTTPS endpoints are checked against the public roots, or the cluster CA if `--tls-ca` is set.
+//! Only unauthenticated SMTP is spoken, so external mail servers need a local relay. Every node
+//! sees the same events, so only the leader sends them.
+
+use crate::config::Config;
+use crate::election::Election;
+use crate::events::{Event, EventBus};
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+// Give up on a single delivery attempt after this long
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Backoff between retries doubles from the first value up to the second
+const RETRY_BACKOFF: (Duration, Duration) = (Duration::from_secs(1), Duration::from_secs(60));
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationConfig {
+    /// Identical notifications to the same sink within this window are sent only once
+    #[serde(default = "default_dedup_window")]
+    pub dedup_window_seconds: u64,
+    pub sinks: Vec<SinkConfig>,
+}
+
+fn default_dedup_window() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// Event types to send (e.g. "host_left"); everything if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    /// POSTs the event as JSON
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// POSTs a plain-text message to an ntfy topic URL
+    Ntfy {
+        url: String,
+        token: Option<String>,
+        priority: Option<u8>,
+    },
+    /// POSTs to a Gotify server's message API
+    Gotify {
+        url: String,
+        token: String,
+        priority: Option<u8>,
+    },
+    /// Sends an email through an SMTP relay that accepts mail without authentication
+    Smtp {
+        /// host:port of the relay
+        server: String,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+impl SinkKind {
+    fn name(&self) -> &'static str {
+        match self {
+            SinkKind::Webhook { .. } => "webhook",
+            SinkKind::Ntfy { .. } => "ntfy",
+            SinkKind::Gotify { .. } => "gotify",
+            SinkKind::Smtp { .. } => "smtp",
+        }
+    }
+
+    fn urls(&self) -> Vec<&str> {
+        match self {
+            SinkKind::Webhook { url, .. }
+            | SinkKind::Ntfy { url, .. }
+            | SinkKind::Gotify { url, .. } => vec![url],
+            SinkKind::Smtp { .. } => Vec::new(),
+        }
+    }
+}
+
+impl NotificationConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read(path).map_err(|e| e.to_string())?;
+        let config = serde_json::from_slice::<Self>(&contents).map_err(|e| e.to_string())?;
+
+        for sink in &config.sinks {
+            for url in sink.kind.urls() {
+                let uri = url
+                    .parse::<Uri>()
+                    .map_err(|e| format!("Invalid {} URL {}: {}", sink.kind.name(), url, e))?;
+                if !matches!(uri.scheme_str(), Some("http" | "https")) {
+                    return Err(format!(
+                        "{} URL {} must use http:// or https://",
+                        sink.kind.name(),
+                        url
+                    ));
+                }
+            }
+            if let Some(unknown) = sink
+                .events
+                .iter()
+                .find(|name| !Event::NAMES.contains(&name.as_str()))
+            {
+                return Err(format!(
+                    "Unknown event type {} (expected one of {})",
+                    unknown,
+                    Event::NAMES.join(", ")
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// The body of webhook notifications.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    summary: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+struct Sink {
+    config: SinkConfig,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl Sink {
+    fn wants(&self, event: &Event) -> bool {
+        self.config.events.is_empty() || self.config.events.iter().any(|e| e == event.name())
+    }
+
+    async fn deliver(&self, event: &Event) -> Result<(), String> {
+        tokio::time::timeout(DELIVERY_TIMEOUT, self.send(event))
+            .await
+            .map_err(|_| "Timed out".to_string())?
+    }
+
+    async fn send(&self, event: &Event) -> Result<(), String> {
+        let summary = event.summary();
+
+        match &self.config.kind {
+            SinkKind::Webhook { url, headers } => {
+                let payload = WebhookPayload { summary, event };
+                let body = serde_json::to_vec(&payload).map_err(|e| e.to_string())?;
+                let mut request = Request::builder()
+                    .method(Method::POST)
+                    .uri(url)
+                    .header("Content-Type", "application/json");
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+                self.post(request, body).await
+            }
+            SinkKind::Ntfy {
+                url,
+                token,
+                priority,
+            } => {
+                let mut request = Request::builder()
+                    .method(Method::POST)
+                    .uri(url)
+                    .header("Title", "homelabd")
+                    .header("Tags", event.name());
+                if let Some(token) = token {
+                    request = request.header("Authorization", format!("Bearer {}", token));
+                }
+                if let Some(priority) = priority {
+                    request = request.header("Priority", priority.to_string());
+                }
+                self.post(request, summary.into_bytes()).await
+            }
+            SinkKind::Gotify {
+                url,
+                token,
+                priority,
+            } => {
+                let body = serde_json::json!({
+                    "title": "homelabd",
+                    "message": summary,
+                    "priority": priority.unwrap_or(5),
+                });
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("{}/message", url.trim_end_matches('/')))
+                    .header("Content-Type", "application/json")
+                    .header("X-Gotify-Key", token);
+                self.post(request, body.to_string().into_bytes()).await
+            }
+            SinkKind::Smtp { server, from, to } => send_mail(server, from, to, &summary).await,
+        }
+    }
+
+    async fn post(
+        &self,
+        request: hyper::http::request::Builder,
+        body: Vec<u8>,
+    ) -> Result<(), String> {
+        let request = request
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| e.to_string())?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map(|body| body.to_bytes())
+            .unwrap_or_default();
+        Err(format!(
+            "{}: {}",
+            status,
+            String::from_utf8_lossy(&body).trim()
+        ))
+    }
+
+    /// Delivers an event, retrying with exponential backoff.
+    async fn deliver_with_retries(&self, event: &Event) {
+        let mut backoff = RETRY_BACKOFF.0;
+        for attempt in 0..=self.config.max_retries {
+            match self.deliver(event).await {
+                Ok(()) => {
+                    log::info!(
+                        "Sent {} notification via {}",
+                        event.name(),
+                        self.config.kind.name()
+                    );
+                    return;
+                }
+                Err(e) if attempt < self.config.max_retries => {
+                    log::warn!(
+                        "Failed to send {} notification via {} (retrying in {:?}): {}",
+                        event.name(),
+                        self.config.kind.name(),
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(RETRY_BACKOFF.1);
+                }
+                Err(e) => log::error!(
+                    "Giving up on {} notification via {}: {}",
+                    event.name(),
+                    self.config.kind.name(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// Sends a single plain-text email. Just enough SMTP for a local relay: no TLS or auth.
+async fn send_mail(server: &str, from: &str, to: &[String], body: &str) -> Result<(), String> {
+    let stream = TcpStream::connect(server)
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {}", server, e))?;
+    let mut stream = BufReader::new(stream);
+
+    expect_reply(&mut stream, 220).await?;
+    command(&mut stream, "HELO homelabd", 250).await?;
+    command(&mut stream, &format!("MAIL FROM:<{}>", from), 250).await?;
+    for recipient in to {
+        command(&mut stream, &format!("RCPT TO:<{}>", recipient), 250).await?;
+    }
+    command(&mut stream, "DATA", 354).await?;
+
+    // Lines starting with a dot would end the message early, so escape them
+    let body = body
+        .lines()
+        .map(|line| match line.starts_with('.') {
+            true => format!(".{}", line),
+            false => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: [homelabd] {}\r\n\r\n{}\r\n.",
+        from,
+        to.join(", "),
+        body.lines().next().unwrap_or_default(),
+        body
+    );
+    command(&mut stream, &message, 250).await?;
+    command(&mut stream, "QUIT", 221).await
+}
+
+async fn command(
+    stream: &mut BufReader<TcpStream>,
+    line: &str,
+    expected: u16,
+) -> Result<(), String> {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    expect_reply(stream, expected).await
+}
+
+/// Reads a possibly multi-line SMTP reply and checks its code.
+async fn expect_reply(stream: &mut BufReader<TcpStream>, expected: u16) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        if stream
+            .read_line(&mut line)
+            .await
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            return Err("SMTP server closed the connection".to_string());
+        }
+
+        let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+        if code != Some(expected) {
+            return Err(format!("Unexpected SMTP reply: {}", line.trim()));
+        }
+        // "250-" continues a multi-line reply, "250 " ends it
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// Delivers cluster events to the configured sinks.
+pub struct Notifier {
+    sinks: Vec<Arc<Sink>>,
+    dedup_window: Duration,
+    events: Arc<EventBus>,
+    /// Events are only sent while this node leads, if set
+    election: Option<Arc<Election>>,
+}
+
+impl Notifier {
+    pub fn new(notifications: NotificationConfig, events: Arc<EventBus>) -> Result<Self, String> {
+        // Sinks are outside the cluster: they're checked against the public roots, and never
+        // see this node's client certificate
+        let client = crate::tls::public_client()?;
+        Ok(Self {
+            sinks: notifications
+                .sinks
+                .into_iter()
+                .map(|config| {
+                    Arc::new(Sink {
+                        config,
+                        client: client.clone(),
+                    })
+                })
+                .collect(),
+            dedup_window: Duration::from_secs(notifications.dedup_window_seconds),
+            events,
+            election: None,
+        })
+    }
+
+    /// Sends events only while this node holds the lease, so a cluster notifies once.
+    pub fn leader_only(mut self, election: Arc<Election>) -> Self {
+        self.election = Some(election);
+        self
+    }
+
+    pub async fn start(self) {
+        log::info!("Sending notifications to {} sinks", self.sinks.len());
+
+        let mut receiver = self.events.subscribe();
+        let mut last_sent = HashMap::<(usize, String), Instant>::new();
+
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Notifier fell behind and missed {} events", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+
+            if let Some(election) = &self.election
+                && election.leadership().is_none()
+            {
+                log::debug!("Not sending {} notification: not the leader", event.name());
+                continue;
+            }
+
+            let now = Instant::now();
+            last_sent.retain(|_, sent| now.duration_since(*sent) < self.dedup_window);
+
+            for (index, sink) in self.sinks.iter().enumerate() {
+                if !sink.wants(&event) {
+                    continue;
+                }
+
+                let key = (index, event.dedup_key());
+                if last_sent.contains_key(&key) {
+                    log::debug!("Suppressing duplicate {} notification", event.name());
+                    continue;
+                }
+                last_sent.insert(key, now);
+
+                let sink = Arc::clone(sink);
+                let event = event.clone();
+                tokio::spawn(async move { sink.deliver_with_retries(&event).await });
+            }
+        }
+    }
+
+    /// Sends a sample event to every sink once, without retries, reporting how each went.
+    pub async fn test(&self) -> Vec<(&'static str, Result<(), String>)> {
+        let event = Event::HostLeft {
+            node_id: "00000000-0000-0000-0000-000000000000".to_string(),
+            hostname: "homelabd-test".to_string(),
+        };
+
+        let mut results = Vec::new();
+        for sink in &self.sinks {
+            results.push((sink.config.kind.name(), sink.deliver(&event).await));
+        }
+        results
+    }
+}
+
+/// Sends a sample notification through every configured sink, for checking the config.
+pub async fn run_test(config: &Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = config
+        .notifications
+        .as_ref()
+        .ok_or("No notifications file given with --notifications")?;
+    let notifications = NotificationConfig::load(path)
+        .map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+
+    let notifier = Notifier::new(notifications, Arc::new(EventBus::new()))?;
+    let mut failed = 0;
+    for (index, (sink, result)) in notifier.test().await.into_iter().enumerate() {
+        match result {
+            Ok(()) => println!("Sink {} ({}): sent", index, sink),
+            Err(e) => {
+                failed += 1;
+                println!("Sink {} ({}): failed: {}", index, sink, e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} sinks failed", failed).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// A webhook endpoint on localhost that fails the first `failures` requests. Returns its URL
+    /// and the bodies of the requests it receives.
+    async fn listen(failures: usize) -> (String, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let failures = Arc::new(AtomicUsize::new(failures));
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let sender = sender.clone();
+                let failures = Arc::clone(&failures);
+                let service = service_fn(move |request: Request<hyper::body::Incoming>| {
+                    let sender = sender.clone();
+                    let failures = Arc::clone(&failures);
+                    async move {
+                        let body = request.into_body().collect().await?.to_bytes();
+                        let _ = sender.send(serde_json::from_slice(&body).unwrap());
+                        let failed = failures
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                            .is_ok();
+                        let status = match failed {
+                            true => StatusCode::INTERNAL_SERVER_ERROR,
+                            false => StatusCode::OK,
+                        };
+                        let mut response = Response::new(Full::new(Bytes::new()));
+                        *response.status_mut() = status;
+                        Ok::<_, hyper::Error>(response)
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (url, receiver)
+    }
+
+    /// Starts a notifier with one webhook sink, returning the bus to publish events on.
+    async fn notifier(sink: serde_json::Value) -> Arc<EventBus> {
+        let notifications = serde_json::from_value::<NotificationConfig>(serde_json::json!({
+            "sinks": [sink],
+        }))
+        .unwrap();
+        let events = Arc::new(EventBus::new());
+        let notifier = Notifier::new(notifications, Arc::clone(&events)).unwrap();
+
+        tokio::spawn(notifier.start());
+        // Let it subscribe before anything is published
+        tokio::task::yield_now().await;
+        events
+    }
+
+    fn host_left(node_id: &str) -> Event {
+        Event::HostLeft {
+            node_id: node_id.to_string(),
+            hostname: format!("host-{}", node_id),
+        }
+    }
+
+    async fn next(received: &mut mpsc::UnboundedReceiver<serde_json::Value>) -> serde_json::Value {
+        tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .expect("no notification arrived")
+            .unwrap()
+    }
+
+    async fn assert_quiet(received: &mut mpsc::UnboundedReceiver<serde_json::Value>) {
+        let extra = tokio::time::timeout(Duration::from_millis(300), received.recv()).await;
+        assert!(extra.is_err(), "unexpected notification: {:?}", extra);
+    }
+
+    #[tokio::test]
+    async fn sends_wanted_events_once_per_dedup_window() {
+        let (url, mut received) = listen(0).await;
+        let events = notifier(serde_json::json!({
+            "type": "webhook",
+            "url": url,
+            "events": ["host_left"],
+        }))
+        .await;
+
+        events.publish(Event::HostJoined {
+            node_id: "a".to_string(),
+            hostname: "host-a".to_string(),
+            primary_ip: "10.0.0.1".to_string(),
+            version: "0.1.0".to_string(),
+        });
+        events.publish(host_left("a"));
+        events.publish(host_left("a"));
+        events.publish(host_left("b"));
+
+        let mut sent = [next(&mut received).await, next(&mut received).await];
+        sent.sort_by_key(|body| body["node_id"].as_str().unwrap_or_default().to_string());
+        assert_eq!(sent[0]["type"], "host_left");
+        assert_eq!(sent[0]["node_id"], "a");
+        assert_eq!(sent[0]["summary"], "Host host-a disappeared");
+        assert_eq!(sent[1]["node_id"], "b");
+        assert_quiet(&mut received).await;
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries() {
+        let (url, mut received) = listen(1).await;
+        let events = notifier(serde_json::json!({ "type": "webhook", "url": url })).await;
+
+        events.publish(host_left("a"));
+
+        assert_eq!(next(&mut received).await["node_id"], "a");
+        assert_eq!(next(&mut received).await["node_id"], "a");
+        assert_quiet(&mut received).await;
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let (url, mut received) = listen(usize::MAX).await;
+        let events = notifier(serde_json::json!({
+            "type": "webhook",
+            "url": url,
+            "max_retries": 1,
+        }))
+        .await;
+
+        events.publish(host_left("a"));
+
+        next(&mut received).await;
+        next(&mut received).await;
+        tokio::time::sleep(RETRY_BACKOFF.0).await;
+        assert_quiet(&mut received).await;
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::Config;
+use crate::resolve::resolve_or_fallback;
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Decides which of a host's announced addresses peers should use to reach it.
+///
+/// In order of precedence: the address the host announced as its primary, addresses in the
+/// preferred networks, then anything else that isn't excluded. Within each of those, addresses
+/// on physical interfaces beat those on bridges, veth pairs and other virtual interfaces. A
+/// forward DNS lookup of the hostname picks between equally good candidates when enabled.
+#[derive(Clone)]
+pub struct PrimaryIpPolicy {
+    preferred: Vec<IpNet>,
+    excluded: Vec<IpNet>,
+    confirm_dns: bool,
+}
+
+impl PrimaryIpPolicy {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            preferred: config.preferred_cidrs.clone(),
+            excluded: config.excluded_cidrs.clone(),
+            confirm_dns: config.confirm_primary_ip_dns,
+        }
+    }
+
+    /// Picks a primary IP from `ips`, where `virtual_ips` are those the host reported on virtual
+    /// interfaces. This may do a blocking DNS lookup.
+    pub fn select(
+        &self,
+        hostname: &str,
+        announced: Option<IpAddr>,
+        ips: &[IpAddr],
+        virtual_ips: &[IpAddr],
+    ) -> Option<IpAddr> {
+        let routable = ips
+            .iter()
+            .copied()
+            .filter(|ip| !ip.is_loopback())
+            .collect::<Vec<_>>();
+
+        // The host knows its own network best, but only trust it with an address it actually has
+        if let Some(announced) = announced {
+            if routable.contains(&announced) {
+                return Some(announced);
+            }
+            log::warn!(
+                "Host {} announced primary IP {} which is not one of its addresses",
+                hostname,
+                announced
+            );
+        }
+
+        let mut candidates = routable
+            .iter()
+            .copied()
+            .filter(|ip| !self.excluded.iter().any(|net| net.contains(ip)))
+            .collect::<Vec<_>>();
+
+        // An excluded address is still better than no address at all
+        if candidates.is_empty() {
+            candidates = routable;
+        }
+
+        // Stable sort keeps announcement order within each preference tier
+        candidates.sort_by_key(|ip| self.rank(ip, virtual_ips));
+
+        if self.confirm_dns {
+            let best_rank = candidates.first().map(|ip| self.rank(ip, virtual_ips))?;
+            let best = candidates
+                .iter()
+                .copied()
+                .take_while(|ip| self.rank(ip, virtual_ips) == best_rank)
+                .collect::<Vec<_>>();
+
+            match resolve_or_fallback(hostname, &best) {
+                Ok(ip) => return Some(ip),
+                Err(e) => log::debug!("Could not confirm primary IP for {}: {}", hostname, e),
+            }
+        }
+
+        candidates.first().copied()
+    }
+
+    /// Index of the first preferred network containing the address, then whether it's on a
+    /// virtual interface. Lower is better.
+    fn rank(&self, ip: &IpAddr, virtual_ips: &[IpAddr]) -> (usize, bool) {
+        let tier = self
+            .preferred
+            .iter()
+            .position(|net| net.contains(ip))
+            .unwrap_or(self.preferred.len());
+
+        (tier, virtual_ips.contains(ip))
+    }
+}
@@ -0,0 +1,250 @@
+use crate::metrics;
+use crate::proto::homelabd::{Envelope, Header, envelope};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever a change to the wire format needs peers to behave differently.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Features a node can advertise in its message headers. Peers that don't set a bit should be
+/// assumed not to support the feature.
+///
+/// Fragmentation is all-or-nothing, since every message is broadcast: while any known peer
+/// lacks `FRAGMENTATION`, oversized messages are sent whole and left to IP fragmentation, as
+/// before fragments existed.
+pub mod capability {
+    /// Can reassemble messages split into `Fragment`s
+    pub const FRAGMENTATION: u64 = 1 << 0;
+    /// Will relay Wake-on-LAN requests onto its local networks
+    pub const WAKE_ON_LAN: u64 = 1 << 1;
+    /// Takes part in leader election
+    pub const LEADER_ELECTION: u64 = 1 << 2;
+    /// Acts on `UpdateRequest`s, so it can be included in rollouts
+    pub const SELF_UPDATE: u64 = 1 << 3;
+
+    pub const ALL: &[(u64, &str)] = &[
+        (FRAGMENTATION, "fragmentation"),
+        (WAKE_ON_LAN, "wake-on-lan"),
+        (LEADER_ELECTION, "leader-election"),
+        (SELF_UPDATE, "self-update"),
+    ];
+}
+
+/// Everything this build supports.
+pub const CAPABILITIES: u64 = capability::FRAGMENTATION
+    | capability::WAKE_ON_LAN
+    | capability::LEADER_ELECTION
+    | capability::SELF_UPDATE;
+
+/// Wraps a message in an envelope with the sending node's header.
+pub fn envelope(node_id: &str, boot: u64, sequence: u64, msg: envelope::Msg) -> Envelope {
+    Envelope {
+        header: Some(Header {
+            protocol_version: PROTOCOL_VERSION,
+            node_id: node_id.to_string(),
+            sequence,
+            capabilities: CAPABILITIES,
+            boot,
+        }),
+        msg: Some(msg),
+    }
+}
+
+/// A boot number from the clock, for when the state directory can't keep one.
+pub fn clock_boot() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// The key a message's sender should be tracked under: its node ID, or the given fallback (its
+/// announced hostname) for peers that predate node IDs.
+pub fn sender_id(env: &Envelope, fallback: &str) -> String {
+    match &env.header {
+        Some(header) if !header.node_id.is_empty() => header.node_id.clone(),
+        _ => fallback.to_string(),
+    }
+}
+
+/// Names of the capabilities set in the given bitmap, for logging.
+pub fn capability_names(capabilities: u64) -> Vec<&'static str> {
+    capability::ALL
+        .iter()
+        .filter(|(bit, _)| capabilities & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+// Senders tracked for sequence checks; past this (a node ID churn storm, or forged headers)
+// tracking starts over rather than growing without bound
+const MAX_TRACKED_SENDERS: usize = 1024;
+
+// How far behind a sender's newest message an older one may arrive and still be accepted
+const SEQUENCE_WINDOW: u64 = 64;
+
+/// The recent sequence numbers seen from one sender in its current boot.
+struct Window {
+    boot: u64,
+    newest: u64,
+    /// Bit `n` is set once `newest - n` has been seen
+    seen: u64,
+}
+
+impl Window {
+    /// Everything up to `sequence` counts as seen, so what came before we started listening
+    /// isn't reported as missed.
+    fn starting_at(boot: u64, sequence: u64) -> Self {
+        Self {
+            boot,
+            newest: sequence,
+            seen: u64::MAX,
+        }
+    }
+}
+
+/// Follows each sender's header sequence numbers to drop duplicate deliveries (the same
+/// datagram arriving on several interfaces) and replays, and to count messages lost in between.
+///
+/// Messages are accepted once each, up to `SEQUENCE_WINDOW` behind the newest from that boot of
+/// the sender. A higher boot means the sender restarted; anything from an earlier boot is stale.
+/// Peers that don't send a boot are assumed to have restarted when their sequence goes back.
+#[derive(Default)]
+pub struct SequenceTracker {
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl SequenceTracker {
+    /// Records a message's boot and sequence number, returning false if it's a duplicate or
+    /// stale message to drop.
+    pub fn check(&self, node_id: &str, boot: u64, sequence: u64) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= MAX_TRACKED_SENDERS && !windows.contains_key(node_id) {
+            windows.clear();
+        }
+
+        let Some(window) = windows.get_mut(node_id) else {
+            windows.insert(node_id.to_string(), Window::starting_at(boot, sequence));
+            return true;
+        };
+        let restarted = boot > window.boot
+            || (boot == 0
+                && window.boot == 0
+                && window.newest.saturating_sub(sequence) >= SEQUENCE_WINDOW);
+        if restarted {
+            missed(SEQUENCE_WINDOW - u64::from(window.seen.count_ones()));
+            *window = Window::starting_at(boot, sequence);
+            return true;
+        }
+        if boot < window.boot {
+            metrics::MESSAGES_DUPLICATE.inc();
+            return false;
+        }
+
+        if sequence > window.newest {
+            let shift = sequence - window.newest;
+            if shift >= SEQUENCE_WINDOW {
+                missed(SEQUENCE_WINDOW - u64::from(window.seen.count_ones()));
+                missed(shift - SEQUENCE_WINDOW);
+                window.seen = 1;
+            } else {
+                let leaving = window.seen >> (SEQUENCE_WINDOW - shift);
+                missed(shift - u64::from(leaving.count_ones()));
+                window.seen = (window.seen << shift) | 1;
+            }
+            window.newest = sequence;
+            return true;
+        }
+
+        let age = window.newest - sequence;
+        if age >= SEQUENCE_WINDOW || window.seen & (1 << age) != 0 {
+            metrics::MESSAGES_DUPLICATE.inc();
+            return false;
+        }
+        window.seen |= 1 << age;
+        true
+    }
+}
+
+/// Counts sequence numbers that left a sender's window without ever arriving.
+fn missed(count: u64) {
+    if count > 0 {
+        metrics::MESSAGES_MISSED.inc_by(count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(tracker: &SequenceTracker, boot: u64, sequences: &[u64]) -> Vec<bool> {
+        sequences
+            .iter()
+            .map(|&sequence| tracker.check("node", boot, sequence))
+            .collect()
+    }
+
+    #[test]
+    fn drops_interleaved_duplicates() {
+        let tracker = SequenceTracker::default();
+        assert_eq!(
+            accepted(&tracker, 1, &[5, 6, 5, 6, 7, 7]),
+            [true, true, false, false, true, false]
+        );
+    }
+
+    #[test]
+    fn accepts_late_messages_once_within_the_window() {
+        let tracker = SequenceTracker::default();
+        assert_eq!(
+            accepted(&tracker, 1, &[10, 13, 11, 12, 11, 10]),
+            [true, true, true, true, false, false]
+        );
+        assert!(tracker.check("node", 1, 14 + SEQUENCE_WINDOW));
+        assert!(!tracker.check("node", 1, 14));
+    }
+
+    #[test]
+    fn replays_are_stale_until_the_sender_restarts() {
+        let tracker = SequenceTracker::default();
+        assert_eq!(accepted(&tracker, 1, &[100, 101]), [true, true]);
+
+        // A replay from far back in this boot, or from an earlier one
+        assert!(!tracker.check("node", 1, 0));
+        assert!(!tracker.check("node", 0, 500));
+
+        assert_eq!(accepted(&tracker, 2, &[0, 1, 0]), [true, true, false]);
+        assert!(!tracker.check("node", 1, 102));
+    }
+
+    #[test]
+    fn senders_without_a_boot_restart_when_their_sequence_goes_back() {
+        let tracker = SequenceTracker::default();
+        assert_eq!(
+            accepted(&tracker, 0, &[100, 99, 100, 0, 1]),
+            [true, false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn survives_the_largest_sequence() {
+        let tracker = SequenceTracker::default();
+        assert_eq!(
+            accepted(&tracker, 1, &[0, u64::MAX, u64::MAX, u64::MAX - 1]),
+            [true, true, false, true]
+        );
+
+        let tracker = SequenceTracker::default();
+        assert_eq!(accepted(&tracker, 0, &[u64::MAX, 0]), [true, true]);
+    }
+
+    #[test]
+    fn tracks_senders_separately() {
+        let tracker = SequenceTracker::default();
+        assert!(tracker.check("a", 1, 5));
+        assert!(tracker.check("b", 1, 5));
+        assert!(!tracker.check("a", 1, 5));
+    }
+}
@@ -1,24 +1,123 @@
+use crate::clock::{Clock, SystemClock};
 use crate::config::Config;
-use crate::proto::homelabd::Envelope;
-use crate::{dispatch::Dispatchable, scheduler::Schedulable};
+use crate::dispatch::{Dispatchable, MessageContext, MessageType};
+use crate::events::{Event, EventBus};
+use crate::interfaces;
+use crate::metrics;
+use crate::primary_ip::PrimaryIpPolicy;
+use crate::proto::homelabd::{CachedBinary, Envelope, NetworkInterface};
+use crate::protocol;
+use crate::scheduler::Schedulable;
 use dns_lookup::lookup_addr;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
-use std::os::unix::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time;
 
 pub struct Host {
     pub name: String,
+    /// Hostname the node announced for itself, which may differ from `name` after reverse DNS
+    pub hostname: String,
     pub ip: Vec<String>,
     pub primaryip: IpAddr,
     pub uptime: i64,
     pub version: String,
+    /// Address the host's last announcement was actually sent from
+    pub source_ip: IpAddr,
+    /// Set when the host announced addresses that don't include the one it sent from
+    pub address_mismatch: bool,
+    /// Persistent identity from the message header, or the hostname for peers that predate it
+    pub node_id: String,
+    pub protocol_version: u32,
+    pub capabilities: u64,
+    pub interfaces: Vec<NetworkInterface>,
+    /// Versions the host rolled back from after they failed to run healthily
+    pub rolled_back_versions: Vec<String>,
+    /// SHA-256 of the host's executable, empty for hosts that predate it
+    pub build_hash: String,
+    /// Target triple the host was built for, empty for hosts that predate it
+    pub target: String,
+    /// Builds for other targets the host can serve
+    pub cached_binaries: Vec<CachedBinary>,
+}
+
+/// A service a host announced, such as a Prometheus exporter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Service {
+    pub name: String,
+    pub port: u16,
+}
+
+impl Host {
+    /// Whether the host advertised the given `protocol::capability`.
+    pub fn supports(&self, capability: u64) -> bool {
+        self.capabilities & capability != 0
+    }
+}
+
+#[cfg(test)]
+impl Host {
+    /// A host announcing only its name and address, for tests to fill in the rest.
+    pub fn announced(node_id: &str, hostname: &str, primaryip: IpAddr) -> Self {
+        Self {
+            name: hostname.to_string(),
+            hostname: hostname.to_string(),
+            ip: vec![primaryip.to_string()],
+            primaryip,
+            uptime: 0,
+            version: String::new(),
+            source_ip: primaryip,
+            address_mismatch: false,
+            node_id: node_id.to_string(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+            capabilities: 0,
+            interfaces: Vec::new(),
+            rolled_back_versions: Vec::new(),
+            build_hash: String::new(),
+            target: String::new(),
+            cached_binaries: Vec::new(),
+        }
+    }
+}
+
+/// An interface that can be woken, remembered independently of the host's announcements.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KnownInterface {
+    pub name: String,
+    pub mac: String,
+    pub networks: Vec<IpNet>,
+}
+
+impl KnownInterface {
+    fn from_announced(iface: &NetworkInterface) -> Self {
+        Self {
+            name: iface.name.clone(),
+            mac: iface.mac.clone(),
+            networks: interfaces::networks(iface),
+        }
+    }
+}
+
+/// What's kept about an evicted host so it can still be woken after it's been off for days.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DepartedHost {
+    node_id: String,
+    hostname: String,
+    interfaces: Vec<KnownInterface>,
+    /// Seconds since the Unix epoch
+    last_seen: u64,
 }
 
 struct HostEntry {
     key: String,
     host: Arc<Host>,
     last_seen: time::SystemTime,
+    /// Every hostname this node has announced, oldest first
+    hostnames: Vec<String>,
+    services: Vec<Service>,
 }
 
 struct Database {
@@ -28,11 +127,29 @@ struct Database {
 
 pub struct HostDatabase {
     db: Mutex<Database>,
+    primary_ip_policy: PrimaryIpPolicy,
+    departed: Mutex<HashMap<String, DepartedHost>>,
+    departed_path: PathBuf,
+    clock: Arc<dyn Clock>,
+    /// Whether to name hosts by reverse DNS of their primary IP
+    reverse_dns: bool,
+    events: Arc<EventBus>,
+    /// Per-peer series last exported, as (hostname, node ID, version), so those for departed
+    /// hosts can be removed without clearing everything first
+    exported: Mutex<HashSet<(String, String, String)>>,
 }
 
 // Maximum age for a host without a recent broadcast before it's considered stale and evicted
 const MAX_HOST_AGE: time::Duration = time::Duration::from_secs(5 * 60);
 
+// How long to remember the MACs of a host that has stopped announcing itself
+const MAX_DEPARTED_AGE: time::Duration = time::Duration::from_secs(90 * 24 * 60 * 60);
+
+const DEPARTED_HOSTS_FILE: &str = "departed_hosts.json";
+
+// Number of previous hostnames to remember for each node
+const MAX_HOSTNAME_HISTORY: usize = 8;
+
 impl Database {
     pub fn new() -> Self {
         Self {
@@ -45,22 +162,10 @@ impl Database {
         &self.hosts
     }
 
-    pub fn hosts_mut(&mut self) -> &mut Vec<HostEntry> {
-        &mut self.hosts
-    }
-
     pub fn host_lookup(&self) -> &std::collections::HashMap<String, usize> {
         &self.host_lookup
     }
 
-    pub fn host_lookup_mut(&mut self) -> &mut std::collections::HashMap<String, usize> {
-        &mut self.host_lookup
-    }
-
-    pub fn pair(&self) -> (&Vec<HostEntry>, &std::collections::HashMap<String, usize>) {
-        (&self.hosts, &self.host_lookup)
-    }
-
     pub fn pair_mut(
         &mut self,
     ) -> (
@@ -72,47 +177,268 @@ impl Database {
 }
 
 impl HostDatabase {
-    pub fn new(_config: &Config) -> Self {
+    pub fn new(config: &Config, events: Arc<EventBus>) -> Self {
+        Self::with_clock(config, events, Arc::new(SystemClock), true)
+    }
+
+    /// Builds a database that ages hosts out by `clock`, for replaying captured traffic.
+    /// Replays turn off `reverse_dns` so results don't depend on the local resolver.
+    pub fn with_clock(
+        config: &Config,
+        events: Arc<EventBus>,
+        clock: Arc<dyn Clock>,
+        reverse_dns: bool,
+    ) -> Self {
+        let departed_path = config.state_dir.join(DEPARTED_HOSTS_FILE);
+        let departed = match std::fs::read(&departed_path) {
+            Ok(contents) => {
+                serde_json::from_slice::<Vec<DepartedHost>>(&contents).unwrap_or_else(|e| {
+                    log::warn!("Ignoring invalid {}: {}", departed_path.display(), e);
+                    Vec::new()
+                })
+            }
+            Err(_) => Vec::new(),
+        };
+
         Self {
             db: Mutex::new(Database::new()),
+            primary_ip_policy: PrimaryIpPolicy::new(config),
+            departed: Mutex::new(
+                departed
+                    .into_iter()
+                    .map(|host| (host.node_id.clone(), host))
+                    .collect(),
+            ),
+            departed_path,
+            clock,
+            reverse_dns,
+            events,
+            exported: Mutex::new(HashSet::new()),
         }
     }
 
-    pub fn host_seen(&self, hostname: &str, host: Host) {
-        let entry = HostEntry {
-            key: hostname.to_string(),
+    pub fn host_seen(&self, host: Host, seen_at: time::SystemTime) {
+        let key = host.node_id.clone();
+        let hostname = host.hostname.clone();
+
+        self.departed.lock().unwrap().remove(&key);
+
+        let mut entry = HostEntry {
+            key: key.clone(),
             host: Arc::new(host),
-            last_seen: time::SystemTime::now(),
+            last_seen: seen_at,
+            hostnames: vec![hostname.clone()],
+            services: Vec::new(),
         };
 
         let mut db = self.db.lock().unwrap();
 
         let (hosts, hosts_lookup) = db.pair_mut();
 
-        if let Some(index) = hosts_lookup.get(hostname) {
-            log::info!("Updating host entry for {}", hostname);
+        if let Some(index) = hosts_lookup.get(&key) {
+            log::info!("Updating host entry for {} ({})", hostname, key);
+
+            let mut hostnames = std::mem::take(&mut hosts[*index].hostnames);
+            if hostnames.last() != Some(&hostname) {
+                log::info!(
+                    "Host {} renamed from {} to {}",
+                    key,
+                    hostnames.last().map(String::as_str).unwrap_or_default(),
+                    hostname
+                );
+                hostnames.push(hostname.clone());
+                if hostnames.len() > MAX_HOSTNAME_HISTORY {
+                    hostnames.remove(0);
+                }
+            }
+            entry.hostnames = hostnames;
+            entry.services = std::mem::take(&mut hosts[*index].services);
+
+            let (old, new) = (&hosts[*index].host, &entry.host);
+            if old.version != new.version {
+                self.events.publish(Event::VersionChanged {
+                    node_id: key.clone(),
+                    hostname: hostname.clone(),
+                    from: old.version.clone(),
+                    to: new.version.clone(),
+                });
+            }
+            for version in &new.rolled_back_versions {
+                if !old.rolled_back_versions.contains(version) {
+                    self.events.publish(Event::RolledBack {
+                        node_id: key.clone(),
+                        hostname: hostname.clone(),
+                        from: version.clone(),
+                        to: new.version.clone(),
+                    });
+                }
+            }
+            if old.hostname != new.hostname || old.primaryip != new.primaryip || old.ip != new.ip {
+                self.events.publish(Event::HostUpdated {
+                    node_id: key.clone(),
+                    hostname: hostname.clone(),
+                    primary_ip: new.primaryip.to_string(),
+                });
+            }
+
             hosts[*index] = entry;
         } else {
-            log::info!("Adding new host entry for {}", hostname);
+            log::info!(
+                "Adding new host entry for {} ({}, protocol {})",
+                hostname,
+                key,
+                entry.host.protocol_version
+            );
+            for iface in &entry.host.interfaces {
+                log::info!(
+                    "Host {} has {} ({}) on {:?}",
+                    hostname,
+                    iface.name,
+                    if iface.mac.is_empty() {
+                        "no MAC"
+                    } else {
+                        &iface.mac
+                    },
+                    interfaces::networks(iface)
+                );
+            }
+            let missing =
+                protocol::capability_names(protocol::CAPABILITIES & !entry.host.capabilities);
+            if !missing.is_empty() {
+                log::info!("Host {} does not support: {}", hostname, missing.join(", "));
+            }
+
+            if let Some(other) = hosts.iter().find(|other| other.host.hostname == hostname) {
+                metrics::HOSTNAME_COLLISIONS
+                    .with_label_values(&[&hostname])
+                    .inc();
+                log::warn!(
+                    "Hostname collision: {} is announced by both {} and {}",
+                    hostname,
+                    other.key,
+                    key
+                );
+            }
+
+            self.events.publish(Event::HostJoined {
+                node_id: key.clone(),
+                hostname: hostname.clone(),
+                primary_ip: entry.host.primaryip.to_string(),
+                version: entry.host.version.clone(),
+            });
+
             hosts.push(entry);
-            hosts_lookup.insert(hostname.to_string(), hosts.len() - 1);
+            hosts_lookup.insert(key, hosts.len() - 1);
         }
     }
 
-    pub fn get_host(&self, name: &str) -> Option<Arc<Host>> {
+    pub fn get_host(&self, node_id: &str) -> Option<Arc<Host>> {
         let db = self.db.lock().unwrap();
 
-        let index = db.host_lookup().get(name)?;
+        let index = db.host_lookup().get(node_id)?;
         let entry = db.hosts().get(*index)?;
 
         Some(Arc::clone(&entry.host))
     }
 
-    pub fn last_seen(&self, name: &str) -> Option<time::SystemTime> {
+    /// Interfaces with a known MAC for the named host, looked up by node ID or hostname among
+    /// live hosts first and then those that have been evicted. Returns the host's hostname too.
+    pub fn known_interfaces(&self, name: &str) -> Option<(String, Vec<KnownInterface>)> {
+        let live = self
+            .hosts()
+            .into_iter()
+            .find(|host| host.node_id == name || host.hostname == name || host.name == name)
+            .map(|host| {
+                let interfaces = host
+                    .interfaces
+                    .iter()
+                    .filter(|iface| !iface.mac.is_empty())
+                    .map(KnownInterface::from_announced)
+                    .collect::<Vec<_>>();
+                (host.hostname.clone(), interfaces)
+            });
+        if live.is_some() {
+            return live;
+        }
+
+        self.departed
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|host| host.node_id == name || host.hostname == name)
+            .max_by_key(|host| host.last_seen)
+            .map(|host| (host.hostname.clone(), host.interfaces.clone()))
+    }
+
+    /// Replaces the services announced by a known host. Returns false if the host is unknown.
+    pub fn services_seen(&self, node_id: &str, services: Vec<Service>) -> bool {
+        let mut db = self.db.lock().unwrap();
+
+        let (hosts, hosts_lookup) = db.pair_mut();
+        let Some(index) = hosts_lookup.get(node_id) else {
+            return false;
+        };
+
+        let entry = &mut hosts[*index];
+        for service in entry.services.iter().filter(|s| !services.contains(s)) {
+            self.events.publish(Event::ExporterRemoved {
+                node_id: entry.key.clone(),
+                hostname: entry.host.hostname.clone(),
+                job: service.name.clone(),
+                port: service.port,
+            });
+        }
+        for service in services.iter().filter(|s| !entry.services.contains(s)) {
+            self.events.publish(Event::ExporterAdded {
+                node_id: entry.key.clone(),
+                hostname: entry.host.hostname.clone(),
+                job: service.name.clone(),
+                port: service.port,
+            });
+        }
+
+        entry.services = services;
+        true
+    }
+
+    /// Every service announced by a live host.
+    pub fn services(&self) -> Vec<(Arc<Host>, Service)> {
+        let db = self.db.lock().unwrap();
+        db.hosts()
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .services
+                    .iter()
+                    .map(|service| (Arc::clone(&entry.host), service.clone()))
+            })
+            .collect()
+    }
+
+    /// Hostnames announced by more than one live node, with the IDs of the nodes using each.
+    pub fn hostname_collisions(&self) -> Vec<(String, Vec<String>)> {
+        let db = self.db.lock().unwrap();
+
+        let mut by_hostname = std::collections::BTreeMap::<&str, Vec<String>>::new();
+        for entry in db.hosts() {
+            by_hostname
+                .entry(&entry.host.hostname)
+                .or_default()
+                .push(entry.key.clone());
+        }
+
+        by_hostname
+            .into_iter()
+            .filter(|(_, node_ids)| node_ids.len() > 1)
+            .map(|(hostname, node_ids)| (hostname.to_string(), node_ids))
+            .collect()
+    }
+
+    pub fn last_seen(&self, node_id: &str) -> Option<time::SystemTime> {
         let db = self.db.lock().unwrap();
 
         db.host_lookup()
-            .get(name)
+            .get(node_id)
             .and_then(|&index| db.hosts().get(index))
             .map(|entry| entry.last_seen)
     }
@@ -125,8 +451,69 @@ impl HostDatabase {
             .collect()
     }
 
+    /// Refreshes the cluster and per-peer gauges. Ages change with time rather than with
+    /// messages, so this is called when metrics are scraped.
+    pub fn update_metrics(&self) {
+        let now = self.clock.now();
+        let db = self.db.lock().unwrap();
+
+        // Scrapes can happen at any time, so rather than clearing the gauges and filling them
+        // again, set every current series and then remove only those that have gone
+        let mut exported = self.exported.lock().unwrap();
+        let mut current = HashSet::new();
+        for entry in db.hosts() {
+            let host = &entry.host;
+            let since = now.duration_since(entry.last_seen).unwrap_or_default();
+            let labels = [host.hostname.as_str(), host.node_id.as_str()];
+
+            metrics::PEER_LAST_SEEN
+                .with_label_values(&labels)
+                .set(since.as_secs_f64());
+            // Uptime was reported when the host last announced itself
+            metrics::PEER_UPTIME
+                .with_label_values(&labels)
+                .set(host.uptime as f64 + since.as_secs_f64());
+            metrics::PEER_VERSION
+                .with_label_values(&[labels[0], labels[1], &host.version])
+                .set(1);
+            current.insert((
+                host.hostname.clone(),
+                host.node_id.clone(),
+                host.version.clone(),
+            ));
+        }
+
+        for (hostname, node_id, version) in exported.difference(&current) {
+            let _ = metrics::PEER_VERSION.remove_label_values(&[hostname, node_id, version]);
+            if !current
+                .iter()
+                .any(|(h, n, _)| h == hostname && n == node_id)
+            {
+                let _ = metrics::PEER_LAST_SEEN.remove_label_values(&[hostname, node_id]);
+                let _ = metrics::PEER_UPTIME.remove_label_values(&[hostname, node_id]);
+            }
+        }
+        *exported = current;
+
+        metrics::KNOWN_HOSTS.set(db.hosts().len() as i64);
+        metrics::KNOWN_EXPORTERS.set(
+            db.hosts()
+                .iter()
+                .map(|entry| entry.services.len() as i64)
+                .sum(),
+        );
+
+        let oldest = db
+            .hosts()
+            .iter()
+            .map(|entry| now.duration_since(entry.last_seen).unwrap_or_default())
+            .max()
+            .unwrap_or_default();
+        metrics::OLDEST_HOST_AGE.set(oldest.as_secs_f64());
+    }
+
     fn evict_old_hosts(&self, max_age: time::Duration) {
-        let now = time::SystemTime::now();
+        let now = self.clock.now();
         let mut db = self.db.lock().unwrap();
         log::info!("Evicting hosts older than {:?}", max_age);
 
@@ -148,10 +535,35 @@ impl HostDatabase {
             }
         }
 
+        let mut departed = Vec::new();
         hosts.retain(|entry| {
-            now.duration_since(entry.last_seen)
+            let keep = now
+                .duration_since(entry.last_seen)
                 .unwrap_or(time::Duration::ZERO)
-                < max_age
+                < max_age;
+            if !keep {
+                self.events.publish(Event::HostLeft {
+                    node_id: entry.key.clone(),
+                    hostname: entry.host.hostname.clone(),
+                });
+                departed.push(DepartedHost {
+                    node_id: entry.key.clone(),
+                    hostname: entry.host.hostname.clone(),
+                    interfaces: entry
+                        .host
+                        .interfaces
+                        .iter()
+                        .filter(|iface| !iface.mac.is_empty())
+                        .map(KnownInterface::from_announced)
+                        .collect(),
+                    last_seen: entry
+                        .last_seen
+                        .duration_since(time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                });
+            }
+            keep
         });
 
         let num_evicted = initial_hosts - hosts.len();
@@ -165,6 +577,44 @@ impl HostDatabase {
             log::info!("Rebuilding lookup for host: {}", entry.key);
             hosts_lookup.insert(entry.key.clone(), index);
         }
+        drop(db);
+
+        self.remember_departed(departed, now);
+    }
+
+    fn remember_departed(&self, evicted: Vec<DepartedHost>, now: time::SystemTime) {
+        let mut departed = self.departed.lock().unwrap();
+        let initial = departed.len();
+
+        let oldest = now
+            .checked_sub(MAX_DEPARTED_AGE)
+            .and_then(|t| t.duration_since(time::UNIX_EPOCH).ok())
+            .unwrap_or_default()
+            .as_secs();
+        departed.retain(|_, host| host.last_seen >= oldest);
+
+        let changed = !evicted.is_empty() || departed.len() != initial;
+        for host in evicted
+            .into_iter()
+            .filter(|host| !host.interfaces.is_empty())
+        {
+            departed.insert(host.node_id.clone(), host);
+        }
+        if !changed {
+            return;
+        }
+
+        let hosts = departed.values().collect::<Vec<_>>();
+        let result = serde_json::to_vec_pretty(&hosts)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(&self.departed_path, json).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::warn!(
+                "Failed to save departed hosts to {}: {}",
+                self.departed_path.display(),
+                e
+            );
+        }
     }
 }
 
@@ -180,23 +630,35 @@ impl Schedulable for HostDatabase {
 
     async fn run(&self) {
         self.evict_old_hosts(MAX_HOST_AGE);
+
+        for (hostname, node_ids) in self.hostname_collisions() {
+            log::warn!(
+                "Hostname {} is in use by {} nodes: {}",
+                hostname,
+                node_ids.len(),
+                node_ids.join(", ")
+            );
+        }
     }
 }
 
+#[async_trait::async_trait]
 impl Dispatchable for HostDatabase {
     fn dispatcher_name(&self) -> &'static str {
         "HostDatabase"
     }
 
-    fn dispatch(&self, msg: &Envelope) -> Result<(), String> {
+    fn message_types(&self) -> &'static [MessageType] {
+        &[MessageType::SystemInfo, MessageType::PrometheusDiscovery]
+    }
+
+    async fn dispatch(&self, msg: &Envelope, context: &MessageContext) -> Result<(), String> {
         match &msg.msg {
             Some(crate::proto::homelabd::envelope::Msg::SystemInfo(sysinfo)) => {
                 let ips = sysinfo
                     .ip
                     .iter()
-                    .map(|ip| ip.parse::<IpAddr>())
-                    .filter(|res| res.is_ok())
-                    .map(|res| res.unwrap())
+                    .flat_map(|ip| ip.parse::<IpAddr>())
                     .collect::<Vec<_>>();
 
                 if ips.is_empty() {
@@ -212,30 +674,109 @@ impl Dispatchable for HostDatabase {
                     return Err("No routable IP addresses found in system info".to_string());
                 }
 
-                let primary_ip = routable_ips.first();
-                if primary_ip.is_none() {
-                    return Err("No primary IP address found in system info".to_string());
-                }
-
-                let primary_ip = primary_ip.unwrap();
+                let announced_primary = sysinfo.primary_ip.parse::<IpAddr>().ok();
 
-                // try a reverse lookup on the IP to see if we can get a more specific hostname
-                let hostname = match lookup_addr(primary_ip) {
-                    Ok(name) => name,
-                    Err(_) => sysinfo.hostname.clone(),
+                // Choosing the primary IP and the reverse lookup on it can both block on a slow
+                // DNS server, so keep them off the async workers
+                let policy = self.primary_ip_policy.clone();
+                let announced_hostname = sysinfo.hostname.clone();
+                let candidates = routable_ips.clone();
+                let reverse_dns = self.reverse_dns;
+                let virtual_ips = sysinfo
+                    .interfaces
+                    .iter()
+                    .filter(|iface| iface.is_virtual)
+                    .flat_map(|iface| &iface.addresses)
+                    .flat_map(|address| address.address.parse::<IpAddr>())
+                    .collect::<Vec<_>>();
+                let selected = tokio::task::spawn_blocking(move || {
+                    let primary_ip = policy.select(
+                        &announced_hostname,
+                        announced_primary,
+                        &candidates,
+                        &virtual_ips,
+                    )?;
+                    // try a reverse lookup on the IP to see if we can get a more specific hostname
+                    let name = if reverse_dns {
+                        lookup_addr(&primary_ip).ok()
+                    } else {
+                        None
+                    };
+                    Some((primary_ip, name))
+                })
+                .await
+                .map_err(|e| format!("Failed to select primary IP: {}", e))?;
+
+                let Some((primary_ip, reverse_name)) = selected else {
+                    return Err("No primary IP address found in system info".to_string());
                 };
+                let hostname = reverse_name.unwrap_or_else(|| sysinfo.hostname.clone());
+
+                // The sender should own one of the addresses it claims; if it doesn't, something is
+                // misconfigured (NAT, a proxy) or another node is announcing on its behalf.
+                let source_ip = context.source.ip();
+                let address_mismatch = !routable_ips.contains(&source_ip);
+                if address_mismatch {
+                    metrics::ADDRESS_MISMATCHES.inc();
+                    log::warn!(
+                        "Host {} announced {:?} but sent from {} (via {})",
+                        sysinfo.hostname,
+                        sysinfo.ip,
+                        source_ip,
+                        context.interface.as_deref().unwrap_or("unknown interface")
+                    );
+                }
 
+                let header = msg.header.clone().unwrap_or_default();
                 let host = Host {
                     name: hostname,
+                    hostname: sysinfo.hostname.clone(),
                     ip: sysinfo.ip.clone(),
-                    primaryip: *primary_ip,
+                    primaryip: primary_ip,
                     uptime: sysinfo.uptime,
                     version: sysinfo.homelabd_version.clone(),
+                    source_ip,
+                    address_mismatch,
+                    node_id: protocol::sender_id(msg, &sysinfo.hostname),
+                    protocol_version: header.protocol_version,
+                    capabilities: header.capabilities,
+                    interfaces: sysinfo.interfaces.clone(),
+                    rolled_back_versions: sysinfo.rolled_back_versions.clone(),
+                    build_hash: sysinfo.build_hash.clone(),
+                    target: sysinfo.target.clone(),
+                    cached_binaries: sysinfo.cached_binaries.clone(),
                 };
-                self.host_seen(&sysinfo.hostname, host);
+                self.host_seen(host, context.received_at);
+                Ok(())
+            }
+            Some(crate::proto::homelabd::envelope::Msg::PrometheusDiscovery(discovery)) => {
+                // Peers that predate headers can only be identified by a target's host
+                let fallback = discovery
+                    .discovered_targets
+                    .first()
+                    .map_or("", |target| target.host.as_str());
+                let node_id = protocol::sender_id(msg, fallback);
+                if node_id.is_empty() {
+                    return Ok(());
+                }
+
+                let services = discovery
+                    .discovered_targets
+                    .iter()
+                    .map(|target| Service {
+                        name: target.job.clone(),
+                        port: target.port as u16,
+                    })
+                    .collect();
+
+                // Discovery can race the host's first SystemInfo; it's re-sent regularly, so
+                // waiting for the next one is fine.
+                if !self.services_seen(&node_id, services) {
+                    log::debug!("Ignoring services from unknown host {}", node_id);
+                }
                 Ok(())
             }
-            _ => Ok(()),
+            _ => Err("Unexpected message type".to_string()),
         }
     }
 }
@@ -1,2 +1,4 @@
 pub mod hostdb;
 pub mod prometheus;
+pub mod update;
+pub mod wol;
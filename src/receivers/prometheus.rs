@@ -1,21 +1,29 @@
 use crate::config::Config;
-use crate::dispatch::Dispatchable;
-use crate::proto::homelabd::{Envelope, PrometheusDiscoveryMessage, PrometheusExporter};
+use crate::dispatch::{Dispatchable, MessageContext, MessageType};
+use crate::election::{LeaderTask, Leadership};
+use crate::proto::homelabd::{Envelope, PrometheusExporter};
+use crate::protocol;
 use crate::receivers::hostdb::HostDatabase;
-use crate::scheduler::Schedulable;
 
 use serde::Serialize;
 use std::sync::{Arc, Mutex};
 
+/// One entry in Prometheus' file-based service discovery format.
 #[derive(Serialize)]
-struct TargetGroup {
-    targets: Vec<String>,
-    labels: std::collections::HashMap<String, String>,
+pub struct TargetGroup {
+    pub targets: Vec<String>,
+    pub labels: std::collections::BTreeMap<String, String>,
+}
+
+struct DiscoveredTarget {
+    /// Node that announced the exporter, used to find it in the HostDB
+    node_id: String,
+    exporter: PrometheusExporter,
 }
 
 pub struct PrometheusEmitter {
     hostdb: Arc<HostDatabase>,
-    discovered_targets: Mutex<Vec<PrometheusExporter>>,
+    discovered_targets: Mutex<Vec<DiscoveredTarget>>,
 }
 
 impl PrometheusEmitter {
@@ -31,31 +39,27 @@ impl PrometheusEmitter {
             );
         }
 
-        Ok(Self {
-            hostdb,
-            discovered_targets: Mutex::new(Vec::new()),
-        })
-    }
-}
-
-#[async_trait::async_trait]
-impl Schedulable for PrometheusEmitter {
-    fn name(&self) -> &'static str {
-        "PrometheusEmitter"
+        Ok(Self::with_hostdb(hostdb))
     }
 
-    fn interval_seconds(&self) -> u64 {
-        60
+    /// Builds an emitter without checking for /etc/prometheus, for when targets are only
+    /// inspected rather than written out.
+    pub fn with_hostdb(hostdb: Arc<HostDatabase>) -> Self {
+        Self {
+            hostdb,
+            discovered_targets: Mutex::new(Vec::new()),
+        }
     }
 
-    async fn run(&self) {
+    /// Scrape targets for every known homelabd instance and discovered exporter.
+    pub fn targets(&self) -> Vec<TargetGroup> {
         // Load HostDB targets for monitoring homelabd instances
         let mut targets = self
             .hostdb
             .hosts()
             .iter()
             .map(|host| {
-                let mut labels = std::collections::HashMap::new();
+                let mut labels = std::collections::BTreeMap::new();
                 labels.insert("job".to_string(), "homelabd".to_string());
                 labels.insert("version".to_string(), host.version.clone());
                 labels.insert("instance".to_string(), format!("{}:8800", host.name));
@@ -69,12 +73,12 @@ impl Schedulable for PrometheusEmitter {
 
         // Add discovered Prometheus exporters
         let my_targets = self.discovered_targets.lock().unwrap();
-        for exporter in my_targets.iter() {
-            let mut labels = std::collections::HashMap::new();
+        for DiscoveredTarget { node_id, exporter } in my_targets.iter() {
+            let mut labels = std::collections::BTreeMap::new();
             labels.insert("job".to_string(), exporter.job.clone());
 
             // Get host from HostDB for its primary IP
-            if let Some(host) = self.hostdb.get_host(&exporter.host) {
+            if let Some(host) = self.hostdb.get_host(node_id) {
                 labels.insert(
                     "instance".to_string(),
                     format!("{}:{}", host.name, exporter.port),
@@ -89,6 +93,23 @@ impl Schedulable for PrometheusEmitter {
             }
         }
 
+        targets
+    }
+}
+
+#[async_trait::async_trait]
+impl LeaderTask for PrometheusEmitter {
+    fn name(&self) -> &'static str {
+        "PrometheusEmitter"
+    }
+
+    fn interval_seconds(&self) -> u64 {
+        60
+    }
+
+    async fn run(&self, leadership: &Leadership) {
+        let targets = self.targets();
+
         let payload = serde_json::to_string_pretty(&targets);
         if let Err(e) = payload {
             log::error!("Failed to serialize Prometheus targets: {}", e);
@@ -96,6 +117,14 @@ impl Schedulable for PrometheusEmitter {
         }
 
         let file_path = "/etc/prometheus/homelabd.json";
+        // A node that lost the lease since the run started must not overwrite the new leader's
+        if !leadership.holds() {
+            log::info!(
+                "Not writing Prometheus targets: no longer the leader for term {}",
+                leadership.term()
+            );
+            return;
+        }
         if let Err(e) = std::fs::write(file_path, payload.unwrap()) {
             log::error!("Failed to write Prometheus targets to {}: {}", file_path, e);
             return;
@@ -108,30 +137,41 @@ impl Schedulable for PrometheusEmitter {
     }
 }
 
+#[async_trait::async_trait]
 impl Dispatchable for PrometheusEmitter {
     fn dispatcher_name(&self) -> &'static str {
         "PrometheusEmitter"
     }
 
-    fn dispatch(&self, msg: &Envelope) -> Result<(), String> {
+    fn message_types(&self) -> &'static [MessageType] {
+        &[MessageType::PrometheusDiscovery]
+    }
+
+    async fn dispatch(&self, msg: &Envelope, _context: &MessageContext) -> Result<(), String> {
         match &msg.msg {
             Some(crate::proto::homelabd::envelope::Msg::PrometheusDiscovery(discovery)) => {
-                // Add to self.discovered_targets, but overwrite matching entries that have the same host and job combo
-                let mut my_targets = self.discovered_targets.lock().unwrap();
+                // Each discovery is the sender's full list, so it replaces what we had for them
+                let fallback = discovery
+                    .discovered_targets
+                    .first()
+                    .map_or("", |target| target.host.as_str());
+                let node_id = protocol::sender_id(msg, fallback);
+                if node_id.is_empty() {
+                    return Ok(());
+                }
 
-                for target in &discovery.discovered_targets {
-                    if let Some(existing) = my_targets.iter_mut().find(|t| {
-                        t.host == target.host && t.job == target.job && t.port == target.port
-                    }) {
-                        *existing = target.clone();
-                    } else {
-                        my_targets.push(target.clone());
+                let mut my_targets = self.discovered_targets.lock().unwrap();
+                my_targets.retain(|target| target.node_id != node_id);
+                my_targets.extend(discovery.discovered_targets.iter().map(|target| {
+                    DiscoveredTarget {
+                        node_id: node_id.clone(),
+                        exporter: target.clone(),
                     }
-                }
+                }));
 
                 Ok(())
             }
-            _ => Ok(()),
+            _ => Err("Unexpected message type".to_string()),
         }
     }
 }
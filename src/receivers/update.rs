@@ -0,0 +1,220 @@
+use crate::config::Config;
+use crate::dispatch::{Dispatchable, MessageContext, MessageType};
+use crate::election::Election;
+use crate::proto::homelabd::{Envelope, UpdateRequest, envelope};
+use crate::receivers::hostdb::HostDatabase;
+use crate::tls::PeerClient;
+use crate::{binary, protocol, update};
+
+use rand::seq::SliceRandom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Peers to fetch an update from at once; enough to share the load without every node hitting
+// every peer
+const MAX_SOURCES: usize = 8;
+
+/// Updates this node when the leader's rollout asks it to.
+pub struct SelfUpdater {
+    node_id: String,
+    hostdb: Arc<HostDatabase>,
+    /// Requests are only taken from the current leader, in its current term
+    election: Arc<Election>,
+    peers: Arc<PeerClient>,
+    state_dir: PathBuf,
+    hostname: String,
+    /// Set if this node is in its own `--rollout-hold` list
+    held: bool,
+    updating: AtomicBool,
+}
+
+impl SelfUpdater {
+    pub fn new(
+        config: &Config,
+        node_id: &str,
+        hostdb: Arc<HostDatabase>,
+        election: Arc<Election>,
+        peers: Arc<PeerClient>,
+    ) -> Self {
+        let hostname = match &config.hostname_override {
+            Some(hostname) => hostname.clone(),
+            None => hostname::get()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        };
+
+        Self {
+            node_id: node_id.to_string(),
+            hostdb,
+            election,
+            peers,
+            state_dir: config.state_dir.clone(),
+            held: config
+                .rollout_hold
+                .iter()
+                .any(|held| *held == hostname || held == node_id),
+            hostname,
+            updating: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether this node is held back from rollouts, by its own configuration or at runtime by
+    /// the leader.
+    fn is_held(&self) -> bool {
+        self.held
+            || self
+                .election
+                .rollout_holds()
+                .iter()
+                .any(|held| *held == self.hostname || *held == self.node_id)
+    }
+
+    /// Where to fetch the update from: the rollout's source, plus a random handful of other
+    /// peers running or caching the same build. Empty if no known host announces the requested
+    /// build for this version and target, as nothing then vouches for the hash.
+    fn sources(&self, request: &UpdateRequest) -> Vec<String> {
+        let announced = update::sources(
+            &self.hostdb.hosts(),
+            binary::TARGET,
+            &request.version,
+            &self.peers,
+        )
+        .into_iter()
+        .filter(|source| source.build_hash == request.build_hash)
+        .map(|source| source.url)
+        .collect::<Vec<_>>();
+        if announced.is_empty() {
+            return Vec::new();
+        }
+
+        let mut peers = announced
+            .into_iter()
+            .filter(|url| *url != request.url)
+            .collect::<Vec<_>>();
+        peers.shuffle(&mut rand::rng());
+        peers.truncate(MAX_SOURCES - 1);
+
+        let mut sources = vec![request.url.clone()];
+        sources.extend(peers);
+        sources
+    }
+}
+
+#[async_trait::async_trait]
+impl Dispatchable for SelfUpdater {
+    fn dispatcher_name(&self) -> &'static str {
+        "SelfUpdater"
+    }
+
+    fn message_types(&self) -> &'static [MessageType] {
+        &[MessageType::UpdateRequest]
+    }
+
+    async fn dispatch(&self, msg: &Envelope, context: &MessageContext) -> Result<(), String> {
+        let Some(envelope::Msg::UpdateRequest(request)) = &msg.msg else {
+            return Err("Unexpected message type".to_string());
+        };
+
+        // Every node sees the request, only the target acts on it
+        if request.target_node_id != self.node_id {
+            return Ok(());
+        }
+        if self.is_held() {
+            log::warn!(
+                "Ignoring update to {} from {}: this node is held",
+                request.version,
+                context.source
+            );
+            return Ok(());
+        }
+        if request.version == env!("HOMELABD_VERSION") {
+            return Ok(());
+        }
+
+        if update::rolled_back_versions(&self.state_dir).contains(&request.version) {
+            return Err(format!(
+                "Refusing {}: this node has rolled back from it before",
+                request.version
+            ));
+        }
+
+        // Installing over an unconfirmed update would lose the binary to roll back to
+        if let Some(pending) = update::pending(&self.state_dir) {
+            return Err(format!(
+                "Refusing {}: the update to {} hasn't been confirmed yet",
+                request.version, pending.version
+            ));
+        }
+
+        // Anyone on the network can send a request, so only the lease holder's count
+        let sender = protocol::sender_id(msg, "");
+        match self.election.lease() {
+            Some(lease) if lease.holder == sender && lease.term == request.term => {}
+            Some(lease) => {
+                return Err(format!(
+                    "Ignoring update to {} from {} in term {}: {} leads in term {}",
+                    request.version, sender, request.term, lease.holder, lease.term
+                ));
+            }
+            None => {
+                return Err(format!(
+                    "Ignoring update to {} from {}: no node holds the lease",
+                    request.version, sender
+                ));
+            }
+        }
+
+        if request.build_hash.is_empty() {
+            return Err(format!(
+                "Refusing {}: the request has no build hash to check it against",
+                request.version
+            ));
+        }
+        let sources = self.sources(request);
+        if sources.is_empty() {
+            return Err(format!(
+                "Refusing {}: no known host announces build {} of it for {}",
+                request.version,
+                request.build_hash,
+                binary::TARGET
+            ));
+        }
+
+        // The leader repeats requests until the node comes back, so one may already be running
+        if self.updating.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        log::info!(
+            "Updating from {} to {} with {} (term {})",
+            env!("HOMELABD_VERSION"),
+            request.version,
+            request.url,
+            request.term
+        );
+
+        let result = async {
+            let binary =
+                update::download(&self.peers, &sources, &request.build_hash, &self.state_dir)
+                    .await?;
+            update::install(&binary, &request.version, &self.state_dir)
+                .map_err(|e| format!("Failed to install update: {}", e))
+        }
+        .await;
+
+        match result {
+            Ok(exe) => {
+                log::info!("Installed {}, restarting", exe.display());
+                let e = update::restart(&exe);
+                self.updating.store(false, Ordering::SeqCst);
+                Err(format!("Failed to restart after update: {}", e))
+            }
+            Err(e) => {
+                self.updating.store(false, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+}
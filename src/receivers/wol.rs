@@ -0,0 +1,58 @@
+use crate::dispatch::{Dispatchable, MessageContext, MessageType};
+use crate::proto::homelabd::Envelope;
+use crate::wol;
+
+use std::net::Ipv4Addr;
+
+/// Sends magic packets on behalf of peers that don't share a network with the host being woken.
+pub struct WakeOnLanRelay {
+    node_id: String,
+}
+
+impl WakeOnLanRelay {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Dispatchable for WakeOnLanRelay {
+    fn dispatcher_name(&self) -> &'static str {
+        "WakeOnLanRelay"
+    }
+
+    fn message_types(&self) -> &'static [MessageType] {
+        &[MessageType::WakeOnLan]
+    }
+
+    async fn dispatch(&self, msg: &Envelope, context: &MessageContext) -> Result<(), String> {
+        match &msg.msg {
+            Some(crate::proto::homelabd::envelope::Msg::WakeOnLan(wake)) => {
+                // Every node sees the request, only the chosen relay acts on it
+                if wake.relay_node_id != self.node_id {
+                    return Ok(());
+                }
+
+                let mac = wol::parse_mac(&wake.target_mac)?;
+                let broadcast = wake
+                    .broadcast_address
+                    .parse::<Ipv4Addr>()
+                    .map_err(|e| format!("Invalid broadcast address: {}", e))?;
+
+                log::info!(
+                    "Waking {} ({}) via {} on behalf of {}",
+                    wake.target,
+                    wake.target_mac,
+                    broadcast,
+                    context.source
+                );
+                wol::send_magic_packet(mac, broadcast)
+                    .await
+                    .map_err(|e| format!("Failed to send magic packet: {}", e))
+            }
+            _ => Err("Unexpected message type".to_string()),
+        }
+    }
+}
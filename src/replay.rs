@@ -0,0 +1,290 @@
+use crate::api::{self, HostInfo, ServiceInfo};
+use crate::capture::CaptureReader;
+use crate::clock::{Clock, VirtualClock};
+use crate::config::Config;
+use crate::dispatch::{Dispatcher, MessageContext};
+use crate::events::EventBus;
+use crate::proto::homelabd::CaptureRecord;
+use crate::receivers::hostdb::HostDatabase;
+use crate::receivers::prometheus::{PrometheusEmitter, TargetGroup};
+use crate::scheduler::Schedulable;
+
+use clap::Args;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Args, Debug, Clone)]
+pub struct ReplayArgs {
+    /// Capture file written by `homelabd sniff --capture`
+    pub capture: PathBuf,
+
+    /// Keep the virtual clock running this long after the last record, e.g. to see evictions
+    #[arg(long, default_value_t = 0)]
+    pub run_on_seconds: u64,
+
+    /// Compare the final state with this snapshot and fail if they differ
+    #[arg(long)]
+    pub expect: Option<PathBuf>,
+}
+
+/// State of the receivers at the end of a replay, printed so it can be saved and compared
+/// against later runs.
+#[derive(Serialize)]
+struct Snapshot {
+    hosts: Vec<HostInfo>,
+    services: Vec<ServiceInfo>,
+    prometheus_targets: Vec<TargetGroup>,
+}
+
+/// Feeds captured datagrams to a `Dispatcher` on virtual time, running scheduled tasks whenever
+/// the clock passes their next due time, as the scheduler would have.
+pub struct Replay {
+    clock: Arc<VirtualClock>,
+    dispatcher: Dispatcher,
+    tasks: Vec<(Arc<dyn Schedulable>, SystemTime)>,
+}
+
+impl Replay {
+    /// Tasks first run at the clock's current time, like the scheduler's immediate first tick.
+    pub fn new(
+        clock: Arc<VirtualClock>,
+        dispatcher: Dispatcher,
+        tasks: Vec<Arc<dyn Schedulable>>,
+    ) -> Self {
+        let start = clock.now();
+        Self {
+            clock,
+            dispatcher,
+            tasks: tasks.into_iter().map(|task| (task, start)).collect(),
+        }
+    }
+
+    /// Dispatches one record at its capture time and waits for every handler to finish with it.
+    pub async fn feed(&mut self, record: &CaptureRecord) -> Result<(), String> {
+        let received_at = record.received_at();
+        self.advance_to(received_at).await;
+
+        let source = record
+            .source
+            .parse()
+            .map_err(|e| format!("Invalid source address {}: {}", record.source, e))?;
+        let context = MessageContext {
+            source,
+            interface: Some(record.interface.clone()).filter(|iface| !iface.is_empty()),
+            received_at: self.clock.now(),
+            size: record.data.len(),
+        };
+
+        self.dispatcher.dispatch(&record.data, context);
+        self.dispatcher.idle().await;
+        Ok(())
+    }
+
+    /// Moves the clock to `time`, running every task that comes due on the way in order.
+    pub async fn advance_to(&mut self, time: SystemTime) {
+        loop {
+            let due = self
+                .tasks
+                .iter_mut()
+                .filter(|(_, next_run)| *next_run <= time)
+                .min_by_key(|(_, next_run)| *next_run);
+            let Some((task, next_run)) = due else {
+                break;
+            };
+
+            self.clock.advance_to(*next_run);
+            *next_run += Duration::from_secs(task.interval_seconds().max(1));
+            task.run().await;
+        }
+
+        self.clock.advance_to(time);
+    }
+}
+
+/// Replays a capture through fresh HostDatabase and PrometheusEmitter instances and prints what
+/// they ended up with.
+pub async fn replay(config: &Config, args: ReplayArgs) -> Result<(), Error> {
+    let (snapshot, records) = run(
+        config,
+        &args.capture,
+        Duration::from_secs(args.run_on_seconds),
+    )
+    .await?;
+    eprintln!("Replayed {} records", records);
+
+    let Some(expect) = args.expect else {
+        println!("{}", serde_json::to_string_pretty(&snapshot)?);
+        return Ok(());
+    };
+
+    let expected = std::fs::read_to_string(&expect)
+        .map_err(|e| format!("Failed to read {}: {}", expect.display(), e))?;
+    compare(&snapshot, &expected)
+        .map_err(|e| format!("Replay differs from {}: {}", expect.display(), e))?;
+    eprintln!("Replay matches {}", expect.display());
+    Ok(())
+}
+
+/// Feeds every record in a capture to fresh receivers, keeps the clock running for `run_on`
+/// afterwards, and returns their final state with the number of records replayed.
+async fn run(
+    config: &Config,
+    capture: &Path,
+    run_on: Duration,
+) -> Result<(Snapshot, usize), Error> {
+    let mut reader = CaptureReader::open(capture)
+        .map_err(|e| format!("Failed to open {}: {}", capture.display(), e))?;
+    let Some(first) = reader.next_record()? else {
+        return Err(format!("{} contains no records", capture.display()).into());
+    };
+
+    // Start from empty state, and keep the replay independent of this machine's DNS
+    let state_dir = std::env::temp_dir().join(format!("homelabd-replay-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&state_dir)?;
+    let config = Config {
+        state_dir: state_dir.clone(),
+        confirm_primary_ip_dns: false,
+        ..config.clone()
+    };
+
+    let clock = Arc::new(VirtualClock::new(first.received_at()));
+    let hostdb = Arc::new(HostDatabase::with_clock(
+        &config,
+        Arc::new(EventBus::new()),
+        Arc::clone(&clock) as Arc<dyn Clock>,
+        false,
+    ));
+    let emitter = Arc::new(PrometheusEmitter::with_hostdb(Arc::clone(&hostdb)));
+
+    let mut dispatcher = Dispatcher::new();
+    dispatcher.register(Arc::clone(&hostdb));
+    dispatcher.register(Arc::clone(&emitter));
+
+    // The emitter's own task only writes its targets out, which the snapshot covers instead
+    let mut replay = Replay::new(Arc::clone(&clock), dispatcher, vec![hostdb.clone()]);
+
+    let mut records = 0;
+    let mut next = Some(first);
+    while let Some(record) = next {
+        replay.feed(&record).await?;
+        records += 1;
+        next = reader.next_record()?;
+    }
+
+    replay.advance_to(clock.now() + run_on).await;
+    let _ = std::fs::remove_dir_all(&state_dir);
+
+    let snapshot = Snapshot {
+        hosts: api::hosts(&hostdb, clock.now()),
+        services: api::services(&hostdb),
+        prometheus_targets: emitter.targets(),
+    };
+    Ok((snapshot, records))
+}
+
+/// Checks a snapshot against a saved one, describing the first line that differs. Both sides
+/// go through `serde_json::Value` so key order in the saved file doesn't matter.
+fn compare(snapshot: &Snapshot, expected: &str) -> Result<(), Error> {
+    let expected = serde_json::from_str::<serde_json::Value>(expected)?;
+    let actual = serde_json::to_value(snapshot)?;
+    if actual == expected {
+        return Ok(());
+    }
+
+    let expected = serde_json::to_string_pretty(&expected)?;
+    let actual = serde_json::to_string_pretty(&actual)?;
+    let (line, (want, got)) = expected
+        .lines()
+        .chain(std::iter::repeat("<end of snapshot>"))
+        .zip(actual.lines().chain(std::iter::repeat("<end of snapshot>")))
+        .enumerate()
+        .find(|(_, (want, got))| want != got)
+        .unwrap_or((0, ("", "")));
+    Err(format!(
+        "line {}:\n  expected: {}\n  actual:   {}",
+        line + 1,
+        want.trim(),
+        got.trim()
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    // Two nodes, alpha and beta, both announcing grafana. Grafana stops before alpha's last
+    // discovery, and beta leaves about 15 seconds before the capture ends.
+    const CAPTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/two-hosts.capture"
+    );
+    const SNAPSHOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/two-hosts.json");
+
+    async fn replay_fixture(run_on: Duration) -> Snapshot {
+        let config = Config::parse_from(["homelabd"]);
+        let (snapshot, records) = run(&config, Path::new(CAPTURE), run_on).await.unwrap();
+        assert_eq!(records, 29);
+        snapshot
+    }
+
+    #[tokio::test]
+    async fn matches_saved_snapshot() {
+        let snapshot = replay_fixture(Duration::ZERO).await;
+        compare(&snapshot, &std::fs::read_to_string(SNAPSHOT).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tracks_hosts_and_their_exporters() {
+        let snapshot = replay_fixture(Duration::ZERO).await;
+
+        let hostnames = snapshot
+            .hosts
+            .iter()
+            .map(|host| host.hostname.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(hostnames, ["alpha", "beta"]);
+
+        // alpha's last discovery was empty, so only beta's grafana is left
+        let services = snapshot
+            .services
+            .iter()
+            .map(|service| (service.host.as_str(), service.name.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(services, [("beta", "grafana")]);
+    }
+
+    #[tokio::test]
+    async fn evicts_hosts_that_go_quiet() {
+        let snapshot = replay_fixture(Duration::from_secs(600)).await;
+        assert!(snapshot.hosts.is_empty());
+        assert!(snapshot.services.is_empty());
+        assert!(snapshot.prometheus_targets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compare_ignores_key_order_and_reports_the_changed_line() {
+        let snapshot = replay_fixture(Duration::ZERO).await;
+        let saved = std::fs::read_to_string(SNAPSHOT).unwrap();
+
+        // Round-tripping through Value sorts the keys, unlike the struct's field order
+        let sorted =
+            serde_json::to_string(&serde_json::from_str::<serde_json::Value>(&saved).unwrap())
+                .unwrap();
+        compare(&snapshot, &sorted).unwrap();
+
+        let changed = saved.replacen("\"grafana\"", "\"loki\"", 1);
+        let error = compare(&snapshot, &changed).unwrap_err().to_string();
+        assert!(error.contains("expected: \"name\": \"loki\""), "{}", error);
+        assert!(
+            error.contains("actual:   \"name\": \"grafana\""),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,5 +1,4 @@
 use std::net::{IpAddr, ToSocketAddrs};
-use std::str::FromStr;
 
 /// Resolves a hostname to an IP address, but only returns it if it's in the known list.
 /// Falls back to the first known IP address if resolution fails or returns an untrusted IP.
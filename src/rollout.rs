@@ -0,0 +1,667 @@
+//! Rolling updates across the cluster, coordinated by the leader: canaries first, then batches,
+//! waiting for each wave to come back on the new version and confirm it before starting the next.
+
+use crate::api::{RolloutNode, RolloutState, RolloutStatus, UpdateState};
+use crate::config::Config;
+use crate::election::Election;
+use crate::net::Network;
+use crate::proto::homelabd::{UpdateRequest, envelope};
+use crate::protocol::capability;
+use crate::receivers::hostdb::HostDatabase;
+use crate::scheduler::Schedulable;
+use crate::tls::PeerClient;
+use crate::{binary, update};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+struct PlannedNode {
+    node_id: String,
+    hostname: String,
+    wave: usize,
+    state: UpdateState,
+    started_at: Option<SystemTime>,
+    /// When the node was first seen back on the new version
+    healthy_at: Option<SystemTime>,
+    /// Where this node fetches the update from, which depends on its target
+    url: String,
+    build_hash: String,
+    source: String,
+}
+
+struct Plan {
+    version: String,
+    /// Leader term the rollout was started (or last resumed) in, sent as the fencing token
+    term: u64,
+    state: RolloutState,
+    reason: Option<String>,
+    nodes: Vec<PlannedNode>,
+}
+
+pub struct Rollout {
+    network: Arc<Network>,
+    hostdb: Arc<HostDatabase>,
+    election: Arc<Election>,
+    peers: Arc<PeerClient>,
+    canaries: usize,
+    batch_size: usize,
+    health_timeout: Duration,
+    /// How long an updated node takes to confirm its update, and can still roll back until
+    confirm_after: Duration,
+    /// Holds from this node's `--rollout-hold`; those made at runtime are kept by the election
+    configured_holds: Vec<String>,
+    plan: Mutex<Option<Plan>>,
+}
+
+impl Rollout {
+    pub fn new(
+        config: &Config,
+        network: Arc<Network>,
+        hostdb: Arc<HostDatabase>,
+        election: Arc<Election>,
+        peers: Arc<PeerClient>,
+    ) -> Self {
+        Self {
+            network,
+            hostdb,
+            election,
+            peers,
+            canaries: config.rollout_canaries.max(1),
+            batch_size: config.rollout_batch_size.max(1),
+            health_timeout: Duration::from_secs(config.rollout_health_timeout_seconds),
+            confirm_after: Duration::from_secs(config.update_confirm_seconds),
+            configured_holds: config.rollout_hold.clone(),
+            plan: Mutex::new(None),
+        }
+    }
+
+    pub fn status(&self) -> RolloutStatus {
+        let plan = self.plan.lock().unwrap();
+        let now = SystemTime::now();
+
+        RolloutStatus {
+            is_leader: self.election.leadership().is_some(),
+            state: plan.as_ref().map_or(RolloutState::Idle, |plan| plan.state),
+            version: plan.as_ref().map(|plan| plan.version.clone()),
+            reason: plan.as_ref().and_then(|plan| plan.reason.clone()),
+            held: self.holds(),
+            nodes: plan
+                .iter()
+                .flat_map(|plan| &plan.nodes)
+                .map(|node| RolloutNode {
+                    node_id: node.node_id.clone(),
+                    hostname: node.hostname.clone(),
+                    wave: node.wave,
+                    state: node.state,
+                    source: node.source.clone(),
+                    updating_seconds: node
+                        .started_at
+                        .filter(|_| node.state == UpdateState::Updating)
+                        .map(|started| now.duration_since(started).unwrap_or_default().as_secs()),
+                })
+                .collect(),
+        }
+    }
+
+    fn require_leader(&self) -> Result<u64, String> {
+        self.election.leadership().ok_or_else(|| {
+            match self
+                .election
+                .lease()
+                .and_then(|lease| self.hostdb.get_host(&lease.holder))
+            {
+                Some(leader) => format!(
+                    "Not the leader; rollouts are coordinated by {} ({})",
+                    leader.hostname, leader.primaryip
+                ),
+                None => "Not the leader, and no node currently holds the lease".to_string(),
+            }
+        })
+    }
+
+    /// Hosts held back from rollouts, by configuration or at runtime.
+    fn holds(&self) -> Vec<String> {
+        let mut holds = self.configured_holds.clone();
+        for host in self.election.rollout_holds() {
+            if !holds.contains(&host) {
+                holds.push(host);
+            }
+        }
+        holds
+    }
+
+    fn is_held(&self, node_id: &str, hostname: &str) -> bool {
+        self.holds()
+            .iter()
+            .any(|held| held == node_id || held == hostname)
+    }
+
+    /// Plans a rollout of `version` to every node not already on it, each served from a node that
+    /// runs or caches the build for its target.
+    pub fn start(&self, version: &str) -> Result<RolloutStatus, String> {
+        let term = self.require_leader()?;
+        let self_node_id = self.network.node_id();
+
+        {
+            let mut plan = self.plan.lock().unwrap();
+            if let Some(current) = plan.as_ref()
+                && matches!(current.state, RolloutState::Running | RolloutState::Paused)
+            {
+                return Err(format!(
+                    "A rollout to {} is already in progress",
+                    current.version
+                ));
+            }
+
+            let hosts = self.hostdb.hosts();
+            let mut targets = hosts
+                .iter()
+                .filter(|host| host.version != version)
+                .filter(|host| host.supports(capability::SELF_UPDATE))
+                .filter(|host| !host.rolled_back_versions.iter().any(|v| v == version))
+                .filter(|host| !self.is_held(&host.node_id, &host.hostname))
+                .collect::<Vec<_>>();
+            // Update ourselves last: the rollout's state doesn't survive our restart
+            targets.sort_by_key(|host| (host.node_id == self_node_id, host.hostname.clone()));
+            if targets.is_empty() {
+                return Err(format!("Every node that can be updated is on {}", version));
+            }
+
+            let mut nodes: Vec<PlannedNode> = Vec::new();
+            let mut unavailable = Vec::new();
+            for host in targets {
+                // Hosts that predate target triples are assumed to match us
+                let target = match host.target.as_str() {
+                    "" => binary::TARGET,
+                    target => target,
+                };
+                let Some(source) = update::sources(&hosts, target, version, &self.peers)
+                    .into_iter()
+                    .next()
+                else {
+                    unavailable.push(format!("{} ({})", host.hostname, target));
+                    continue;
+                };
+
+                let index = nodes.len();
+                let wave = if host.node_id == self_node_id {
+                    nodes.last().map_or(0, |last| last.wave + 1)
+                } else if index < self.canaries {
+                    0
+                } else {
+                    1 + (index - self.canaries) / self.batch_size
+                };
+                nodes.push(PlannedNode {
+                    node_id: host.node_id.clone(),
+                    hostname: host.hostname.clone(),
+                    wave,
+                    state: UpdateState::Pending,
+                    started_at: None,
+                    healthy_at: None,
+                    url: source.url,
+                    build_hash: source.build_hash,
+                    source: source.hostname,
+                });
+            }
+
+            if nodes.is_empty() {
+                return Err(format!(
+                    "No node runs or caches {} for {}",
+                    version,
+                    unavailable.join(", ")
+                ));
+            }
+            if !unavailable.is_empty() {
+                log::warn!(
+                    "Leaving {} out of the rollout: no node runs or caches {} for them",
+                    unavailable.join(", "),
+                    version
+                );
+            }
+
+            log::info!("Starting rollout of {} to {} nodes", version, nodes.len());
+            *plan = Some(Plan {
+                version: version.to_string(),
+                term,
+                state: RolloutState::Running,
+                reason: None,
+                nodes,
+            });
+        }
+
+        Ok(self.status())
+    }
+
+    pub fn pause(&self) -> Result<RolloutStatus, String> {
+        self.require_leader()?;
+        self.transition(&[RolloutState::Running], |plan| {
+            log::info!("Rollout of {} paused", plan.version);
+            plan.state = RolloutState::Paused;
+            plan.reason = Some("Paused by request".to_string());
+        })
+    }
+
+    /// Continues a paused rollout, retrying any nodes that failed and haven't since been held.
+    pub fn resume(&self) -> Result<RolloutStatus, String> {
+        let term = self.require_leader()?;
+        self.transition(&[RolloutState::Paused], |plan| {
+            log::info!("Rollout of {} resumed", plan.version);
+            plan.state = RolloutState::Running;
+            plan.reason = None;
+            plan.term = term;
+            for node in &mut plan.nodes {
+                if node.state == UpdateState::Failed {
+                    node.state = UpdateState::Pending;
+                    node.started_at = None;
+                }
+            }
+            drop_held(plan, &self.holds());
+        })
+    }
+
+    pub fn abort(&self) -> Result<RolloutStatus, String> {
+        self.require_leader()?;
+        self.transition(&[RolloutState::Running, RolloutState::Paused], |plan| {
+            log::info!("Rollout of {} aborted", plan.version);
+            plan.state = RolloutState::Aborted;
+            plan.reason = Some("Aborted by request".to_string());
+        })
+    }
+
+    /// Keeps a host out of this and future rollouts, and stops asking it to update if it's
+    /// already been asked. The hold goes out with the lease, so it outlasts this node's
+    /// leadership, and the host itself refuses updates while it's held.
+    pub fn hold(&self, host: &str) -> Result<RolloutStatus, String> {
+        self.require_leader()?;
+        let mut holds = self.election.rollout_holds();
+        if !holds.iter().any(|held| held == host) {
+            holds.push(host.to_string());
+            self.election.set_rollout_holds(holds);
+        }
+        self.drop_held();
+        log::info!("Holding {} back from rollouts", host);
+        Ok(self.status())
+    }
+
+    /// Takes held hosts out of the current rollout, unless they're already on its version.
+    fn drop_held(&self) {
+        if let Some(plan) = self.plan.lock().unwrap().as_mut() {
+            drop_held(plan, &self.holds());
+        }
+    }
+
+    pub fn release(&self, host: &str) -> Result<RolloutStatus, String> {
+        self.require_leader()?;
+        if self.configured_holds.iter().any(|held| held == host) {
+            return Err(format!(
+                "{} is held by --rollout-hold on this node, which only a restart can change",
+                host
+            ));
+        }
+        let mut holds = self.election.rollout_holds();
+        holds.retain(|held| held != host);
+        self.election.set_rollout_holds(holds);
+        log::info!("Released {} for rollouts", host);
+        Ok(self.status())
+    }
+
+    fn transition(
+        &self,
+        from: &[RolloutState],
+        apply: impl FnOnce(&mut Plan),
+    ) -> Result<RolloutStatus, String> {
+        {
+            let mut plan = self.plan.lock().unwrap();
+            let Some(plan) = plan.as_mut() else {
+                return Err("No rollout has been started".to_string());
+            };
+            if !from.contains(&plan.state) {
+                return Err(format!(
+                    "The rollout to {} is {:?}",
+                    plan.version, plan.state
+                ));
+            }
+            apply(plan);
+        }
+        Ok(self.status())
+    }
+
+    /// Advances the current wave, returning the update requests to (re)send.
+    fn advance(&self, now: SystemTime) -> Vec<UpdateRequest> {
+        let mut plan = self.plan.lock().unwrap();
+        let Some(plan) = plan.as_mut() else {
+            return Vec::new();
+        };
+        if plan.state != RolloutState::Running {
+            return Vec::new();
+        }
+
+        if self.election.leadership() != Some(plan.term) {
+            log::warn!("Pausing rollout of {}: lost leadership", plan.version);
+            plan.state = RolloutState::Paused;
+            plan.reason = Some("Lost leadership".to_string());
+            return Vec::new();
+        }
+        // Holds can also arrive with a lease adopted from an earlier leader
+        drop_held(plan, &self.holds());
+
+        // Nodes back on the new version can still roll back until they confirm it
+        if let Some(node) = plan.nodes.iter_mut().find(|node| {
+            node.state == UpdateState::Updated
+                && self
+                    .hostdb
+                    .get_host(&node.node_id)
+                    .is_some_and(|host| host.rolled_back_versions.contains(&plan.version))
+        }) {
+            let reason = format!("{} rolled back from {}", node.hostname, plan.version);
+            log::warn!("Pausing rollout: {}", reason);
+            node.state = UpdateState::Failed;
+            plan.state = RolloutState::Paused;
+            plan.reason = Some(reason);
+            return Vec::new();
+        }
+        let settling = |node: &PlannedNode| {
+            node.healthy_at
+                .is_some_and(|at| now.duration_since(at).unwrap_or_default() < self.confirm_after)
+        };
+
+        let Some(wave) = plan
+            .nodes
+            .iter()
+            .filter(|node| node.state != UpdateState::Updated)
+            .map(|node| node.wave)
+            .min()
+        else {
+            if !plan.nodes.iter().any(settling) {
+                log::info!("Rollout of {} complete", plan.version);
+                plan.state = RolloutState::Completed;
+            }
+            return Vec::new();
+        };
+        // The next wave waits until every earlier one has confirmed its update
+        let earlier_settling = plan
+            .nodes
+            .iter()
+            .any(|node| node.wave < wave && settling(node));
+
+        let self_node_id = self.network.node_id();
+        let mut requests = Vec::new();
+        for node in plan.nodes.iter_mut().filter(|node| node.wave == wave) {
+            match node.state {
+                UpdateState::Pending if earlier_settling => {}
+                UpdateState::Pending => {
+                    log::info!("Updating {} to {}", node.hostname, plan.version);
+                    node.state = UpdateState::Updating;
+                    node.started_at = Some(now);
+                }
+                UpdateState::Updating => {
+                    let host = self.hostdb.get_host(&node.node_id);
+                    if host
+                        .as_ref()
+                        .is_some_and(|host| host.version == plan.version)
+                    {
+                        log::info!("{} is healthy on {}", node.hostname, plan.version);
+                        node.state = UpdateState::Updated;
+                        node.healthy_at = Some(now);
+                        continue;
+                    }
+
+                    let rolled_back =
+                        host.is_some_and(|host| host.rolled_back_versions.contains(&plan.version));
+                    let started = node.started_at.unwrap_or(now);
+                    let timed_out =
+                        now.duration_since(started).unwrap_or_default() > self.health_timeout;
+                    if rolled_back || timed_out {
+                        let reason = if rolled_back {
+                            format!("{} rolled back from {}", node.hostname, plan.version)
+                        } else {
+                            format!(
+                                "{} did not come back on {} within {}s",
+                                node.hostname,
+                                plan.version,
+                                self.health_timeout.as_secs()
+                            )
+                        };
+                        log::warn!("Pausing rollout: {}", reason);
+                        node.state = UpdateState::Failed;
+                        plan.state = RolloutState::Paused;
+                        plan.reason = Some(reason);
+                    }
+                }
+                UpdateState::Updated | UpdateState::Failed => {}
+            }
+
+            if node.state == UpdateState::Updating {
+                requests.push(UpdateRequest {
+                    target_node_id: node.node_id.clone(),
+                    version: plan.version.clone(),
+                    url: node.url.clone(),
+                    term: plan.term,
+                    build_hash: node.build_hash.clone(),
+                });
+            }
+        }
+
+        if plan.state != RolloutState::Running {
+            return Vec::new();
+        }
+
+        // Our own update ends this process, and the rollout with it
+        if requests
+            .iter()
+            .any(|request| request.target_node_id == self_node_id)
+        {
+            plan.state = RolloutState::Completed;
+            plan.reason = Some("Updating the coordinator itself".to_string());
+        }
+        requests
+    }
+}
+
+fn drop_held(plan: &mut Plan, holds: &[String]) {
+    plan.nodes.retain(|node| {
+        let held = holds
+            .iter()
+            .any(|held| *held == node.node_id || *held == node.hostname);
+        if held && node.state != UpdateState::Updated {
+            log::info!("Leaving held {} out of the rollout", node.hostname);
+            return false;
+        }
+        true
+    });
+}
+
+#[async_trait::async_trait]
+impl Schedulable for Rollout {
+    fn name(&self) -> &'static str {
+        "Rollout"
+    }
+
+    fn interval_seconds(&self) -> u64 {
+        5
+    }
+
+    async fn run(&self) {
+        for request in self.advance(SystemTime::now()) {
+            if let Err(e) = self
+                .network
+                .send(envelope::Msg::UpdateRequest(request))
+                .await
+            {
+                log::warn!("Failed to send update request: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::events::EventBus;
+    use crate::receivers::hostdb::Host;
+    use crate::transport::{Datagram, Transport};
+    use bytes::Bytes;
+    use clap::Parser;
+    use std::path::PathBuf;
+
+    /// Sends nowhere and never receives; the tests call `advance` directly.
+    struct Discard;
+
+    #[async_trait::async_trait]
+    impl Transport for Discard {
+        fn name(&self) -> &'static str {
+            "discard"
+        }
+
+        async fn send(&self, _: Bytes) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        async fn recv(&self) -> std::io::Result<Datagram> {
+            std::future::pending().await
+        }
+    }
+
+    /// A leader with hosts a, b and c on 1.0.0, and a source for 2.0.0.
+    struct Cluster {
+        rollout: Rollout,
+        election: Arc<Election>,
+        state_dir: PathBuf,
+    }
+
+    impl Cluster {
+        async fn start() -> Self {
+            let state_dir =
+                std::env::temp_dir().join(format!("homelabd-rollout-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&state_dir).unwrap();
+            let config = Config {
+                state_dir: state_dir.clone(),
+                ..Config::parse_from(["homelabd"])
+            };
+            let network = Arc::new(Network::new(Arc::new(Discard), "leader".to_string()));
+            let hostdb = Arc::new(HostDatabase::new(&config, Arc::new(EventBus::new())));
+
+            let hosts = [
+                ("source", "2.0.0"),
+                ("a", "1.0.0"),
+                ("b", "1.0.0"),
+                ("c", "1.0.0"),
+            ];
+            for (index, (hostname, version)) in hosts.into_iter().enumerate() {
+                let ip = format!("10.0.0.{}", index + 1).parse().unwrap();
+                let host = Host {
+                    version: version.to_string(),
+                    capabilities: capability::SELF_UPDATE,
+                    build_hash: format!("{}-hash", version),
+                    target: binary::TARGET.to_string(),
+                    ..Host::announced(&format!("id-{}", hostname), hostname, ip)
+                };
+                hostdb.host_seen(host, SystemTime::now());
+            }
+
+            let start = SystemTime::now();
+            let clock = Arc::new(VirtualClock::new(start));
+            let election = Arc::new(Election::new(
+                Arc::clone(&network),
+                Arc::clone(&hostdb),
+                Arc::clone(&clock) as Arc<dyn crate::clock::Clock>,
+                &state_dir,
+            ));
+            clock.advance_to(start + Duration::from_secs(60));
+            election.run().await;
+            assert!(election.leadership().is_some());
+
+            let rollout = Rollout::new(
+                &config,
+                network,
+                hostdb,
+                Arc::clone(&election),
+                Arc::new(PeerClient::new(&config).unwrap()),
+            );
+            Self {
+                rollout,
+                election,
+                state_dir,
+            }
+        }
+
+        fn planned(&self) -> Vec<String> {
+            let status = self.rollout.status();
+            status.nodes.into_iter().map(|node| node.hostname).collect()
+        }
+    }
+
+    impl Drop for Cluster {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.state_dir);
+        }
+    }
+
+    fn targets(requests: Vec<UpdateRequest>) -> Vec<String> {
+        requests
+            .into_iter()
+            .map(|request| request.target_node_id)
+            .collect()
+    }
+
+    fn after(now: SystemTime, seconds: u64) -> SystemTime {
+        now + Duration::from_secs(seconds)
+    }
+
+    #[tokio::test]
+    async fn held_nodes_are_no_longer_asked_to_update() {
+        let cluster = Cluster::start().await;
+        cluster.rollout.start("2.0.0").unwrap();
+        let now = SystemTime::now();
+
+        // Requests repeat until the node comes back on the new version
+        assert_eq!(targets(cluster.rollout.advance(now)), ["id-a"]);
+        assert_eq!(targets(cluster.rollout.advance(after(now, 5))), ["id-a"]);
+
+        cluster.rollout.hold("a").unwrap();
+        assert_eq!(cluster.planned(), ["b", "c"]);
+        assert_eq!(
+            targets(cluster.rollout.advance(after(now, 10))),
+            ["id-b", "id-c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn holds_from_the_lease_take_nodes_out_of_the_rollout() {
+        let cluster = Cluster::start().await;
+        cluster.rollout.start("2.0.0").unwrap();
+        let now = SystemTime::now();
+        assert_eq!(targets(cluster.rollout.advance(now)), ["id-a"]);
+
+        // As adopted from a lease announced by an earlier leader
+        cluster.election.set_rollout_holds(vec!["id-a".to_string()]);
+        assert_eq!(
+            targets(cluster.rollout.advance(after(now, 5))),
+            ["id-b", "id-c"]
+        );
+        assert_eq!(cluster.planned(), ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn resuming_does_not_retry_held_nodes() {
+        let cluster = Cluster::start().await;
+        cluster.rollout.start("2.0.0").unwrap();
+        let now = SystemTime::now();
+        cluster.rollout.advance(now);
+
+        let timeout = cluster.rollout.health_timeout.as_secs();
+        assert!(cluster.rollout.advance(after(now, timeout + 1)).is_empty());
+        let status = cluster.rollout.status();
+        assert_eq!(status.state, RolloutState::Paused);
+        assert_eq!(status.nodes[0].state, UpdateState::Failed);
+
+        cluster.election.set_rollout_holds(vec!["a".to_string()]);
+        cluster.rollout.resume().unwrap();
+        assert_eq!(cluster.planned(), ["b", "c"]);
+        assert_eq!(
+            targets(cluster.rollout.advance(after(now, timeout + 5))),
+            ["id-b", "id-c"]
+        );
+    }
+}
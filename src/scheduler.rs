@@ -26,6 +26,11 @@ impl Scheduler {
         self.schedulables.push(sub);
     }
 
+    /// Everything registered so far, so tasks can also be triggered on demand.
+    pub fn tasks(&self) -> Vec<Arc<dyn Schedulable>> {
+        self.schedulables.clone()
+    }
+
     pub async fn run(self) {
         let mut handles = Vec::new();
         for sub in self.schedulables {
@@ -0,0 +1,655 @@
+use crate::clock::Clock;
+use crate::cluster_key::ClusterKey;
+use crate::config::Config;
+use crate::dispatch::Dispatcher;
+use crate::election::Election;
+use crate::events::EventBus;
+use crate::net::{self, Network};
+use crate::proto::homelabd::{InterfaceAddress, NetworkInterface};
+use crate::receivers::hostdb::HostDatabase;
+use crate::scheduler::Scheduler;
+use crate::subsystems::{self_update, system_info};
+use crate::transport::{Datagram, Transport};
+
+use bytes::Bytes;
+use clap::Args;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Args, Debug, Clone)]
+pub struct SimArgs {
+    /// Number of nodes to run
+    #[arg(long, default_value_t = 3)]
+    pub nodes: usize,
+
+    /// How long to run for, in simulated seconds
+    #[arg(long, default_value_t = 600)]
+    pub duration_seconds: u64,
+
+    /// Print each node's view of the cluster this often, in simulated seconds
+    #[arg(long, default_value_t = 60)]
+    pub report_interval_seconds: u64,
+
+    /// Seed for fault injection, so runs can be repeated exactly
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+
+    /// Probability that a datagram is lost on its way to each peer
+    #[arg(long, default_value_t = 0.0)]
+    pub drop: f64,
+
+    /// Probability that a datagram is delivered twice
+    #[arg(long, default_value_t = 0.0)]
+    pub duplicate: f64,
+
+    /// Delay before each datagram is delivered, in milliseconds
+    #[arg(long, default_value_t = 0)]
+    pub delay_ms: u64,
+
+    /// Extra random delay of up to this many milliseconds, which can reorder datagrams
+    #[arg(long, default_value_t = 0)]
+    pub jitter_ms: u64,
+
+    /// Split the first --partition-size nodes from the rest at this many seconds
+    #[arg(long)]
+    pub partition_at: Option<u64>,
+
+    /// Heal the partition at this many seconds
+    #[arg(long)]
+    pub heal_at: Option<u64>,
+
+    #[arg(long, default_value_t = 1)]
+    pub partition_size: usize,
+
+    /// Stop a node, as NODE@SECONDS (e.g. 2@120). Can be given more than once
+    #[arg(long, value_parser = parse_stop)]
+    pub stop: Vec<(usize, u64)>,
+}
+
+fn parse_stop(value: &str) -> Result<(usize, u64), String> {
+    let (node, at) = value
+        .split_once('@')
+        .ok_or_else(|| "Expected NODE@SECONDS".to_string())?;
+    Ok((
+        node.parse().map_err(|e| format!("Invalid node: {}", e))?,
+        at.parse().map_err(|e| format!("Invalid time: {}", e))?,
+    ))
+}
+
+/// Faults applied to every datagram on a `SimNetwork`.
+pub struct Faults {
+    pub drop: f64,
+    pub duplicate: f64,
+    pub delay: Duration,
+    pub jitter: Duration,
+}
+
+/// Wall-clock time that follows the tokio clock, so it stands still and jumps along with it
+/// when the runtime's time is paused.
+pub struct SimClock {
+    start: SystemTime,
+    started: Instant,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self {
+            start: SystemTime::now(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> SystemTime {
+        self.start + self.started.elapsed()
+    }
+}
+
+struct Endpoint {
+    address: SocketAddr,
+    sender: mpsc::UnboundedSender<Datagram>,
+    /// Endpoints only reach others on the same side of a partition
+    side: usize,
+    up: bool,
+}
+
+/// An in-memory stand-in for the multicast group. Every datagram sent is delivered to every
+/// attached endpoint, including the sender, subject to the configured faults.
+pub struct SimNetwork {
+    faults: Faults,
+    clock: Arc<SimClock>,
+    rng: Mutex<StdRng>,
+    endpoints: Mutex<Vec<Endpoint>>,
+}
+
+impl SimNetwork {
+    pub fn new(faults: Faults, seed: u64, clock: Arc<SimClock>) -> Arc<Self> {
+        Arc::new(Self {
+            faults,
+            clock,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            endpoints: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn attach(self: &Arc<Self>, address: SocketAddr) -> SimTransport {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut endpoints = self.endpoints.lock().unwrap();
+        endpoints.push(Endpoint {
+            address,
+            sender,
+            side: 0,
+            up: true,
+        });
+
+        SimTransport {
+            network: Arc::clone(self),
+            index: endpoints.len() - 1,
+            receiver: tokio::sync::Mutex::new(receiver),
+        }
+    }
+
+    /// Cuts the network in two: endpoints before `size` can no longer reach those after it.
+    pub fn partition(&self, size: usize) {
+        for (index, endpoint) in self.endpoints.lock().unwrap().iter_mut().enumerate() {
+            endpoint.side = usize::from(index >= size);
+        }
+    }
+
+    pub fn heal(&self) {
+        for endpoint in self.endpoints.lock().unwrap().iter_mut() {
+            endpoint.side = 0;
+        }
+    }
+
+    /// Takes an endpoint off the network, as if its host had been switched off.
+    pub fn disconnect(&self, index: usize) {
+        if let Some(endpoint) = self.endpoints.lock().unwrap().get_mut(index) {
+            endpoint.up = false;
+        }
+    }
+
+    fn deliver(&self, from: usize, data: Bytes) {
+        let endpoints = self.endpoints.lock().unwrap();
+        let Some(sender) = endpoints.get(from).filter(|endpoint| endpoint.up) else {
+            return;
+        };
+
+        let mut rng = self.rng.lock().unwrap();
+        for endpoint in endpoints.iter() {
+            if !endpoint.up || endpoint.side != sender.side {
+                continue;
+            }
+            if rng.random_bool(self.faults.drop) {
+                continue;
+            }
+
+            let copies = if rng.random_bool(self.faults.duplicate) {
+                2
+            } else {
+                1
+            };
+            for _ in 0..copies {
+                let jitter = self.faults.jitter.mul_f64(rng.random::<f64>());
+                let delay = self.faults.delay + jitter;
+                let source = sender.address;
+                let destination = endpoint.sender.clone();
+                let clock = Arc::clone(&self.clock);
+                let data = data.clone();
+
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = destination.send(Datagram {
+                        data,
+                        source,
+                        interface: Some("sim0".to_string()),
+                        received_at: clock.now(),
+                    });
+                });
+            }
+        }
+    }
+}
+
+/// One node's connection to a `SimNetwork`.
+pub struct SimTransport {
+    network: Arc<SimNetwork>,
+    index: usize,
+    receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+#[async_trait::async_trait]
+impl Transport for SimTransport {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn send(&self, datagram: Bytes) -> std::io::Result<()> {
+        self.network.deliver(self.index, datagram);
+        Ok(())
+    }
+
+    async fn recv(&self) -> std::io::Result<Datagram> {
+        match self.receiver.lock().await.recv().await {
+            Some(datagram) => Ok(datagram),
+            // The network holds every sender, so this only happens as it's torn down
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// A homelabd instance running on a `SimNetwork`, with its own config and receivers.
+pub struct SimNode {
+    pub name: String,
+    pub hostdb: Arc<HostDatabase>,
+    pub election: Arc<Election>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl SimNode {
+    /// Starts node `index` with the subsystems that make sense off real hardware: system info
+    /// announcements, the self-update check, the HostDatabase and leader election. Its state
+    /// goes in a directory of its own under `config.state_dir`.
+    pub fn start(
+        index: usize,
+        config: &Config,
+        network: &Arc<SimNetwork>,
+        clock: Arc<SimClock>,
+    ) -> Result<Self, Error> {
+        let name = format!("sim-{}", index);
+        let ip = Ipv4Addr::new(10, 99, 0, index as u8 + 1);
+        let state_dir = config.state_dir.join(&name);
+        std::fs::create_dir_all(&state_dir)?;
+
+        let config = Config {
+            hostname_override: Some(name.clone()),
+            primary_ip: None,
+            state_dir,
+            confirm_primary_ip_dns: false,
+            ..config.clone()
+        };
+
+        let transport =
+            Arc::new(network.attach(SocketAddr::new(IpAddr::V4(ip), config.multicast_port)));
+        let cluster_key = ClusterKey::from_config(&config)?;
+        let peer = Arc::new(
+            Network::new(transport, format!("node-{}", index))
+                .with_cluster_key(cluster_key.clone()),
+        );
+
+        let interfaces = vec![NetworkInterface {
+            name: "eth0".to_string(),
+            mac: format!("02:00:00:00:00:{:02x}", index + 1),
+            addresses: vec![InterfaceAddress {
+                address: ip.to_string(),
+                prefix_len: 24,
+            }],
+            mtu: 1500,
+            up: true,
+            is_virtual: false,
+        }];
+
+        let mut scheduler = Scheduler::new(&config);
+        let mut dispatcher = Dispatcher::new().with_cluster_key(cluster_key);
+
+        scheduler.register(Arc::new(
+            system_info::SystemInfo::new(&config, 10, Arc::clone(&peer))
+                .with_interfaces(interfaces),
+        ));
+
+        let events = Arc::new(EventBus::new());
+        let hostdb = Arc::new(HostDatabase::with_clock(
+            &config,
+            Arc::clone(&events),
+            clock.clone(),
+            false,
+        ));
+        scheduler.register(Arc::clone(&hostdb));
+        peer.watch_peers(Arc::clone(&hostdb));
+        scheduler.register(Arc::new(self_update::SelfUpdateCheck::new(
+            Arc::clone(&hostdb),
+            events,
+            60,
+        )));
+        dispatcher.watch_peers(Arc::clone(&hostdb));
+        dispatcher.register(Arc::clone(&hostdb));
+
+        let election = Arc::new(Election::new(
+            Arc::clone(&peer),
+            Arc::clone(&hostdb),
+            clock,
+            &config.state_dir,
+        ));
+        scheduler.register(Arc::clone(&election));
+        dispatcher.register(Arc::clone(&election));
+
+        Ok(Self {
+            name,
+            hostdb,
+            election,
+            tasks: vec![
+                tokio::spawn(net::start_listener(peer.transport(), dispatcher)),
+                tokio::spawn(scheduler.run()),
+            ],
+        })
+    }
+
+    pub fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+
+    /// The leader this node currently recognises, as `node-N (term T)`.
+    pub fn leader(&self) -> String {
+        match self.election.lease() {
+            Some(lease) => format!("{} (term {})", lease.holder, lease.term),
+            None => "none".to_string(),
+        }
+    }
+
+    /// Hostnames this node currently knows about, sorted.
+    pub fn members(&self) -> Vec<String> {
+        let mut members = self
+            .hostdb
+            .hosts()
+            .iter()
+            .map(|host| host.hostname.clone())
+            .collect::<Vec<_>>();
+        members.sort();
+        members
+    }
+}
+
+enum Event {
+    Report,
+    Partition,
+    Heal,
+    Stop(usize),
+}
+
+/// Runs the simulation on its own single-threaded runtime with paused time, which jumps ahead
+/// whenever every node is idle, so hours of cluster behaviour take seconds. Blocks until done.
+pub fn run(config: &Config, args: SimArgs) -> Result<(), Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()?;
+
+    let config = Config {
+        state_dir: std::env::temp_dir().join(format!("homelabd-sim-{}", std::process::id())),
+        ..config.clone()
+    };
+    let result = runtime.block_on(simulate(&config, &args));
+    let _ = std::fs::remove_dir_all(&config.state_dir);
+    result
+}
+
+async fn simulate(config: &Config, args: &SimArgs) -> Result<(), Error> {
+    if args.nodes == 0 || args.nodes > 254 {
+        return Err("--nodes must be between 1 and 254".into());
+    }
+    for probability in [args.drop, args.duplicate] {
+        if !(0.0..=1.0).contains(&probability) {
+            return Err("Probabilities must be between 0 and 1".into());
+        }
+    }
+
+    let clock = Arc::new(SimClock::new());
+    let network = SimNetwork::new(
+        Faults {
+            drop: args.drop,
+            duplicate: args.duplicate,
+            delay: Duration::from_millis(args.delay_ms),
+            jitter: Duration::from_millis(args.jitter_ms),
+        },
+        args.seed,
+        Arc::clone(&clock),
+    );
+
+    let mut nodes = Vec::new();
+    for index in 0..args.nodes {
+        nodes.push(SimNode::start(index, config, &network, Arc::clone(&clock))?);
+    }
+
+    let mut events = (1..)
+        .map(|n| n * args.report_interval_seconds.max(1))
+        .take_while(|&at| at <= args.duration_seconds)
+        .map(|at| (at, Event::Report))
+        .collect::<Vec<_>>();
+    events.extend(args.partition_at.map(|at| (at, Event::Partition)));
+    events.extend(args.heal_at.map(|at| (at, Event::Heal)));
+    events.extend(args.stop.iter().map(|&(node, at)| (at, Event::Stop(node))));
+    // Stable, so changes at a given time are made before that time's report
+    events.sort_by_key(|(at, event)| (*at, matches!(event, Event::Report)));
+
+    let start = Instant::now();
+    let mut stopped = vec![false; nodes.len()];
+    for (at, event) in events {
+        tokio::time::sleep_until(start + Duration::from_secs(at)).await;
+
+        match event {
+            Event::Report => {
+                for (node, _) in nodes.iter().zip(&stopped).filter(|(_, stopped)| !**stopped) {
+                    println!(
+                        "[{:>5}s] {} sees {}; leader {}",
+                        at,
+                        node.name,
+                        node.members().join(", "),
+                        node.leader()
+                    );
+                }
+            }
+            Event::Partition => {
+                println!(
+                    "[{:>5}s] Partitioning nodes 0..{} from the rest",
+                    at, args.partition_size
+                );
+                network.partition(args.partition_size);
+            }
+            Event::Heal => {
+                println!("[{:>5}s] Healing partition", at);
+                network.heal();
+            }
+            Event::Stop(index) => {
+                let Some(node) = nodes.get(index) else {
+                    return Err(format!("No node {} to stop", index).into());
+                };
+                println!("[{:>5}s] Stopping {}", at, node.name);
+                node.stop();
+                network.disconnect(index);
+                stopped[index] = true;
+            }
+        }
+    }
+
+    for node in &nodes {
+        node.stop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::homelabd::{LeaseMessage, envelope};
+    use clap::Parser;
+    use std::path::PathBuf;
+
+    struct Cluster {
+        network: Arc<SimNetwork>,
+        nodes: Vec<SimNode>,
+        state_dir: PathBuf,
+    }
+
+    impl Cluster {
+        fn start(size: usize) -> Self {
+            Self::start_with_key(size, None)
+        }
+
+        /// Starts `size` nodes, all signing control messages with `secret` if it's given.
+        fn start_with_key(size: usize, secret: Option<&str>) -> Self {
+            let state_dir =
+                std::env::temp_dir().join(format!("homelabd-sim-test-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&state_dir).unwrap();
+            let cluster_key = secret.map(|secret| {
+                let path = state_dir.join("cluster_key");
+                std::fs::write(&path, secret).unwrap();
+                path
+            });
+            let config = Config {
+                state_dir: state_dir.clone(),
+                cluster_key,
+                ..Config::parse_from(["homelabd"])
+            };
+            let clock = Arc::new(SimClock::new());
+            let faults = Faults {
+                drop: 0.0,
+                duplicate: 0.0,
+                delay: Duration::from_millis(1),
+                jitter: Duration::ZERO,
+            };
+            let network = SimNetwork::new(faults, 0, Arc::clone(&clock));
+            let nodes = (0..size)
+                .map(|index| SimNode::start(index, &config, &network, Arc::clone(&clock)).unwrap())
+                .collect();
+
+            Self {
+                network,
+                nodes,
+                state_dir,
+            }
+        }
+
+        fn stop(&self, index: usize) {
+            self.nodes[index].stop();
+            self.network.disconnect(index);
+        }
+
+        /// What each of the given nodes recognises as the current lease, as (holder, term).
+        fn leaders(&self, indices: impl IntoIterator<Item = usize>) -> Vec<Option<(String, u64)>> {
+            indices
+                .into_iter()
+                .map(|index| {
+                    self.nodes[index]
+                        .election
+                        .lease()
+                        .map(|lease| (lease.holder, lease.term))
+                })
+                .collect()
+        }
+    }
+
+    impl Drop for Cluster {
+        fn drop(&mut self) {
+            for node in &self.nodes {
+                node.stop();
+            }
+            let _ = std::fs::remove_dir_all(&self.state_dir);
+        }
+    }
+
+    async fn advance(seconds: u64) {
+        tokio::time::sleep(Duration::from_secs(seconds)).await;
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn lease(holder: &str, term: u64) -> Option<(String, u64)> {
+        Some((holder.to_string(), term))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nodes_join_each_other() {
+        let cluster = Cluster::start(3);
+        advance(30).await;
+
+        for node in &cluster.nodes {
+            assert_eq!(node.members(), names(&["sim-0", "sim-1", "sim-2"]));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lowest_node_id_becomes_leader_everywhere() {
+        let cluster = Cluster::start(3);
+        advance(60).await;
+
+        assert_eq!(cluster.leaders(0..3), vec![lease("node-0", 1); 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stopped_nodes_are_evicted_and_lose_leadership() {
+        let cluster = Cluster::start(3);
+        advance(60).await;
+        cluster.stop(0);
+
+        advance(60).await;
+        assert_eq!(cluster.leaders(1..3), vec![lease("node-1", 2); 2]);
+
+        advance(6 * 60).await;
+        for node in &cluster.nodes[1..] {
+            assert_eq!(node.members(), names(&["sim-1", "sim-2"]));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partitions_split_and_heal() {
+        let cluster = Cluster::start(3);
+        advance(60).await;
+        cluster.network.partition(1);
+
+        // The majority elects its own leader in a later term; the old one carries on alone
+        advance(6 * 60).await;
+        assert_eq!(cluster.nodes[0].members(), names(&["sim-0"]));
+        for node in &cluster.nodes[1..] {
+            assert_eq!(node.members(), names(&["sim-1", "sim-2"]));
+        }
+        assert_eq!(
+            cluster.leaders(0..3),
+            vec![lease("node-0", 1), lease("node-1", 2), lease("node-1", 2)]
+        );
+
+        // After healing, the higher term wins and everyone sees everyone again
+        cluster.network.heal();
+        advance(60).await;
+        for node in &cluster.nodes {
+            assert_eq!(node.members(), names(&["sim-0", "sim-1", "sim-2"]));
+        }
+        assert_eq!(cluster.leaders(0..3), vec![lease("node-1", 2); 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unsigned_leases_are_ignored_with_a_cluster_key() {
+        let cluster = Cluster::start_with_key(3, Some("correct horse battery staple, twice over"));
+        advance(60).await;
+        assert_eq!(cluster.leaders(0..3), vec![lease("node-0", 1); 3]);
+
+        let intruder = Network::new(
+            Arc::new(cluster.network.attach("10.99.0.200:9999".parse().unwrap())),
+            "intruder".to_string(),
+        );
+        intruder
+            .send(envelope::Msg::Lease(LeaseMessage {
+                holder_node_id: "intruder".to_string(),
+                term: u64::MAX,
+                duration_ms: 60_000,
+                rollout_holds: Vec::new(),
+            }))
+            .await
+            .unwrap();
+
+        advance(10).await;
+        assert_eq!(cluster.leaders(0..3), vec![lease("node-0", 1); 3]);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::capture::CaptureWriter;
+use crate::config::Config;
+use crate::ctl::OutputFormat;
+use crate::dispatch::{MessageContext, MessageType};
+use crate::fragment::Reassembler;
+use crate::net;
+use crate::proto::homelabd::{Envelope, envelope};
+
+use clap::Args;
+use prost::Message;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Args, Debug, Clone)]
+pub struct SniffArgs {
+    /// Also write every datagram received to this capture file
+    #[arg(long)]
+    pub capture: Option<PathBuf>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub output: OutputFormat,
+}
+
+#[derive(Serialize)]
+struct SniffedMessage<'a> {
+    received_at: f64,
+    source: String,
+    interface: Option<&'a str>,
+    size: usize,
+    /// Whether this message was put back together from fragments
+    reassembled: bool,
+    envelope: Option<&'a Envelope>,
+    error: Option<String>,
+}
+
+/// Joins the multicast group the same way the daemon does and prints everything received,
+/// without dispatching or sending anything.
+pub async fn sniff(config: &Config, args: SniffArgs) -> Result<(), Error> {
+    let socket = net::join_multicast(config)?;
+    let mut capture = match &args.capture {
+        Some(path) => Some(
+            CaptureWriter::create(path)
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+
+    eprintln!(
+        "Sniffing {}:{}, press Ctrl-C to stop",
+        config.multicast_group, config.multicast_port
+    );
+
+    let reassembler = Reassembler::new();
+    let mut buf = vec![0u8; net::RECEIVE_BUFFER_SIZE];
+    loop {
+        let (size, peer) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+
+        let data = &buf[..size];
+        let context = MessageContext {
+            source: peer,
+            interface: net::interface_for(peer.ip()),
+            received_at: SystemTime::now(),
+            size,
+        };
+
+        if let Some(capture) = capture.as_mut() {
+            capture.write(data, &context)?;
+        }
+
+        print_datagram(args.output, data, &context, false)?;
+
+        // Show reassembled messages as well as the fragments they arrived in
+        if let Ok(Envelope {
+            msg: Some(envelope::Msg::Fragment(fragment)),
+            ..
+        }) = Envelope::decode(data)
+        {
+            match reassembler.add(peer.ip(), &fragment, context.received_at) {
+                Ok(Some(message)) => {
+                    let context = MessageContext {
+                        size: message.len(),
+                        ..context
+                    };
+                    print_datagram(args.output, &message, &context, true)?;
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Bad fragment from {}: {}", peer, e),
+            }
+        }
+    }
+
+    if let Some(mut capture) = capture {
+        capture.flush()?;
+        eprintln!("Captured {} datagrams", capture.records());
+    }
+
+    Ok(())
+}
+
+fn print_datagram(
+    output: OutputFormat,
+    data: &[u8],
+    context: &MessageContext,
+    reassembled: bool,
+) -> Result<(), Error> {
+    let decoded = Envelope::decode(data);
+    let received_at = context
+        .received_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    if output == OutputFormat::Json {
+        let sniffed = SniffedMessage {
+            received_at,
+            source: context.source.to_string(),
+            interface: context.interface.as_deref(),
+            size: context.size,
+            reassembled,
+            envelope: decoded.as_ref().ok(),
+            error: decoded.as_ref().err().map(|e| e.to_string()),
+        };
+        println!("{}", serde_json::to_string(&sniffed)?);
+        return Ok(());
+    }
+
+    let prefix = format!(
+        "{:.3} {} {} {}B{}",
+        received_at,
+        context.source,
+        context.interface.as_deref().unwrap_or("-"),
+        context.size,
+        if reassembled { " (reassembled)" } else { "" }
+    );
+
+    match decoded {
+        Ok(env) => {
+            let kind = env
+                .msg
+                .as_ref()
+                .map(|msg| MessageType::of(msg).name())
+                .unwrap_or("Unknown");
+            let header = env.header.clone().unwrap_or_default();
+            println!(
+                "{} {} node={} seq={} v{} {:?}",
+                prefix, kind, header.node_id, header.sequence, header.protocol_version, env.msg
+            );
+        }
+        Err(e) => println!("{} decode error: {}", prefix, e),
+    }
+
+    Ok(())
+}
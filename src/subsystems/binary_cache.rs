@@ -0,0 +1,113 @@
+use crate::binary::{self, BinaryCache};
+use crate::receivers::hostdb::HostDatabase;
+use crate::tls::PeerClient;
+use crate::{scheduler::Schedulable, update};
+use rand::seq::SliceRandom;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Versions kept per target: the newest, and the one before for peers still rolling forward
+const KEEP_VERSIONS: usize = 2;
+
+// Peers to fetch a build from at once
+const MAX_SOURCES: usize = 8;
+
+/// Keeps a copy of the newest build of each other target in the cluster, so peers of that
+/// architecture can update from this node too.
+pub struct BinaryCacheSync {
+    hostdb: Arc<HostDatabase>,
+    cache: BinaryCache,
+    peers: Arc<PeerClient>,
+    /// Where partial downloads are kept
+    state_dir: PathBuf,
+    interval: u64,
+}
+
+impl BinaryCacheSync {
+    pub fn new(
+        hostdb: Arc<HostDatabase>,
+        cache: BinaryCache,
+        peers: Arc<PeerClient>,
+        state_dir: PathBuf,
+        interval: u64,
+    ) -> Self {
+        Self {
+            hostdb,
+            cache,
+            peers,
+            state_dir,
+            interval,
+        }
+    }
+
+    /// The newest version of each other target that peers run or cache.
+    fn wanted(&self) -> BTreeMap<String, String> {
+        let mut newest = BTreeMap::<String, String>::new();
+        let hosts = self.hostdb.hosts();
+        let builds = hosts
+            .iter()
+            .map(|host| (host.target.clone(), host.version.clone()))
+            .chain(hosts.iter().flat_map(|host| {
+                host.cached_binaries
+                    .iter()
+                    .map(|cached| (cached.target.clone(), cached.version.clone()))
+            }));
+
+        for (target, version) in builds {
+            if target.is_empty() || target == binary::TARGET {
+                continue;
+            }
+            let entry = newest.entry(target).or_insert_with(|| version.clone());
+            if update::is_newer(&version, entry) {
+                *entry = version;
+            }
+        }
+        newest
+    }
+}
+
+#[async_trait::async_trait]
+impl Schedulable for BinaryCacheSync {
+    fn name(&self) -> &'static str {
+        "BinaryCacheSync"
+    }
+
+    fn interval_seconds(&self) -> u64 {
+        self.interval
+    }
+
+    async fn run(&self) {
+        for (target, version) in self.wanted() {
+            if self.cache.get(&target, &version).is_some() {
+                continue;
+            }
+
+            let sources = update::sources(&self.hostdb.hosts(), &target, &version, &self.peers);
+            let Some(hash) = sources.first().map(|source| source.build_hash.clone()) else {
+                continue;
+            };
+            let mut urls = sources
+                .into_iter()
+                .filter(|source| source.build_hash == hash)
+                .map(|source| source.url)
+                .collect::<Vec<_>>();
+            urls.shuffle(&mut rand::rng());
+            urls.truncate(MAX_SOURCES);
+
+            log::info!("Caching the {} build of {}", target, version);
+            let binary = match update::download(&self.peers, &urls, &hash, &self.state_dir).await {
+                Ok(binary) => binary,
+                Err(e) => {
+                    log::warn!("Failed to cache the {} build of {}: {}", target, version, e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.cache.store(&target, &version, &binary) {
+                log::warn!("Failed to cache the {} build of {}: {}", target, version, e);
+                continue;
+            }
+            self.cache.prune(&target, KEEP_VERSIONS);
+        }
+    }
+}
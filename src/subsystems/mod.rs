@@ -1,3 +1,5 @@
+pub mod binary_cache;
 pub mod prometheus_scan;
 pub mod self_update;
 pub mod system_info;
+pub mod update_watchdog;
@@ -1,17 +1,16 @@
 use std::sync::Arc;
 
-use crate::net::send_multicast;
-use crate::proto::homelabd::{Envelope, PrometheusDiscoveryMessage, PrometheusExporter};
-use crate::{config::Config, scheduler::Schedulable};
+use crate::net::Network;
+use crate::proto::homelabd::{PrometheusDiscoveryMessage, PrometheusExporter, envelope};
+use crate::scheduler::Schedulable;
 use hostname;
 use log::info;
 use procfs::process::all_processes;
-use prost::Message;
 
 pub struct PrometheusScan {
     interval: u64,
     registry: ExporterRegistry,
-    config: Arc<Config>,
+    network: Arc<Network>,
 }
 
 #[derive(Clone)]
@@ -31,11 +30,11 @@ struct ExporterRegistry {
 }
 
 impl PrometheusScan {
-    pub fn new(config: Arc<Config>, interval: u64) -> Self {
+    pub fn new(network: Arc<Network>, interval: u64) -> Self {
         Self {
             interval,
             registry: ExporterRegistry::new(),
-            config,
+            network,
         }
     }
 }
@@ -73,26 +72,19 @@ impl Schedulable for PrometheusScan {
             })
             .collect::<Vec<PrometheusExporter>>();
 
-        if exporters.is_empty() {
-            info!("No Prometheus exporters found in the system.");
-            return;
-        }
-
+        // Sent even when empty, so peers drop exporters that have gone away
         info!(
             "Discovered {} Prometheus exporters: {:?}",
             exporters.len(),
             exporters
         );
 
-        let msg = Envelope {
-            msg: Some(crate::proto::homelabd::envelope::Msg::PrometheusDiscovery(
-                PrometheusDiscoveryMessage {
-                    discovered_targets: exporters,
-                },
-            )),
-        };
+        let msg = envelope::Msg::PrometheusDiscovery(PrometheusDiscoveryMessage {
+            discovered_targets: exporters,
+        });
 
-        send_multicast(&self.config, msg.encode_to_vec().into())
+        self.network
+            .send(msg)
             .await
             .expect("Failed to send Prometheus discovery message");
     }
@@ -161,9 +153,8 @@ impl ExporterRegistry {
 
         all_processes
             .unwrap()
-            .into_iter()
             .filter(|proc| proc.is_ok())
-            .map(|proc| match proc {
+            .filter_map(|proc| match proc {
                 Ok(proc) => {
                     if let Ok(exe) = proc.exe() {
                         self.candidate(&exe.to_string_lossy())
@@ -173,11 +164,9 @@ impl ExporterRegistry {
                 }
                 Err(_) => None,
             })
-            .filter(|c| c.is_some())
-            .map(|c| c.unwrap())
             .map(|c| DiscoveredExporter {
                 job: c.job.clone(),
-                port: c.port as u16,
+                port: c.port,
             })
             .collect::<Vec<_>>()
     }
@@ -1,13 +1,85 @@
-use crate::{config::Config, scheduler::Schedulable};
+use crate::events::{Event, EventBus};
+use crate::receivers::hostdb::{Host, HostDatabase};
+use crate::{scheduler::Schedulable, update};
 use log::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
 
+// How long a host can stay behind the newest version before it's reported as outdated, which
+// leaves a rollout time to reach it
+const OUTDATED_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// A host running an older version than the newest one in the cluster.
+struct Behind {
+    newest: String,
+    since: Instant,
+    reported: bool,
+}
+
+/// Notices when peers run a newer version than this node, and publishes an event for any host
+/// left behind on an older version. Updating is left to the leader's rollout, so nodes never
+/// restart all at once.
 pub struct SelfUpdateCheck {
+    hostdb: Arc<HostDatabase>,
+    events: Arc<EventBus>,
     interval: u64,
+    /// Newest version already reported, so it's only logged once
+    reported: Mutex<Option<String>>,
+    behind: Mutex<HashMap<String, Behind>>,
 }
 
 impl SelfUpdateCheck {
-    pub fn new(_config: &Config, interval: u64) -> Self {
-        Self { interval }
+    pub fn new(hostdb: Arc<HostDatabase>, events: Arc<EventBus>, interval: u64) -> Self {
+        Self {
+            hostdb,
+            events,
+            interval,
+            reported: Mutex::new(None),
+            behind: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check_outdated(&self, hosts: &[Arc<Host>], newest: &str) {
+        let now = Instant::now();
+        let mut behind = self.behind.lock().unwrap();
+        behind.retain(|node_id, _| {
+            hosts
+                .iter()
+                .any(|host| &host.node_id == node_id && update::is_newer(newest, &host.version))
+        });
+
+        for host in hosts
+            .iter()
+            .filter(|host| update::is_newer(newest, &host.version))
+        {
+            let entry = behind
+                .entry(host.node_id.clone())
+                .or_insert_with(|| Behind {
+                    newest: newest.to_string(),
+                    since: now,
+                    reported: false,
+                });
+            // Give each newer version the same time to arrive
+            if entry.newest != newest {
+                *entry = Behind {
+                    newest: newest.to_string(),
+                    since: now,
+                    reported: false,
+                };
+            }
+
+            if !entry.reported && now.duration_since(entry.since) >= OUTDATED_AFTER {
+                entry.reported = true;
+                self.events.publish(Event::OutdatedVersion {
+                    node_id: host.node_id.clone(),
+                    hostname: host.hostname.clone(),
+                    version: host.version.clone(),
+                    newest: newest.to_string(),
+                });
+            }
+        }
     }
 }
 
@@ -22,7 +94,28 @@ impl Schedulable for SelfUpdateCheck {
     }
 
     async fn run(&self) {
-        // TODO: Check versions from peers and trigger update if needed
-        info!("SelfUpdateCheck: (simulated) checking for new versions...");
+        let hosts = self.hostdb.hosts();
+        let Some(newest) = hosts
+            .iter()
+            .map(|host| host.version.clone())
+            .reduce(|a, b| if update::is_newer(&b, &a) { b } else { a })
+        else {
+            return;
+        };
+        self.check_outdated(&hosts, &newest);
+
+        if !update::is_newer(&newest, env!("HOMELABD_VERSION")) {
+            return;
+        }
+        let mut reported = self.reported.lock().unwrap();
+        if reported.as_ref() != Some(&newest) {
+            info!(
+                "Version {} is running on peers (this node has {}); update with `homelabd ctl rollout start {}`",
+                newest,
+                env!("HOMELABD_VERSION"),
+                newest
+            );
+            *reported = Some(newest);
+        }
     }
 }
@@ -1,27 +1,40 @@
 use crate::config::Config;
-use crate::net::send_multicast;
-use crate::proto::homelabd::{Envelope, SystemInfoMessage};
+use crate::interfaces;
+use crate::net::Network;
+use crate::proto::homelabd::{NetworkInterface, SystemInfoMessage, envelope};
 use crate::scheduler::Schedulable;
+use crate::{binary, update};
 use hostname::get;
 use if_addrs::get_if_addrs;
 use log::info;
-use prost::Message;
+use std::sync::Arc;
 use sys_info;
 
 pub struct SystemInfo {
     interval: u64,
     version: String,
     config: Config,
+    network: Arc<Network>,
+    /// Announced instead of this machine's own interfaces, for simulated nodes
+    interfaces: Option<Vec<NetworkInterface>>,
 }
 
 impl SystemInfo {
-    pub fn new(config: &Config, interval: u64) -> Self {
+    pub fn new(config: &Config, interval: u64, network: Arc<Network>) -> Self {
         Self {
             interval,
             version: env!("HOMELABD_VERSION").to_string(),
             config: config.clone(),
+            network,
+            interfaces: None,
         }
     }
+
+    #[cfg(any(test, feature = "sim"))]
+    pub fn with_interfaces(mut self, interfaces: Vec<NetworkInterface>) -> Self {
+        self.interfaces = Some(interfaces);
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -35,7 +48,10 @@ impl Schedulable for SystemInfo {
     }
 
     async fn run(&self) {
-        let hostname = get().unwrap_or_default().to_string_lossy().into_owned();
+        let hostname = match &self.config.hostname_override {
+            Some(hostname) => hostname.clone(),
+            None => get().unwrap_or_default().to_string_lossy().into_owned(),
+        };
         let uptime = match sys_info::boottime() {
             Ok(val) => val.tv_sec,
             Err(e) => {
@@ -46,30 +62,48 @@ impl Schedulable for SystemInfo {
                 0
             }
         };
-        let addrs: Vec<String> = get_if_addrs()
-            .unwrap_or_default()
-            .iter()
-            .filter(|ifa| ifa.ip().is_ipv4() && !ifa.ip().is_loopback())
-            .map(|ifa| ifa.ip().to_string())
-            .collect();
-
-        let msg = Envelope {
-            msg: Some(crate::proto::homelabd::envelope::Msg::SystemInfo(
-                SystemInfoMessage {
-                    hostname,
-                    uptime,
-                    ip: addrs,
-                    homelabd_version: self.version.clone(),
-                },
-            )),
+        let (addrs, interfaces) = match &self.interfaces {
+            Some(interfaces) => {
+                let addrs = interfaces
+                    .iter()
+                    .flat_map(|iface| &iface.addresses)
+                    .filter(|address| !address.address.contains(':'))
+                    .map(|address| address.address.clone())
+                    .collect();
+                (addrs, interfaces.clone())
+            }
+            None => {
+                let addrs: Vec<String> = get_if_addrs()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter(|ifa| ifa.ip().is_ipv4() && !ifa.ip().is_loopback())
+                    .map(|ifa| ifa.ip().to_string())
+                    .collect();
+                (addrs, interfaces::local_interfaces())
+            }
         };
 
+        let msg = envelope::Msg::SystemInfo(SystemInfoMessage {
+            hostname,
+            uptime,
+            ip: addrs,
+            homelabd_version: self.version.clone(),
+            primary_ip: self
+                .config
+                .primary_ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+            interfaces,
+            rolled_back_versions: update::rolled_back_versions(&self.config.state_dir),
+            build_hash: binary::build_hash(),
+            target: binary::TARGET.to_string(),
+            cached_binaries: binary::BinaryCache::new(&self.config.state_dir).entries(),
+        });
+
         info!("Broadcasting system info: {:?}", msg);
 
-        send_multicast(&self.config, msg.encode_to_vec().into())
-            .await
-            .unwrap_or_else(|e| {
-                log::warn!("Failed to send system info: {}", e);
-            });
+        self.network.send(msg).await.unwrap_or_else(|e| {
+            log::warn!("Failed to send system info: {}", e);
+        });
     }
 }
@@ -0,0 +1,88 @@
+use crate::receivers::hostdb::HostDatabase;
+use crate::{scheduler::Schedulable, update};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Keeps or rolls back a pending update. It's kept once this node has run for the confirm period
+/// and hears its own announcements on the new version; it's rolled back if that still hasn't
+/// happened after three times as long. Crash loops are caught on start by `check_pending`.
+pub struct UpdateWatchdog {
+    state_dir: PathBuf,
+    hostdb: Arc<HostDatabase>,
+    node_id: String,
+    confirm_after: Duration,
+    started: Instant,
+}
+
+impl UpdateWatchdog {
+    pub fn new(
+        state_dir: PathBuf,
+        hostdb: Arc<HostDatabase>,
+        node_id: &str,
+        confirm_after: Duration,
+    ) -> Self {
+        Self {
+            state_dir,
+            hostdb,
+            node_id: node_id.to_string(),
+            confirm_after,
+            started: Instant::now(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Schedulable for UpdateWatchdog {
+    fn name(&self) -> &'static str {
+        "UpdateWatchdog"
+    }
+
+    fn interval_seconds(&self) -> u64 {
+        10
+    }
+
+    async fn run(&self) {
+        let Some(pending) = update::pending(&self.state_dir) else {
+            return;
+        };
+
+        // The binary installed for the update says it's a different version, so it isn't the
+        // one that was asked for
+        if pending.version != env!("HOMELABD_VERSION") {
+            let reason = format!(
+                "{} was installed but reports itself as {}",
+                pending.version,
+                env!("HOMELABD_VERSION")
+            );
+            match update::rollback(&self.state_dir, &reason) {
+                Ok(exe) => log::error!("Failed to restart: {}", update::restart(&exe)),
+                Err(e) => log::error!("Failed to roll back {}: {}", pending.version, e),
+            }
+            return;
+        }
+
+        let running = self.started.elapsed();
+        let seen = self
+            .hostdb
+            .get_host(&self.node_id)
+            .is_some_and(|host| host.version == env!("HOMELABD_VERSION"));
+
+        if seen && running >= self.confirm_after {
+            match update::confirm(&self.state_dir) {
+                Ok(()) => log::info!(
+                    "Update to {} confirmed after {}s",
+                    pending.version,
+                    running.as_secs()
+                ),
+                Err(e) => log::warn!("Failed to confirm update to {}: {}", pending.version, e),
+            }
+        } else if running >= self.confirm_after * 3 {
+            let reason = format!("not seen on the network after {}s", running.as_secs());
+            match update::rollback(&self.state_dir, &reason) {
+                Ok(exe) => log::error!("Failed to restart: {}", update::restart(&exe)),
+                Err(e) => log::error!("Failed to roll back {}: {}", pending.version, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! HTTPS for the API and for calls between peers, and a small cluster CA to issue their
+//! certificates.
+//!
+//! `homelabd ca init` creates the CA, and `homelabd ca issue` a certificate for each node, with
+//! its addresses as subject names since peers are reached by IP. Give every node `--tls-cert`,
+//! `--tls-key` and `--tls-ca`: they then serve HTTPS, check each other's certificates against
+//! the CA, and present their own as client certificates when fetching updates. Certificates
+//! carry an API role: nodes only need read, while `--role control` suits an operator's machine.
+
+use crate::auth::Role;
+use crate::config::Config;
+
+use clap::{Args, Subcommand};
+use http_body_util::Empty;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::AUTHORIZATION;
+use hyper::{Request, Response};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose, SanType,
+};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca.key";
+const CA_NAME: &str = "homelabd cluster CA";
+const CA_VALIDITY_DAYS: i64 = 3650;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// The HTTPS server configuration, if `--tls-cert` is set. With `--tls-ca`, clients may also
+/// present a certificate signed by the CA.
+pub fn server_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, String> {
+    let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+        return Ok(None);
+    };
+
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match &config.tls_ca {
+        Some(ca) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider())
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(|e| format!("Invalid CA {}: {}", ca.display(), e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| format!("Invalid certificate {}: {}", cert.display(), e))?;
+    Ok(Some(Arc::new(server)))
+}
+
+/// An HTTP client that also speaks HTTPS: checking servers against the cluster CA if there is
+/// one, or the public roots otherwise, and presenting this node's certificate to servers that
+/// ask for one. For calls to peers only.
+pub fn client<B>(config: &Config) -> Result<Client<HttpsConnector<HttpConnector>, B>, String>
+where
+    B: hyper::body::Body + Send + 'static,
+    B::Data: Send,
+{
+    let roots = match &config.tls_ca {
+        Some(ca) => roots(ca)?,
+        None => public_roots(),
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots);
+    let tls = match (&config.tls_ca, &config.tls_cert, &config.tls_key) {
+        (Some(_), Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| format!("Invalid certificate {}: {}", cert.display(), e))?,
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(https_client(tls))
+}
+
+/// An HTTP client for services outside the cluster, checking them against the public roots and
+/// never presenting this node's certificate.
+pub fn public_client<B>() -> Result<Client<HttpsConnector<HttpConnector>, B>, String>
+where
+    B: hyper::body::Body + Send + 'static,
+    B::Data: Send,
+{
+    let tls = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(public_roots())
+        .with_no_client_auth();
+    Ok(https_client(tls))
+}
+
+fn public_roots() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+fn https_client<B>(tls: ClientConfig) -> Client<HttpsConnector<HttpConnector>, B>
+where
+    B: hyper::body::Body + Send + 'static,
+    B::Data: Send,
+{
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder(TokioExecutor::new()).build(connector)
+}
+
+/// Calls to other nodes' HTTP APIs. Peers are assumed to be configured like this node: on the
+/// same port, with HTTPS if this node has it.
+pub struct PeerClient {
+    client: Client<HttpsConnector<HttpConnector>, Empty<Bytes>>,
+    token: Option<String>,
+    scheme: &'static str,
+    port: u16,
+}
+
+impl PeerClient {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(Self {
+            client: client(config)?,
+            token: config.token.clone(),
+            scheme: if config.tls_cert.is_some() {
+                "https"
+            } else {
+                "http"
+            },
+            port: config.http_port,
+        })
+    }
+
+    /// The URL of `path` on the peer at `ip`.
+    pub fn url(&self, ip: IpAddr, path: &str) -> String {
+        format!(
+            "{}://{}{}",
+            self.scheme,
+            SocketAddr::new(ip, self.port),
+            path
+        )
+    }
+
+    /// A GET request for `url`, carrying our token if there is one.
+    pub fn get(&self, url: &str) -> hyper::http::request::Builder {
+        let request = Request::get(url);
+        match &self.token {
+            Some(token) => request.header(AUTHORIZATION, format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    pub async fn send(
+        &self,
+        request: Request<Empty<Bytes>>,
+    ) -> Result<Response<Incoming>, hyper_util::client::legacy::Error> {
+        self.client.request(request).await
+    }
+}
+
+fn roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| format!("Invalid CA {}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let pem = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let pem = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut &pem[..])
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+        .ok_or_else(|| format!("No private key in {}", path.display()))
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CaArgs {
+    /// Directory holding the CA's certificate and key
+    #[arg(long, default_value = "/etc/homelabd/ca")]
+    pub dir: PathBuf,
+
+    #[command(subcommand)]
+    pub command: CaCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CaCommand {
+    /// Create the CA, writing ca.pem (for --tls-ca on every node) and ca.key (keep it safe)
+    Init,
+    /// Issue a certificate for a node or client, writing <name>.pem and <name>.key
+    Issue {
+        /// Name for the certificate and its files, usually the hostname
+        name: String,
+        /// Addresses the node is reached on; localhost is always included
+        #[arg(long, value_delimiter = ',')]
+        ip: Vec<IpAddr>,
+        /// DNS names the node is reached on, besides the name itself
+        #[arg(long, value_delimiter = ',')]
+        dns: Vec<String>,
+        /// How long the certificate is valid for
+        #[arg(long, default_value_t = 825)]
+        days: i64,
+        /// What the certificate may do on the HTTP API
+        #[arg(long, value_enum, default_value_t = Role::Read)]
+        role: Role,
+    },
+}
+
+pub fn run(args: CaArgs) -> Result<(), Error> {
+    match args.command {
+        CaCommand::Init => {
+            let key_path = args.dir.join(CA_KEY_FILE);
+            let key = KeyPair::generate()?;
+            let cert = ca_params()?.self_signed(&key)?;
+            fs::create_dir_all(&args.dir)?;
+            write_private(&key_path, &key.serialize_pem())?;
+            fs::write(args.dir.join(CA_CERT_FILE), cert.pem())?;
+            println!("Created {}", args.dir.join(CA_CERT_FILE).display());
+        }
+        CaCommand::Issue {
+            name,
+            ip,
+            dns,
+            days,
+            role,
+        } => {
+            let ca_key = KeyPair::from_pem(&fs::read_to_string(args.dir.join(CA_KEY_FILE))?)?;
+            // Signing only needs the CA's name and key, both of which are fixed
+            let ca_cert = ca_params()?.self_signed(&ca_key)?;
+
+            let mut params = CertificateParams::new(Vec::new())?;
+            params.distinguished_name = DistinguishedName::new();
+            params.distinguished_name.push(DnType::CommonName, &name);
+            let role: &'static str = role.into();
+            params
+                .distinguished_name
+                .push(DnType::OrganizationalUnitName, role);
+            let names = [name.clone(), "localhost".to_string()]
+                .into_iter()
+                .chain(dns)
+                .collect::<BTreeSet<_>>();
+            let ips = [IpAddr::V4(Ipv4Addr::LOCALHOST)]
+                .into_iter()
+                .chain(ip)
+                .collect::<BTreeSet<_>>();
+            params.subject_alt_names = names
+                .into_iter()
+                .map(|name| name.try_into().map(SanType::DnsName))
+                .chain(ips.into_iter().map(|ip| Ok(SanType::IpAddress(ip))))
+                .collect::<Result<_, _>>()?;
+            params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+            // Nodes are servers to ctl and their peers, and clients of each other
+            params.extended_key_usages = vec![
+                ExtendedKeyUsagePurpose::ServerAuth,
+                ExtendedKeyUsagePurpose::ClientAuth,
+            ];
+            params.not_before = time::OffsetDateTime::now_utc();
+            params.not_after = params.not_before + time::Duration::days(days);
+
+            let key = KeyPair::generate()?;
+            let cert = params.signed_by(&key, &ca_cert, &ca_key)?;
+            let cert_path = args.dir.join(format!("{}.pem", name));
+            write_private(
+                &args.dir.join(format!("{}.key", name)),
+                &key.serialize_pem(),
+            )?;
+            fs::write(&cert_path, cert.pem())?;
+            println!("Issued {}", cert_path.display());
+        }
+    }
+    Ok(())
+}
+
+fn ca_params() -> Result<CertificateParams, rcgen::Error> {
+    let mut params = CertificateParams::new(Vec::new())?;
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.not_before = time::OffsetDateTime::now_utc();
+    params.not_after = params.not_before + time::Duration::days(CA_VALIDITY_DAYS);
+    Ok(params)
+}
+
+/// Writes a key readable only by its owner, never over an existing one.
+fn write_private(path: &Path, contents: &str) -> Result<(), String> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
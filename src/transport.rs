@@ -0,0 +1,28 @@
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+/// A datagram received from a peer.
+pub struct Datagram {
+    pub data: Bytes,
+    pub source: SocketAddr,
+    /// Local interface the datagram arrived on, if known
+    pub interface: Option<String>,
+    pub received_at: SystemTime,
+}
+
+/// Carries datagrams between this node and every peer. The daemon uses UDP multicast; the
+/// simulator swaps in an in-memory network.
+#[async_trait::async_trait]
+pub trait Transport: Send + Sync {
+    /// Backend name, used to label metrics
+    fn name(&self) -> &'static str;
+
+    /// Sends one datagram to every peer. Callers are responsible for keeping it under
+    /// `fragment::MAX_DATAGRAM_SIZE`, unless some peer can't reassemble fragments.
+    async fn send(&self, datagram: Bytes) -> std::io::Result<()>;
+
+    /// Waits for the next datagram from any peer. Must be cancel safe: the listener races it
+    /// against a timer.
+    async fn recv(&self) -> std::io::Result<Datagram>;
+}
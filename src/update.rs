@@ -0,0 +1,683 @@
+//! Replacing the running binary with one fetched from a peer.
+
+use crate::receivers::hostdb::Host;
+use crate::tls::PeerClient;
+use crate::{binary, metrics};
+
+use http_body_util::{BodyExt, Empty};
+use hyper::StatusCode;
+use hyper::body::Bytes;
+use hyper::header::{
+    ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_RANGE, ETAG, HeaderMap, HeaderName, RANGE,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+
+// Per request, so a whole binary from an older peer still has time to arrive
+const DOWNLOAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+// Size of each piece of an update fetched from a peer
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Chunks of downloads in progress, in the state directory under the build's hash, so a download
+// that's interrupted picks up where it left off
+const DOWNLOADS_DIR: &str = "downloads";
+
+// Marks an installed update as unproven, in the state directory
+const PENDING_FILE: &str = "update_pending.json";
+
+// Versions this node has rolled back from, one per line, in the state directory
+const ROLLED_BACK_FILE: &str = "rolled_back_versions";
+
+// Starts of a pending update allowed before it's treated as crash-looping
+const MAX_PENDING_STARTS: u32 = 3;
+
+/// An update that hasn't yet run healthily for long enough to be kept.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingUpdate {
+    pub version: String,
+    pub previous_version: String,
+    /// How many times the new binary has started
+    pub starts: u32,
+}
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// Whether `candidate` is a later version than `current` by semver precedence, so a pre-release
+/// comes before its release and build metadata is ignored. Anything that isn't a valid version
+/// compares as 0.0.0.
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    fn parse(version: &str) -> semver::Version {
+        semver::Version::parse(version).unwrap_or(semver::Version::new(0, 0, 0))
+    }
+
+    parse(candidate).cmp_precedence(&parse(current)).is_gt()
+}
+
+/// A peer that can serve a build, and that build's hash.
+pub struct Source {
+    pub url: String,
+    pub hostname: String,
+    pub build_hash: String,
+}
+
+/// Peers that can serve the `target` build of `version`: those running it, at `/homelabd`, then
+/// those holding a cached copy.
+pub fn sources(
+    hosts: &[Arc<Host>],
+    target: &str,
+    version: &str,
+    client: &PeerClient,
+) -> Vec<Source> {
+    let url = |host: &Host, path: &str| client.url(host.primaryip, path);
+
+    let running = hosts
+        .iter()
+        .filter(|host| host.target == target && host.version == version)
+        .filter(|host| !host.build_hash.is_empty())
+        .map(|host| Source {
+            url: url(host, "/homelabd"),
+            hostname: host.hostname.clone(),
+            build_hash: host.build_hash.clone(),
+        });
+    let cached = hosts.iter().flat_map(|host| {
+        host.cached_binaries
+            .iter()
+            .filter(|cached| cached.target == target && cached.version == version)
+            .map(|cached| Source {
+                url: url(host, &format!("/homelabd/{}/{}", target, version)),
+                hostname: host.hostname.clone(),
+                build_hash: cached.build_hash.clone(),
+            })
+    });
+
+    running.chain(cached).collect()
+}
+
+/// Fetches a binary built with `hash` from several peers at once, a chunk from each in turn, so
+/// no one peer serves the whole of it. Peers that fail are dropped and their chunks handed to the
+/// rest. Chunks are kept under `state_dir` until the binary is complete, so a later attempt only
+/// fetches what's missing. Without a hash (an older source) the whole binary comes from the first
+/// URL.
+pub async fn download(
+    client: &PeerClient,
+    urls: &[String],
+    hash: &str,
+    state_dir: &Path,
+) -> Result<Bytes, String> {
+    let first = urls.first().ok_or("No peers to download from")?;
+
+    let binary = if hash.is_empty() {
+        let (status, _, body) = fetch(client, first, None).await?;
+        if status != StatusCode::OK {
+            return Err(format!("{} returned {}", first, status));
+        }
+        body
+    } else {
+        download_chunks(client, urls, hash, state_dir).await?
+    };
+
+    if !binary.starts_with(ELF_MAGIC) {
+        return Err(format!("{} did not return an executable", first));
+    }
+    Ok(binary)
+}
+
+async fn download_chunks(
+    client: &PeerClient,
+    urls: &[String],
+    hash: &str,
+    state_dir: &Path,
+) -> Result<Bytes, String> {
+    // The hash names a directory, so make sure it's only a hash
+    if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(format!("Invalid build hash {}", hash));
+    }
+    let staging = state_dir.join(DOWNLOADS_DIR).join(hash);
+    tokio::fs::create_dir_all(&staging)
+        .await
+        .map_err(|e| format!("Failed to create {}: {}", staging.display(), e))?;
+
+    let etag = format!("\"{}\"", hash);
+    let size = binary_size(client, urls, &etag).await?;
+    let chunks = size.div_ceil(CHUNK_SIZE) as usize;
+    let chunk_len = |index: usize| {
+        (index as u64 * CHUNK_SIZE + CHUNK_SIZE).min(size) - index as u64 * CHUNK_SIZE
+    };
+
+    let mut staged = Vec::with_capacity(chunks);
+    for index in 0..chunks {
+        let chunk = tokio::fs::read(staging.join(index.to_string())).await.ok();
+        staged.push(
+            chunk
+                .filter(|chunk| chunk.len() as u64 == chunk_len(index))
+                .map(Bytes::from),
+        );
+    }
+    let resumed = staged.iter().flatten().count();
+    if resumed > 0 {
+        log::info!(
+            "Resuming download of {} with {} of {} chunks",
+            hash,
+            resumed,
+            chunks
+        );
+    }
+
+    let parts = Mutex::new(staged);
+    let mut peers = urls.to_vec();
+    loop {
+        let missing = {
+            let parts = parts.lock().unwrap();
+            (0..chunks)
+                .filter(|&index| parts[index].is_none())
+                .collect::<VecDeque<_>>()
+        };
+        if missing.is_empty() {
+            break;
+        }
+        if peers.is_empty() {
+            return Err(format!(
+                "Every peer failed with {} of {} chunks left",
+                missing.len(),
+                chunks
+            ));
+        }
+
+        log::info!(
+            "Fetching {} chunks of {} from {} peers",
+            missing.len(),
+            hash,
+            peers.len()
+        );
+        let queue = Mutex::new(missing);
+        let (queue, parts, etag, staging) = (&queue, &parts, etag.as_str(), &staging);
+        let healthy = futures::future::join_all(peers.iter().map(|url| async move {
+            loop {
+                let Some(index) = queue.lock().unwrap().pop_front() else {
+                    return true;
+                };
+                let start = index as u64 * CHUNK_SIZE;
+                let end = (start + CHUNK_SIZE).min(size) - 1;
+
+                match fetch_chunk(client, url, etag, start, end).await {
+                    Ok(chunk) => {
+                        metrics::BINARY_CHUNKS_FETCHED
+                            .with_label_values(&[url, "ok"])
+                            .inc();
+                        let path = staging.join(index.to_string());
+                        if let Err(e) = tokio::fs::write(&path, &chunk).await {
+                            log::warn!("Failed to stage {}: {}", path.display(), e);
+                        }
+                        parts.lock().unwrap()[index] = Some(chunk);
+                    }
+                    Err(e) => {
+                        metrics::BINARY_CHUNKS_FETCHED
+                            .with_label_values(&[url, "error"])
+                            .inc();
+                        log::warn!("Dropping {} as an update source: {}", url, e);
+                        queue.lock().unwrap().push_back(index);
+                        return false;
+                    }
+                }
+            }
+        }))
+        .await;
+
+        peers = peers
+            .into_iter()
+            .zip(healthy)
+            .filter_map(|(url, healthy)| healthy.then_some(url))
+            .collect();
+    }
+
+    let mut binary = Vec::with_capacity(size as usize);
+    for part in parts.into_inner().unwrap().into_iter().flatten() {
+        binary.extend_from_slice(&part);
+    }
+    // Either way the staged chunks are done with: a bad one can't be told from the rest
+    let _ = tokio::fs::remove_dir_all(&staging).await;
+    let expected = hash.to_string();
+    let binary = tokio::task::spawn_blocking(move || {
+        let matches = binary::sha256(&binary) == expected;
+        (binary, matches)
+    });
+    match binary.await {
+        Ok((binary, true)) => Ok(Bytes::from(binary)),
+        Ok((_, false)) => Err(format!("Downloaded binary doesn't match {}", hash)),
+        Err(e) => Err(format!("Failed to check the downloaded binary: {}", e)),
+    }
+}
+
+/// Asks each peer in turn for the first byte, to learn the binary's size.
+async fn binary_size(client: &PeerClient, urls: &[String], etag: &str) -> Result<u64, String> {
+    let mut last_error = String::new();
+    for url in urls {
+        match fetch(client, url, Some((0, 0))).await {
+            Ok((StatusCode::PARTIAL_CONTENT, headers, _))
+                if header(&headers, ETAG) == Some(etag) =>
+            {
+                let size = header(&headers, CONTENT_RANGE)
+                    .and_then(|range| range.rsplit_once('/'))
+                    .and_then(|(_, size)| size.parse().ok());
+                if let Some(size) = size {
+                    return Ok(size);
+                }
+                last_error = format!("{} sent no usable Content-Range", url);
+            }
+            Ok((status, _, _)) => {
+                last_error = format!("{} returned {} without the build", url, status)
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+async fn fetch_chunk(
+    client: &PeerClient,
+    url: &str,
+    etag: &str,
+    start: u64,
+    end: u64,
+) -> Result<Bytes, String> {
+    let (status, headers, body) = fetch(client, url, Some((start, end))).await?;
+    if status != StatusCode::PARTIAL_CONTENT {
+        return Err(format!("{} returned {}", url, status));
+    }
+    if header(&headers, ETAG) != Some(etag) {
+        return Err(format!("{} is serving a different build", url));
+    }
+
+    if body.len() as u64 != end - start + 1 {
+        return Err(format!(
+            "{} sent {} bytes of {}-{}",
+            url,
+            body.len(),
+            start,
+            end
+        ));
+    }
+    Ok(body)
+}
+
+/// GETs `url`, or a byte range of it. Whole responses may come zstd-compressed, and are
+/// decompressed here; ranges are always plain.
+async fn fetch(
+    client: &PeerClient,
+    url: &str,
+    range: Option<(u64, u64)>,
+) -> Result<(StatusCode, HeaderMap, Bytes), String> {
+    let request = match range {
+        Some((start, end)) => client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", start, end)),
+        None => client.get(url).header(ACCEPT_ENCODING, "zstd"),
+    };
+    let request = request
+        .body(Empty::new())
+        .map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+
+    tokio::time::timeout(DOWNLOAD_TIMEOUT, async {
+        let response = client
+            .send(request)
+            .await
+            .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+        let (parts, body) = response.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map(|body| body.to_bytes())
+            .map_err(|e| format!("Failed to read {}: {}", url, e))?;
+        let body = match header(&parts.headers, CONTENT_ENCODING) {
+            None | Some("identity") => body,
+            Some("zstd") if range.is_none() => Bytes::from(
+                zstd::stream::decode_all(&body[..])
+                    .map_err(|e| format!("Failed to decompress {}: {}", url, e))?,
+            ),
+            Some(encoding) => {
+                return Err(format!("{} sent an unexpected {} encoding", url, encoding));
+            }
+        };
+        Ok((parts.status, parts.headers, body))
+    })
+    .await
+    .map_err(|_| format!("Timed out fetching {}", url))?
+}
+
+fn header(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Writes `binary` over the running executable, via a rename so a crash part-way through never
+/// leaves a truncated binary behind. The running binary is kept alongside for rollback, and the
+/// update is marked pending until the watchdog confirms it.
+pub fn install(binary: &[u8], version: &str, state_dir: &Path) -> io::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let staging = exe.with_extension("new");
+
+    fs::write(&staging, binary)?;
+    fs::set_permissions(&staging, fs::Permissions::from_mode(0o755))?;
+    fs::copy(&exe, exe.with_extension("previous"))?;
+    write_pending(
+        state_dir,
+        &PendingUpdate {
+            version: version.to_string(),
+            previous_version: env!("HOMELABD_VERSION").to_string(),
+            starts: 0,
+        },
+    )?;
+    fs::rename(&staging, &exe)?;
+    Ok(exe)
+}
+
+pub fn pending(state_dir: &Path) -> Option<PendingUpdate> {
+    let contents = fs::read(state_dir.join(PENDING_FILE)).ok()?;
+    match serde_json::from_slice(&contents) {
+        Ok(pending) => Some(pending),
+        Err(e) => {
+            log::warn!("Ignoring unreadable {}: {}", PENDING_FILE, e);
+            None
+        }
+    }
+}
+
+fn write_pending(state_dir: &Path, pending: &PendingUpdate) -> io::Result<()> {
+    fs::create_dir_all(state_dir)?;
+    fs::write(state_dir.join(PENDING_FILE), serde_json::to_vec(pending)?)
+}
+
+/// Keeps the running version: it has been healthy for long enough.
+pub fn confirm(state_dir: &Path) -> io::Result<()> {
+    fs::remove_file(state_dir.join(PENDING_FILE))
+}
+
+/// Versions this node has rolled back from and won't accept again.
+pub fn rolled_back_versions(state_dir: &Path) -> Vec<String> {
+    fs::read_to_string(state_dir.join(ROLLED_BACK_FILE))
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Puts the previous binary back and refuses the pending version from now on. Returns the
+/// restored executable, ready to `restart`.
+pub fn rollback(state_dir: &Path, reason: &str) -> io::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let pending = pending(state_dir);
+    let version = pending
+        .as_ref()
+        .map_or(env!("HOMELABD_VERSION"), |pending| &pending.version);
+
+    log::error!(
+        "Rolling back from {} to {}: {}",
+        version,
+        pending
+            .as_ref()
+            .map_or("the previous binary", |pending| &pending.previous_version),
+        reason
+    );
+    fs::rename(exe.with_extension("previous"), &exe)?;
+
+    let mut rolled_back = rolled_back_versions(state_dir);
+    if !rolled_back.iter().any(|v| v == version) {
+        rolled_back.push(version.to_string());
+        fs::write(
+            state_dir.join(ROLLED_BACK_FILE),
+            rolled_back.join("\n") + "\n",
+        )?;
+    }
+    confirm(state_dir)?;
+    Ok(exe)
+}
+
+/// Called first thing on start: counts starts of a pending update, and rolls back one that keeps
+/// restarting. Only returns once this binary is the one that should run.
+pub fn check_pending(state_dir: &Path) -> io::Result<()> {
+    let Some(mut pending) = pending(state_dir) else {
+        return Ok(());
+    };
+
+    // The previous binary is running again, so whatever happened the update isn't pending
+    if pending.previous_version == env!("HOMELABD_VERSION") {
+        log::warn!(
+            "Update to {} was pending but {} is running; forgetting it",
+            pending.version,
+            pending.previous_version
+        );
+        return confirm(state_dir);
+    }
+
+    pending.starts += 1;
+    if pending.starts > MAX_PENDING_STARTS {
+        let exe = rollback(
+            state_dir,
+            &format!("restarted {} times without settling", MAX_PENDING_STARTS),
+        )?;
+        return Err(restart(&exe));
+    }
+
+    log::info!(
+        "Update to {} is pending (start {} of {})",
+        pending.version,
+        pending.starts,
+        MAX_PENDING_STARTS
+    );
+    write_pending(state_dir, &pending)
+}
+
+/// Replaces this process with a fresh run of `exe` and the same arguments. Only returns if
+/// that fails.
+pub fn restart(exe: &PathBuf) -> io::Error {
+    std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .exec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    /// A build big enough to come in several chunks.
+    fn build() -> Arc<Vec<u8>> {
+        let mut build = ELF_MAGIC.to_vec();
+        build.extend((0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8));
+        Arc::new(build)
+    }
+
+    /// A peer on localhost serving ranges of `build`, which fails every request after the first
+    /// `answers`. Returns its URL and the ranges it was asked for.
+    async fn peer(build: Arc<Vec<u8>>, answers: usize) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/homelabd", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let etag = format!("\"{}\"", binary::sha256(&build));
+
+        let asked = Arc::clone(&requests);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (build, asked, etag) = (Arc::clone(&build), Arc::clone(&asked), etag.clone());
+                let service = service_fn(move |request: Request<hyper::body::Incoming>| {
+                    let range = header(request.headers(), RANGE)
+                        .unwrap_or_default()
+                        .to_string();
+                    let mut asked = asked.lock().unwrap();
+                    asked.push(range.clone());
+
+                    let size = build.len() as u64;
+                    let response = match binary::parse_range(&range, size) {
+                        _ if asked.len() > answers => Response::builder()
+                            .status(StatusCode::SERVICE_UNAVAILABLE)
+                            .body(Full::new(Bytes::new())),
+                        Ok(Some((start, end))) => Response::builder()
+                            .status(StatusCode::PARTIAL_CONTENT)
+                            .header(ETAG, &etag)
+                            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size))
+                            .body(Full::new(Bytes::copy_from_slice(
+                                &build[start as usize..=end as usize],
+                            ))),
+                        _ => Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Full::new(Bytes::new())),
+                    };
+                    async move { response }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn state_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("homelabd-update-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn client() -> PeerClient {
+        PeerClient::new(&Config::parse_from(["homelabd"])).unwrap()
+    }
+
+    /// The chunks a peer was asked for, leaving out the first byte fetched to learn the size.
+    fn chunks(requests: &Mutex<Vec<String>>) -> Vec<String> {
+        let requests = requests.lock().unwrap();
+        requests
+            .iter()
+            .filter(|range| *range != "bytes=0-0")
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn downloads_chunks_from_every_peer() {
+        let build = build();
+        let hash = binary::sha256(&build);
+        let (first, first_requests) = peer(Arc::clone(&build), usize::MAX).await;
+        let (second, second_requests) = peer(Arc::clone(&build), usize::MAX).await;
+        let state_dir = state_dir();
+
+        let downloaded = download(&client(), &[first, second], &hash, &state_dir)
+            .await
+            .unwrap();
+
+        assert_eq!(downloaded, build[..]);
+        let (first, second) = (chunks(&first_requests), chunks(&second_requests));
+        assert!(!first.is_empty() && !second.is_empty());
+        assert_eq!(first.len() + second.len(), 3);
+        // Finished downloads don't leave chunks behind
+        assert!(!state_dir.join(DOWNLOADS_DIR).join(&hash).exists());
+        let _ = fs::remove_dir_all(&state_dir);
+    }
+
+    #[tokio::test]
+    async fn hands_the_chunks_of_failing_peers_to_the_rest() {
+        let build = build();
+        let hash = binary::sha256(&build);
+        // Answers the size probe, then fails
+        let (failing, failing_requests) = peer(Arc::clone(&build), 1).await;
+        let (healthy, healthy_requests) = peer(Arc::clone(&build), usize::MAX).await;
+        let state_dir = state_dir();
+
+        let downloaded = download(&client(), &[failing, healthy], &hash, &state_dir)
+            .await
+            .unwrap();
+
+        assert_eq!(downloaded, build[..]);
+        assert_eq!(chunks(&failing_requests).len(), 1);
+        assert_eq!(chunks(&healthy_requests).len(), 3);
+        let _ = fs::remove_dir_all(&state_dir);
+    }
+
+    #[tokio::test]
+    async fn fails_once_every_peer_has() {
+        let build = build();
+        let hash = binary::sha256(&build);
+        let (url, _) = peer(Arc::clone(&build), 2).await;
+        let state_dir = state_dir();
+
+        let error = download(&client(), &[url], &hash, &state_dir)
+            .await
+            .unwrap_err();
+
+        assert!(error.contains("Every peer failed"), "{}", error);
+        // What did arrive is kept for the next attempt
+        assert_eq!(
+            fs::read_dir(state_dir.join(DOWNLOADS_DIR).join(&hash))
+                .unwrap()
+                .count(),
+            1
+        );
+        let _ = fs::remove_dir_all(&state_dir);
+    }
+
+    #[tokio::test]
+    async fn resumes_from_staged_chunks() {
+        let build = build();
+        let hash = binary::sha256(&build);
+        let state_dir = state_dir();
+        let staging = state_dir.join(DOWNLOADS_DIR).join(&hash);
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(staging.join("0"), &build[..CHUNK_SIZE as usize]).unwrap();
+        // Cut short, so fetched again
+        fs::write(staging.join("1"), &build[CHUNK_SIZE as usize..][..10]).unwrap();
+        let (url, requests) = peer(Arc::clone(&build), usize::MAX).await;
+
+        let downloaded = download(&client(), &[url], &hash, &state_dir)
+            .await
+            .unwrap();
+
+        assert_eq!(downloaded, build[..]);
+        let size = build.len();
+        assert_eq!(
+            chunks(&requests),
+            [
+                format!("bytes={}-{}", CHUNK_SIZE, 2 * CHUNK_SIZE - 1),
+                format!("bytes={}-{}", 2 * CHUNK_SIZE, size - 1),
+            ]
+        );
+        let _ = fs::remove_dir_all(&state_dir);
+    }
+
+    #[tokio::test]
+    async fn refuses_peers_serving_another_build() {
+        let build = build();
+        let other = binary::sha256(b"another build");
+        let (url, _) = peer(build, usize::MAX).await;
+        let state_dir = state_dir();
+
+        let error = download(&client(), &[url], &other, &state_dir)
+            .await
+            .unwrap_err();
+
+        assert!(error.contains("without the build"), "{}", error);
+        let _ = fs::remove_dir_all(&state_dir);
+    }
+
+    #[test]
+    fn orders_versions_by_semver_precedence() {
+        assert!(is_newer("0.2.0", "0.1.9"));
+        assert!(is_newer("0.10.0", "0.9.0"));
+        assert!(is_newer("0.2.0", "0.2.0-rc.1"));
+        assert!(!is_newer("0.2.0-rc.1", "0.2.0"));
+        assert!(is_newer("0.2.0-rc.2", "0.2.0-rc.1"));
+        assert!(is_newer("0.2.0-rc.10", "0.2.0-rc.9"));
+        assert!(!is_newer("0.2.0+build.2", "0.2.0+build.1"));
+        assert!(!is_newer("garbage", "0.0.0"));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::protocol::capability;
+use crate::receivers::hostdb::HostDatabase;
+
+use ipnet::IpNet;
+use serde::Serialize;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tokio::net::UdpSocket;
+
+// Conventional Wake-on-LAN port ("discard")
+const WOL_PORT: u16 = 9;
+
+/// How to wake a host: which peer sends the magic packet, and where.
+#[derive(Debug, Serialize)]
+pub struct WakePlan {
+    pub target: String,
+    pub target_mac: String,
+    pub relay_node_id: String,
+    pub relay_hostname: String,
+    pub broadcast_address: Ipv4Addr,
+}
+
+/// Finds a live peer that shares an IPv4 network with one of the target's known interfaces,
+/// preferring this node (`self_node_id`) so the packet doesn't need relaying at all.
+pub fn plan(hostdb: &HostDatabase, target: &str, self_node_id: &str) -> Result<WakePlan, String> {
+    let (hostname, interfaces) = hostdb
+        .known_interfaces(target)
+        .ok_or_else(|| format!("Unknown host {}", target))?;
+    if interfaces.is_empty() {
+        return Err(format!("No MAC address is known for {}", hostname));
+    }
+
+    let mut relays = hostdb
+        .hosts()
+        .into_iter()
+        .filter(|host| host.supports(capability::WAKE_ON_LAN) && host.hostname != hostname)
+        .collect::<Vec<_>>();
+    relays.sort_by_key(|host| host.node_id != self_node_id);
+
+    for iface in &interfaces {
+        for network in &iface.networks {
+            let IpNet::V4(network) = network else {
+                continue;
+            };
+
+            let relay = relays.iter().find(|relay| {
+                relay
+                    .interfaces
+                    .iter()
+                    .flat_map(crate::interfaces::networks)
+                    .any(|net| net == IpNet::V4(*network))
+            });
+
+            if let Some(relay) = relay {
+                return Ok(WakePlan {
+                    target: hostname,
+                    target_mac: iface.mac.clone(),
+                    relay_node_id: relay.node_id.clone(),
+                    relay_hostname: relay.hostname.clone(),
+                    broadcast_address: network.broadcast(),
+                });
+            }
+        }
+    }
+
+    Err(format!("No live peer shares a network with {}", hostname))
+}
+
+pub fn parse_mac(mac: &str) -> Result<[u8; 6], String> {
+    let octets = mac
+        .split([':', '-'])
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid MAC address {}: {}", mac, e))?;
+
+    octets
+        .try_into()
+        .map_err(|_| format!("Invalid MAC address {}", mac))
+}
+
+/// Six 0xff bytes followed by the target MAC repeated sixteen times.
+pub fn magic_packet(mac: [u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+    packet
+}
+
+pub async fn send_magic_packet(mac: [u8; 6], broadcast: Ipv4Addr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(&magic_packet(mac), SocketAddrV4::new(broadcast, WOL_PORT))
+        .await?;
+    Ok(())
+}